@@ -0,0 +1,113 @@
+use crate::services::ts::TxidAnomaly;
+use crate::services::CloudProtoMagic;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+/// How much attention an [`Anomaly`](Anomaly) deserves
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Copy, Clone)]
+pub enum AnomalySeverity {
+    /// Unusual, but the connection continues normally
+    Notice,
+    /// Something was dropped or ignored, the connection continues
+    Warning,
+    /// The connection or the current operation fails
+    Error,
+}
+
+/// What kind of protocol [`Anomaly`](Anomaly) was seen
+#[derive(Eq, PartialEq, Debug, Clone)]
+#[non_exhaustive]
+pub enum AnomalyKind {
+    /// A received frame is over the soft frame length limit, but was accepted
+    OversizedFrame {
+        frame_length: usize,
+        soft_frame_length: usize,
+    },
+    /// A received frame is over the maximum frame length, and was rejected
+    FrameTooLarge {
+        frame_length: usize,
+        max_frame_length: usize,
+    },
+    /// A received frame could not be decoded
+    BadFrame(String),
+    /// A received packet kind is not handled by the service
+    UnknownPacketKind { magic: CloudProtoMagic, kind: u8 },
+    /// A received ACK does not contain a single txid
+    BadAckSize(usize),
+    /// A received event could not be parsed
+    InvalidEvent(String),
+    /// The txids of received events deviate from the expected sequence
+    Txid(TxidAnomaly),
+    /// Nothing was received for longer than the idle timeout, and the connection was given up
+    IdleTimeout(Duration),
+}
+
+impl AnomalyKind {
+    pub fn severity(&self) -> AnomalySeverity {
+        match self {
+            Self::OversizedFrame { .. } | Self::Txid(_) => AnomalySeverity::Notice,
+            Self::UnknownPacketKind { .. } | Self::BadAckSize(_) | Self::InvalidEvent(_) => {
+                AnomalySeverity::Warning
+            }
+            Self::FrameTooLarge { .. } | Self::BadFrame(_) | Self::IdleTimeout(_) => {
+                AnomalySeverity::Error
+            }
+        }
+    }
+}
+
+/// A deviation from the expected protocol, reported on the channel given to `set_anomaly_sender`.
+///
+/// Anomalies are also logged, but the channel lets monitoring act on them without parsing logs.
+/// The sender can be cloned and given to many sockets, to collect the anomalies of a whole server.
+#[derive(Eq, PartialEq, Debug, Clone)]
+#[non_exhaustive]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    pub severity: AnomalySeverity,
+    /// The [`connection_id`](crate::services::ts::TsEventSocket::connection_id) of TS sockets,
+    /// `None` for anomalies of other sockets
+    pub connection_id: Option<u64>,
+    pub at: SystemTime,
+}
+
+// Reports anomalies until the receiver is dropped
+#[derive(Debug, Default, Clone)]
+pub(crate) struct AnomalyReporter {
+    tx: Option<mpsc::UnboundedSender<Anomaly>>,
+    connection_id: Option<u64>,
+}
+
+impl AnomalyReporter {
+    pub(crate) fn new(tx: Option<mpsc::UnboundedSender<Anomaly>>) -> Self {
+        Self {
+            tx,
+            connection_id: None,
+        }
+    }
+
+    pub(crate) fn with_connection_id(mut self, connection_id: u64) -> Self {
+        self.connection_id = Some(connection_id);
+        self
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub(crate) fn report(&mut self, kind: AnomalyKind) {
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+        let anomaly = Anomaly {
+            severity: kind.severity(),
+            kind,
+            connection_id: self.connection_id,
+            at: SystemTime::now(),
+        };
+        if tx.send(anomaly).is_err() {
+            self.tx = None;
+        }
+    }
+}
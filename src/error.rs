@@ -0,0 +1,184 @@
+use crate::framing::CloudProtoError;
+use crate::services::lfo::LfoError;
+use crate::services::ts::TsError;
+use std::io::ErrorKind;
+use thiserror::Error;
+
+/// Any error returned by this crate, for applications that handle several services.
+///
+/// Each error has a stable numeric [`code`](Error::code), so that applications (or FFI consumers)
+/// can branch on errors without matching on strings. Codes are grouped by service:
+/// `1xxx` for the framing layer and `2xxx` for LFO. TS errors reuse the framing codes,
+/// since they only split framing errors by cause. IO errors are all reported as `1100`.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error(transparent)]
+    CloudProto(#[from] CloudProtoError),
+    #[error(transparent)]
+    Lfo(#[from] LfoError),
+    #[error(transparent)]
+    Ts(#[from] TsError),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::CloudProto(e.into())
+    }
+}
+
+impl Error {
+    /// Stable numeric code of this error. Codes are never reused or reassigned.
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::CloudProto(e) => cloudproto_code(e),
+            Self::Lfo(e) => lfo_code(e),
+            Self::Ts(e) => ts_code(e),
+        }
+    }
+
+    /// Whether the same operation may succeed if retried, possibly on a new connection.
+    ///
+    /// This is true for dropped connections, timeouts and data corrupted in transit,
+    /// and false for errors that would happen again, like a missing file or a protocol violation.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::CloudProto(e) => cloudproto_retryable(e),
+            Self::Lfo(e) => lfo_retryable(e),
+            Self::Ts(e) => ts_retryable(e),
+        }
+    }
+}
+
+fn cloudproto_code(e: &CloudProtoError) -> u32 {
+    match e {
+        CloudProtoError::BadMagic(..) => 1001,
+        CloudProtoError::BadVersion(..) => 1002,
+        CloudProtoError::BadFrameSize(..) => 1003,
+        CloudProtoError::PayloadTooShort(..) => 1004,
+        CloudProtoError::PayloadInvalidSize(..) => 1005,
+        CloudProtoError::WrongConnectionPacketKind(..) => 1006,
+        CloudProtoError::ClosedByPeer(_) => 1007,
+        CloudProtoError::TransformFailed(_) => 1009,
+        CloudProtoError::Io { .. } => 1100,
+    }
+}
+
+fn lfo_code(e: &LfoError) -> u32 {
+    match e {
+        LfoError::NotFound => 2001,
+        LfoError::InvalidRequest => 2002,
+        LfoError::ServerError(_) => 2003,
+        LfoError::BadReplyKind(_) => 2004,
+        LfoError::ReplyParseError { .. } => 2005,
+        LfoError::InvalidFinalSize { .. } => 2006,
+        LfoError::DecompressionLimitExceeded { .. } => 2007,
+        LfoError::InvalidHash { .. } => 2008,
+        LfoError::CloudProto(e) => cloudproto_code(e),
+    }
+}
+
+fn ts_code(e: &TsError) -> u32 {
+    match e {
+        TsError::Protocol(e) => cloudproto_code(e),
+        TsError::ClosedByPeer { .. } => 1007,
+        TsError::IdleTimeout { .. } => 1008,
+        TsError::AlreadyClosed => 1010,
+        TsError::Timeout(_) => 1011,
+        TsError::UnexpectedEvent(..) => 1012,
+        TsError::ReservedPacketKind(_) => 1013,
+        TsError::Io { .. } => 1100,
+    }
+}
+
+fn cloudproto_retryable(e: &CloudProtoError) -> bool {
+    match e {
+        CloudProtoError::ClosedByPeer(_) => true,
+        CloudProtoError::Io { source } => io_retryable(source),
+        _ => false,
+    }
+}
+
+fn io_retryable(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::WouldBlock
+    )
+}
+
+fn lfo_retryable(e: &LfoError) -> bool {
+    match e {
+        // The server may have a transient issue, or the data was corrupted in transit
+        LfoError::ServerError(_)
+        | LfoError::InvalidFinalSize { .. }
+        | LfoError::InvalidHash { .. } => true,
+        LfoError::CloudProto(e) => cloudproto_retryable(e),
+        _ => false,
+    }
+}
+
+fn ts_retryable(e: &TsError) -> bool {
+    match e {
+        TsError::Protocol(_)
+        | TsError::AlreadyClosed
+        | TsError::UnexpectedEvent(..)
+        | TsError::ReservedPacketKind(_) => false,
+        TsError::ClosedByPeer { .. } | TsError::IdleTimeout { .. } | TsError::Timeout(_) => true,
+        TsError::Io { source } => io_retryable(source),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::CloudProtoCodec;
+    use crate::services::CloudProtoMagic;
+    use bytes::BytesMut;
+    use std::time::Duration;
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn codes_and_retries() {
+        let not_found = Error::from(LfoError::NotFound);
+        assert_eq!(not_found.code(), 2001);
+        assert!(!not_found.is_retryable());
+
+        let timeout = Error::from(TsError::IdleTimeout {
+            timeout: Duration::from_secs(1),
+            origin: Default::default(),
+        });
+        assert_eq!(timeout.code(), 1008);
+        assert!(timeout.is_retryable());
+
+        // Wrapped framing errors keep their code
+        let wrapped = Error::from(LfoError::CloudProto(CloudProtoError::BadMagic(
+            CloudProtoMagic::TS,
+            CloudProtoMagic::LFO,
+        )));
+        assert_eq!(wrapped.code(), 1001);
+        assert!(!wrapped.is_retryable());
+
+        let reset = Error::from(std::io::Error::from(ErrorKind::ConnectionReset));
+        assert_eq!(reset.code(), 1100);
+        assert!(reset.is_retryable());
+        assert!(!Error::from(std::io::Error::from(ErrorKind::PermissionDenied)).is_retryable());
+
+        // A frame announcing less than a header is a protocol violation, not a lost connection
+        let mut codec = CloudProtoCodec::new();
+        let mut short = BytesMut::from(&hex::decode("8f03000100000004").unwrap()[..]);
+        let short = Error::from(codec.decode(&mut short).unwrap_err());
+        assert_eq!(short.code(), 1003);
+        assert!(!short.is_retryable());
+
+        // TS errors keep the codes of the framing errors they come from
+        let ts = Error::from(TsError::from(CloudProtoError::PayloadTooShort(3, 12)));
+        assert_eq!(ts.code(), 1004);
+        assert!(!ts.is_retryable());
+    }
+}
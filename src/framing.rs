@@ -4,18 +4,32 @@
 //! The framing layer handles the common outer header/framing,
 //! but ignores the inner service-specific payload format and interpretation of packet kinds.
 
+pub mod blocking;
+mod codec;
 mod hdr_version;
 mod packet;
+mod sniff;
 mod socket;
+mod transform;
+mod wire_log;
+mod write_queue;
 
+pub use codec::CloudProtoCodec;
 pub use hdr_version::CloudProtoVersion;
 pub use packet::CloudProtoPacket;
-pub use socket::{CloudProtoSocket, DEFAULT_MAX_FRAME_LENGTH};
+pub use sniff::{sniff_protocol, PrefixedIo, SniffedProtocol};
+pub use socket::{
+    CloudProtoSocket, CloudProtoSocketBuilder, FrameProgress, FrameTimestamp, MemoryReport,
+    PartialFrame, WriteQueueDepth, DEFAULT_MAX_FRAME_LENGTH,
+};
+pub use transform::PayloadTransform;
+pub use wire_log::{ExportedPduWriter, FrameDirection, WireLogger};
 
 use crate::services::CloudProtoMagic;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum CloudProtoError {
     #[error("Bad CloudProto magic {0:#x}, expected {1:#x}")]
     BadMagic(CloudProtoMagic, CloudProtoMagic),
@@ -31,6 +45,8 @@ pub enum CloudProtoError {
     WrongConnectionPacketKind(u8, u8),
     #[error("{0}")]
     ClosedByPeer(String),
+    #[error("Payload transform failed: {0}")]
+    TransformFailed(String),
     #[error("CloudProto IO error")]
     Io {
         #[from]
@@ -0,0 +1,129 @@
+//! A synchronous [`CloudProtoSocket`](CloudProtoSocket) over `std::io::Read + Write`,
+//! for small tools that don't want a tokio runtime.
+
+use crate::framing::packet::COMMON_HDR_LEN;
+use crate::framing::{CloudProtoError, CloudProtoPacket, DEFAULT_MAX_FRAME_LENGTH};
+use bytes::BytesMut;
+use std::io::{self, Read, Write};
+use tracing::trace;
+
+/// Blocking version of the async [`CloudProtoSocket`](super::CloudProtoSocket).
+///
+/// Each [`send`](Self::send) writes and flushes one whole packet, and each [`recv`](Self::recv)
+/// reads exactly one. There is no buffering beyond that, so wrapping a `TcpStream` in a
+/// `BufReader`/`BufWriter` is not needed. Payload transforms and wire logging are not supported.
+pub struct CloudProtoSocket<IO: Read + Write> {
+    io: IO,
+    max_frame_length: usize,
+}
+
+impl<IO: Read + Write> CloudProtoSocket<IO> {
+    /// Accepts packets of up to [`DEFAULT_MAX_FRAME_LENGTH`](DEFAULT_MAX_FRAME_LENGTH) bytes, including header
+    pub fn new(io: IO) -> Self {
+        Self::with_max_frame_length(io, DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    /// `max_frame_length` is the maximum accepted size of received packets, including header
+    pub fn with_max_frame_length(io: IO, max_frame_length: usize) -> Self {
+        Self {
+            io,
+            max_frame_length,
+        }
+    }
+
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+
+    /// Writes the packet and flushes the IO
+    pub fn send(&mut self, pkt: CloudProtoPacket) -> io::Result<()> {
+        trace!(
+            "Sending kind 0x{:x} packet with 0x{:x} bytes payload",
+            pkt.kind,
+            pkt.payload.len()
+        );
+        let mut buf = BytesMut::new();
+        pkt.encode_into(&mut buf);
+        self.io.write_all(&buf)?;
+        self.io.flush()
+    }
+
+    /// Blocks until a whole packet is received. Returns `None` if the peer closed the connection cleanly.
+    pub fn recv(&mut self) -> Result<Option<CloudProtoPacket>, CloudProtoError> {
+        let mut hdr = [0u8; COMMON_HDR_LEN];
+        let mut hdr_len = 0;
+        while hdr_len < hdr.len() {
+            match self.io.read(&mut hdr[hdr_len..]) {
+                Ok(0) if hdr_len == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => hdr_len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let frame_len = u32::from_be_bytes(hdr[4..].try_into().unwrap()) as usize;
+        if frame_len > self.max_frame_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame size too big").into());
+        }
+        if frame_len < COMMON_HDR_LEN {
+            return Err(CloudProtoError::BadFrameSize(COMMON_HDR_LEN, frame_len));
+        }
+        let mut frame = BytesMut::with_capacity(frame_len);
+        frame.extend_from_slice(&hdr);
+        frame.resize(frame_len, 0);
+        self.io.read_exact(&mut frame[COMMON_HDR_LEN..])?;
+
+        let pkt = CloudProtoPacket::from_buf(frame.freeze())?;
+        trace!(
+            "Received kind 0x{:x} packet with 0x{:x} bytes payload",
+            pkt.kind,
+            pkt.payload.len()
+        );
+        Ok(Some(pkt))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::CloudProtoVersion;
+    use crate::services::CloudProtoMagic;
+    use std::io::Cursor;
+
+    #[test]
+    fn blocking_send_recv() -> Result<(), CloudProtoError> {
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::LFO,
+            kind: 1,
+            version: CloudProtoVersion::Connect,
+            payload: vec![0xAA; 0x20].into(),
+        };
+        let mut sock = CloudProtoSocket::new(Cursor::new(Vec::new()));
+        sock.send(pkt.clone())?;
+        sock.send(pkt.clone())?;
+        let wire = sock.into_inner().into_inner();
+        assert_eq!(wire.len(), 2 * 0x28);
+
+        let mut sock = CloudProtoSocket::with_max_frame_length(Cursor::new(wire.clone()), 0x28);
+        assert_eq!(sock.recv()?, Some(pkt.clone()));
+        assert_eq!(sock.recv()?, Some(pkt));
+        assert_eq!(sock.recv()?, None);
+
+        let mut sock =
+            CloudProtoSocket::with_max_frame_length(Cursor::new(wire[..0x30].to_vec()), 0x28);
+        assert!(sock.recv()?.is_some());
+        assert!(sock.recv().is_err());
+        let mut sock = CloudProtoSocket::with_max_frame_length(Cursor::new(wire), 0x27);
+        assert!(sock.recv().is_err());
+        Ok(())
+    }
+}
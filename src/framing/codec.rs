@@ -0,0 +1,253 @@
+use crate::anomaly::{AnomalyKind, AnomalyReporter};
+use crate::framing::packet::COMMON_HDR_LEN;
+use crate::framing::{CloudProtoError, CloudProtoPacket, DEFAULT_MAX_FRAME_LENGTH};
+use crate::Anomaly;
+use bytes::BytesMut;
+use tokio::sync::mpsc;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+use tracing::warn;
+
+// The frame length field is right before this offset in the header
+const FRAME_LEN_FIELD_END: usize = 8;
+
+/// Splits a byte stream into [`CloudProtoPacket`](CloudProtoPacket)s, and back.
+///
+/// This is the framing of [`CloudProtoSocket`](super::CloudProtoSocket), for use in your own
+/// `Framed` stacks over other transports. It only handles framing: there are no
+/// [`PayloadTransform`](super::PayloadTransform)s, wire logging or write queue tracking at this level.
+///
+/// Received payloads are slices of the read buffer, so decoding does not copy them.
+#[derive(Debug)]
+pub struct CloudProtoCodec {
+    frames: LengthDelimitedCodec,
+    max_frame_length: usize,
+    soft_frame_length: Option<usize>,
+    soft_limit_hits: u64,
+    anomalies: AnomalyReporter,
+    // Whether the frame at the start of the read buffer was already counted
+    current_frame_counted: bool,
+}
+
+impl CloudProtoCodec {
+    /// A codec accepting frames of up to [`DEFAULT_MAX_FRAME_LENGTH`](DEFAULT_MAX_FRAME_LENGTH)
+    pub fn new() -> Self {
+        Self::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    /// `max_frame_length` is the maximum accepted size of received packets, including header
+    pub fn with_max_frame_length(max_frame_length: usize) -> Self {
+        let frames = LengthDelimitedCodec::builder()
+            .big_endian()
+            .max_frame_length(max_frame_length)
+            .length_field_type::<u32>()
+            .length_adjustment(0)
+            .length_field_offset(4)
+            .num_skip(0)
+            .new_codec();
+        Self {
+            frames,
+            max_frame_length,
+            soft_frame_length: None,
+            soft_limit_hits: 0,
+            anomalies: AnomalyReporter::default(),
+            current_frame_counted: false,
+        }
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    /// Log a warning for each received frame announcing more than `soft_frame_length` bytes.
+    ///
+    /// Frames over the [`max_frame_length`](Self::max_frame_length) are rejected, which ends the session.
+    /// A soft limit somewhat below it shows that the hard limit should be raised before that happens.
+    /// The warning is logged as soon as the frame header is received.
+    pub fn set_soft_frame_length(&mut self, soft_frame_length: Option<usize>) {
+        self.soft_frame_length = soft_frame_length;
+    }
+
+    pub fn soft_frame_length(&self) -> Option<usize> {
+        self.soft_frame_length
+    }
+
+    /// Number of received frames that were over the [`soft_frame_length`](Self::set_soft_frame_length)
+    pub fn soft_limit_hits(&self) -> u64 {
+        self.soft_limit_hits
+    }
+
+    /// Report [`Anomaly`](Anomaly)s of received frames to this channel
+    pub fn set_anomaly_sender(&mut self, tx: Option<mpsc::UnboundedSender<Anomaly>>) {
+        self.set_anomaly_reporter(AnomalyReporter::new(tx));
+    }
+
+    pub(crate) fn set_anomaly_reporter(&mut self, anomalies: AnomalyReporter) {
+        self.anomalies = anomalies;
+    }
+
+    fn check_frame_length(&mut self, src: &BytesMut) -> Result<(), CloudProtoError> {
+        let len = match src.get(4..FRAME_LEN_FIELD_END) {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+        let frame_length = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        // The length includes the header, so a peer can't announce less than that
+        if frame_length < COMMON_HDR_LEN {
+            let e = CloudProtoError::BadFrameSize(COMMON_HDR_LEN, frame_length);
+            self.anomalies.report(AnomalyKind::BadFrame(e.to_string()));
+            return Err(e);
+        }
+        if self.current_frame_counted {
+            return Ok(());
+        }
+        if frame_length > self.max_frame_length {
+            self.current_frame_counted = true;
+            self.anomalies.report(AnomalyKind::FrameTooLarge {
+                frame_length,
+                max_frame_length: self.max_frame_length,
+            });
+            return Ok(());
+        }
+        let soft_limit = match self.soft_frame_length {
+            Some(soft_limit) if frame_length > soft_limit => soft_limit,
+            _ => return Ok(()),
+        };
+        self.current_frame_counted = true;
+        self.soft_limit_hits += 1;
+        warn!(
+            frame_length,
+            soft_frame_length = soft_limit,
+            max_frame_length = self.max_frame_length,
+            "Receiving a frame over the soft length limit"
+        );
+        self.anomalies.report(AnomalyKind::OversizedFrame {
+            frame_length,
+            soft_frame_length: soft_limit,
+        });
+        Ok(())
+    }
+}
+
+impl Default for CloudProtoCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for CloudProtoCodec {
+    type Item = CloudProtoPacket;
+    type Error = CloudProtoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.check_frame_length(src)?;
+        match self.frames.decode(src)? {
+            Some(frame) => {
+                self.current_frame_counted = false;
+                match CloudProtoPacket::from_buf(frame.freeze()) {
+                    Ok(pkt) => Ok(Some(pkt)),
+                    Err(e) => {
+                        self.anomalies.report(AnomalyKind::BadFrame(e.to_string()));
+                        Err(e)
+                    }
+                }
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<CloudProtoPacket> for CloudProtoCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, pkt: CloudProtoPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        pkt.encode_into(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::CloudProtoVersion;
+    use crate::services::CloudProtoMagic;
+    use crate::AnomalySeverity;
+
+    #[test]
+    fn codec_roundtrip() {
+        let mut codec = CloudProtoCodec::with_max_frame_length(0x100);
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::LFO,
+            kind: 1,
+            version: CloudProtoVersion::Connect,
+            payload: vec![0xAA; 0x10].into(),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        let mut partial = buf.split_to(0x10);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(pkt.clone()));
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(pkt));
+        assert!(partial.is_empty());
+
+        let mut huge = BytesMut::from(&hex::decode("9f01000100001000").unwrap()[..]);
+        assert!(codec.decode(&mut huge).is_err());
+    }
+
+    #[test]
+    fn soft_frame_length() {
+        let mut codec = CloudProtoCodec::with_max_frame_length(0x100);
+        codec.set_soft_frame_length(Some(0x20));
+        let pkt = |len| CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 0,
+            version: CloudProtoVersion::Normal,
+            payload: vec![0; len].into(),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(pkt(0x10), &mut buf).unwrap();
+        codec.encode(pkt(0x80), &mut buf).unwrap();
+        let rest = buf.split_off(0x18 + 0x10);
+
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert_eq!(codec.soft_limit_hits(), 0);
+        // Counted once as soon as the header arrives, not on every partial read
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.soft_limit_hits(), 1);
+        buf.unsplit(rest);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(pkt(0x80)));
+        assert_eq!(codec.soft_limit_hits(), 1);
+    }
+
+    #[test]
+    fn frame_anomalies() {
+        let mut codec = CloudProtoCodec::with_max_frame_length(0x100);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        codec.set_anomaly_sender(Some(tx));
+
+        // The frame length is shorter than the header
+        let mut short = BytesMut::from(&hex::decode("9f01000100000004").unwrap()[..]);
+        assert!(matches!(
+            codec.decode(&mut short),
+            Err(CloudProtoError::BadFrameSize(8, 4))
+        ));
+        let anomaly = rx.try_recv().unwrap();
+        assert!(matches!(anomaly.kind, AnomalyKind::BadFrame(_)));
+
+        let mut huge = BytesMut::from(&hex::decode("9f01000100001000").unwrap()[..]);
+        assert!(codec.decode(&mut huge).is_err());
+        let anomaly = rx.try_recv().unwrap();
+        assert_eq!(
+            anomaly.kind,
+            AnomalyKind::FrameTooLarge {
+                frame_length: 0x1000,
+                max_frame_length: 0x100
+            }
+        );
+        assert_eq!(anomaly.severity, AnomalySeverity::Error);
+        assert_eq!(anomaly.connection_id, None);
+        assert!(rx.try_recv().is_err());
+    }
+}
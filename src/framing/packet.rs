@@ -1,12 +1,14 @@
 use crate::framing::{CloudProtoError, CloudProtoVersion};
+use crate::redaction::SensitivePayload;
 use crate::services::CloudProtoMagic;
 use byteorder::{ReadBytesExt, BE};
+use bytes::{BufMut, Bytes, BytesMut};
 use std::io::Cursor;
 
 pub(crate) const COMMON_HDR_LEN: usize = 8;
 
 /// The common framing packet structure of the protocol
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Clone)]
 pub struct CloudProtoPacket {
     /// One magic value corresponds to one backend service
     pub magic: CloudProtoMagic,
@@ -14,21 +16,37 @@ pub struct CloudProtoPacket {
     /// There is no common definition of packet kind at the framing level
     pub kind: u8,
     pub version: CloudProtoVersion,
-    pub payload: Vec<u8>,
+    /// Received payloads share the read buffer of the socket, so they are cheap to clone
+    pub payload: Bytes,
+}
+
+impl std::fmt::Debug for CloudProtoPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloudProtoPacket")
+            .field("magic", &self.magic)
+            .field("kind", &self.kind)
+            .field("version", &self.version)
+            .field("payload", &SensitivePayload(&self.payload))
+            .finish()
+    }
 }
 
 impl CloudProtoPacket {
-    pub(crate) fn from_buf(buf: &[u8]) -> Result<Self, CloudProtoError> {
-        let mut reader = Cursor::new(buf);
+    /// Parses a complete frame. The payload is a slice of `buf`, so it is not copied.
+    pub(crate) fn from_buf(buf: Bytes) -> Result<Self, CloudProtoError> {
+        let mut reader = Cursor::new(&buf[..]);
         let magic = reader.read_u8()?.into();
         let kind = reader.read_u8()?;
         let version = reader.read_u16::<BE>()?.into();
-        let pkt_size = reader.read_u32::<BE>()? as usize - COMMON_HDR_LEN;
+        let frame_size = reader.read_u32::<BE>()? as usize;
         let remaining_size = buf.len() - reader.position() as usize;
+        let pkt_size = frame_size
+            .checked_sub(COMMON_HDR_LEN)
+            .ok_or(CloudProtoError::BadFrameSize(remaining_size, frame_size))?;
         if remaining_size != pkt_size {
             return Err(CloudProtoError::BadFrameSize(remaining_size, pkt_size));
         }
-        let payload = buf[reader.position() as usize..].to_vec();
+        let payload = buf.slice(reader.position() as usize..);
         Ok(Self {
             magic,
             kind,
@@ -37,21 +55,29 @@ impl CloudProtoPacket {
         })
     }
 
-    pub(crate) fn to_buf(&self) -> Vec<u8> {
-        use byteorder::WriteBytesExt;
-        use std::io::Write;
+    pub(crate) fn to_buf(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.encode_into(&mut buf);
+        buf.freeze()
+    }
+
+    /// Length of the whole frame, including the header
+    pub(crate) fn frame_len(&self) -> usize {
+        self.payload.len() + COMMON_HDR_LEN
+    }
+
+    pub(crate) fn encode_into(&self, buf: &mut BytesMut) {
+        buf.reserve(self.frame_len());
+        self.encode_header_into(buf);
+        buf.put_slice(&self.payload);
+    }
 
-        let mut buf = Vec::new();
-        let mut writer = Cursor::new(&mut buf);
-        writer.write_u8(self.magic.into()).unwrap();
-        writer.write_u8(self.kind).unwrap();
-        writer.write_u16::<BE>(self.version.into()).unwrap();
-        writer
-            .write_u32::<BE>((self.payload.len() + COMMON_HDR_LEN) as u32)
-            .unwrap();
-        writer.write_all(&self.payload).unwrap();
-        writer.flush().unwrap();
-        buf
+    pub(crate) fn encode_header_into(&self, buf: &mut BytesMut) {
+        buf.reserve(COMMON_HDR_LEN);
+        buf.put_u8(self.magic.into());
+        buf.put_u8(self.kind);
+        buf.put_u16(self.version.into());
+        buf.put_u32(self.frame_len() as u32);
     }
 }
 
@@ -61,6 +87,7 @@ mod test {
     use crate::framing::CloudProtoVersion;
     use crate::services::CloudProtoMagic;
     use anyhow::Result;
+    use bytes::Bytes;
 
     #[test_log::test]
     fn to_from_buf_serialization() -> Result<()> {
@@ -68,9 +95,9 @@ mod test {
             magic: CloudProtoMagic::Other(0xFF),
             kind: 0x73,
             version: CloudProtoVersion::Other(0x10E9),
-            payload: b"Hello world".to_vec(),
+            payload: Bytes::from_static(b"Hello world"),
         };
-        let pkt2 = CloudProtoPacket::from_buf(&pkt.to_buf())?;
+        let pkt2 = CloudProtoPacket::from_buf(pkt.to_buf())?;
         assert_eq!(pkt, pkt2);
 
         Ok(())
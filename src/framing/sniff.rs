@@ -0,0 +1,153 @@
+use crate::services::CloudProtoMagic;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+// Enough to recognize the HTTP methods below
+const SNIFF_LEN: usize = 4;
+
+const HTTP_PREFIXES: &[&[u8; SNIFF_LEN]] = &[
+    b"GET ", b"HEAD", b"POST", b"PUT ", b"DELE", b"OPTI", b"PATC", b"CONN", b"TRAC",
+    // HTTP/2 connection preface
+    b"PRI ",
+];
+
+/// What the first bytes received on a connection look like
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SniffedProtocol {
+    /// Starts with a known CloudProto magic
+    CloudProto,
+    /// Starts like a plaintext HTTP request
+    Http,
+    /// Anything else, including connections closed before sending enough bytes
+    Unknown,
+}
+
+/// Wraps an IO object and first replays the bytes consumed by [`sniff_protocol`](sniff_protocol)
+pub struct PrefixedIo<IO> {
+    prefix: Vec<u8>,
+    prefix_pos: usize,
+    io: IO,
+}
+
+impl<IO> PrefixedIo<IO> {
+    /// The bytes that were read while sniffing
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Returns the inner IO. Any sniffed bytes not read yet are lost.
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+}
+
+/// Reads the first bytes of a connection to guess which protocol the peer speaks.
+///
+/// Sensors may probe endpoints with plain HTTP requests, so a server sharing a single port
+/// with an HTTP service can use this to hand those connections to its HTTP handler,
+/// instead of failing with a [`BadMagic`](super::CloudProtoError::BadMagic) error.
+/// The returned IO replays the sniffed bytes, so it can be used as if nothing had been read.
+pub async fn sniff_protocol<IO: AsyncRead + Unpin>(
+    mut io: IO,
+) -> io::Result<(SniffedProtocol, PrefixedIo<IO>)> {
+    let mut prefix = Vec::with_capacity(SNIFF_LEN);
+    while prefix.len() < SNIFF_LEN {
+        if (&mut io)
+            .take((SNIFF_LEN - prefix.len()) as u64)
+            .read_buf(&mut prefix)
+            .await?
+            == 0
+        {
+            break;
+        }
+        // A single byte is enough to recognize CloudProto, don't wait for more
+        if prefix.len() == 1 && is_cloudproto_magic(prefix[0]) {
+            break;
+        }
+    }
+    let protocol = match prefix.first() {
+        Some(&magic) if is_cloudproto_magic(magic) => SniffedProtocol::CloudProto,
+        _ if HTTP_PREFIXES.iter().any(|p| &p[..] == prefix.as_slice()) => SniffedProtocol::Http,
+        _ => SniffedProtocol::Unknown,
+    };
+    let io = PrefixedIo {
+        prefix,
+        prefix_pos: 0,
+        io,
+    };
+    Ok((protocol, io))
+}
+
+fn is_cloudproto_magic(byte: u8) -> bool {
+    byte == CloudProtoMagic::TS || byte == CloudProtoMagic::LFO
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for PrefixedIo<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let remaining = &this.prefix[this.prefix_pos..];
+        if !remaining.is_empty() {
+            let count = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..count]);
+            this.prefix_pos += count;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for PrefixedIo<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    async fn sniff(data: &[u8]) -> (SniffedProtocol, Vec<u8>) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(data).await.unwrap();
+        drop(client);
+        let (protocol, mut io) = sniff_protocol(server).await.unwrap();
+        let mut replayed = Vec::new();
+        io.read_to_end(&mut replayed).await.unwrap();
+        (protocol, replayed)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn sniff_protocols() {
+        let http = b"GET / HTTP/1.1\r\nHost: ts01\r\n\r\n";
+        assert_eq!(sniff(http).await, (SniffedProtocol::Http, http.to_vec()));
+        let cloudproto = [0x8F, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08];
+        assert_eq!(
+            sniff(&cloudproto).await,
+            (SniffedProtocol::CloudProto, cloudproto.to_vec())
+        );
+        assert_eq!(
+            sniff(b"\x16\x03\x01").await,
+            (SniffedProtocol::Unknown, b"\x16\x03\x01".to_vec())
+        );
+        assert_eq!(sniff(b"").await, (SniffedProtocol::Unknown, vec![]));
+    }
+}
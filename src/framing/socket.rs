@@ -1,20 +1,159 @@
+use crate::anomaly::{AnomalyKind, AnomalyReporter};
 use crate::framing::packet::CloudProtoPacket;
-use crate::framing::CloudProtoError;
-use bytes::Bytes;
-use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use crate::framing::write_queue::WriteQueue;
+use crate::framing::{
+    CloudProtoCodec, CloudProtoError, FrameDirection, PayloadTransform, WireLogger,
+};
+use crate::redaction::PayloadDump;
+use crate::Anomaly;
+use futures_util::{Sink, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
 use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite, LengthDelimitedCodec};
-use tracing::{error, trace};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::codec::FramedRead;
+use tracing::{error, trace, warn};
+
+// Same defaults as tokio-util's Framed types
+const DEFAULT_READ_BUFFER_CAPACITY: usize = 8 * 1024;
+const DEFAULT_WRITE_BACKPRESSURE_BOUNDARY: usize = 8 * 1024;
 
 /// Default maximum size of a single [`CloudProtoPacket`](super::CloudProtoPacket), including header
 pub const DEFAULT_MAX_FRAME_LENGTH: usize = 32 * 1024 * 1024;
 
+// Frame header bytes before and including the length field
+const FRAME_LEN_FIELD_END: usize = 8;
+
+/// A frame that is still being received
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PartialFrame {
+    /// Bytes of the frame received so far, including header
+    pub received: usize,
+    /// Total size of the frame announced in its header
+    pub announced: usize,
+}
+
+/// Shared handle to the receive progress of a [`CloudProtoSocket`](CloudProtoSocket).
+///
+/// Large frames like multi-MiB LFO replies can take a while to arrive, and the socket
+/// only returns them once complete. The handle can be read from another task while the
+/// socket is busy, e.g. to show download progress.
+#[derive(Debug, Clone, Default)]
+pub struct FrameProgress {
+    current: Arc<Mutex<Option<PartialFrame>>>,
+}
+
+impl FrameProgress {
+    /// The frame currently being received, if its header has arrived
+    pub fn current(&self) -> Option<PartialFrame> {
+        *self.current.lock().unwrap()
+    }
+}
+
+/// When a frame passed through a [`CloudProtoSocket`](CloudProtoSocket)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameTimestamp {
+    /// Wall clock time, to correlate with other logs
+    pub wall: SystemTime,
+    /// Monotonic time, for cadence analysis. Uses `tokio::time`, so it follows a paused clock.
+    pub monotonic: Instant,
+}
+
+impl FrameTimestamp {
+    pub(crate) fn now() -> Self {
+        Self {
+            wall: SystemTime::now(),
+            monotonic: Instant::now(),
+        }
+    }
+}
+
+/// Data written to a [`CloudProtoSocket`](CloudProtoSocket) but not yet passed to its IO
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct WriteQueueDepth {
+    /// Frames that were not completely written yet
+    pub frames: usize,
+    pub bytes: usize,
+}
+
+/// Memory held by a socket, see [`CloudProtoSocket::memory_report`](CloudProtoSocket::memory_report).
+///
+/// Reports can be added together, to watch the total of all the sockets of a server.
+/// Buffer capacities only count memory owned by the socket. Received payloads that are still
+/// held by the application share their allocation with the read buffer, so they are not counted.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[non_exhaustive]
+pub struct MemoryReport {
+    pub sockets: usize,
+    /// Allocated capacity of the read buffers
+    pub read_buffer_capacity: usize,
+    /// Allocated capacity of the write buffers
+    pub write_buffer_capacity: usize,
+    /// Frames written to the sockets but not yet passed to their IO
+    pub queued_frames: usize,
+    pub queued_bytes: usize,
+    /// Events sent but not ACKed yet, when an ACK window is configured on TS sockets
+    pub inflight_events: usize,
+    /// Payload bytes of the unexpected packets captured by TS sockets
+    pub captured_packet_bytes: usize,
+}
+
+impl MemoryReport {
+    /// Total bytes allocated for buffers and captures
+    pub fn total_bytes(&self) -> usize {
+        self.read_buffer_capacity + self.write_buffer_capacity + self.captured_packet_bytes
+    }
+}
+
+impl std::ops::Add for MemoryReport {
+    type Output = Self;
+
+    fn add(mut self, rhs: Self) -> Self {
+        self += rhs;
+        self
+    }
+}
+
+impl std::ops::AddAssign for MemoryReport {
+    fn add_assign(&mut self, rhs: Self) {
+        self.sockets += rhs.sockets;
+        self.read_buffer_capacity += rhs.read_buffer_capacity;
+        self.write_buffer_capacity += rhs.write_buffer_capacity;
+        self.queued_frames += rhs.queued_frames;
+        self.queued_bytes += rhs.queued_bytes;
+        self.inflight_events += rhs.inflight_events;
+        self.captured_packet_bytes += rhs.captured_packet_bytes;
+    }
+}
+
+impl std::iter::Sum for MemoryReport {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |total, report| total + report)
+    }
+}
+
 /// The common socket that carries framing-layer [`packets`](super::CloudProtoPacket) used by higher level protocols
 pub struct CloudProtoSocket<IO: AsyncRead + AsyncWrite> {
-    read: FramedRead<ReadHalf<IO>, LengthDelimitedCodec>,
-    write: FramedWrite<WriteHalf<IO>, BytesCodec>,
+    read: FramedRead<ReadHalf<IO>, CloudProtoCodec>,
+    write: WriteHalf<IO>,
+    write_queue: WriteQueue,
+    write_backpressure_boundary: usize,
+    write_backpressure_frames: Option<usize>,
+    transform: Option<Box<dyn PayloadTransform>>,
+    progress: Option<FrameProgress>,
+    last_frame_received_at: Option<FrameTimestamp>,
+    wire_logger: Option<Box<dyn WireLogger>>,
+    dump_event_id: Option<fn(&CloudProtoPacket) -> Option<u32>>,
+    peer_addr: Option<SocketAddr>,
+    anomalies: AnomalyReporter,
+    // Total bytes ever queued for writing, and the running total at the end of each queued frame
+    queued_bytes: u64,
+    queued_frame_ends: VecDeque<u64>,
 }
 
 impl<IO> CloudProtoSocket<IO>
@@ -36,18 +175,259 @@ where
     ///
     /// The socket buffers individual packets, `max_frame_length` will be the maximum accepted size
     /// of [`CloudProtoPacket`](super::CloudProtoPacket)s, including header.
+    /// See [`CloudProtoSocketBuilder`](CloudProtoSocketBuilder) for other buffering knobs.
     pub fn with_max_frame_length(io: IO, max_frame_length: usize) -> Self {
-        let (read, write) = tokio::io::split(io);
-        let read = LengthDelimitedCodec::builder()
-            .big_endian()
+        CloudProtoSocketBuilder::new()
             .max_frame_length(max_frame_length)
-            .length_field_type::<u32>()
-            .length_adjustment(0)
-            .length_field_offset(4)
-            .num_skip(0)
-            .new_read(read);
-        let write = FramedWrite::new(write, BytesCodec::new());
-        Self { read, write }
+            .build(io)
+    }
+
+    /// Returns a handle to follow the progress of frames as they are received.
+    /// Progress is updated each time this socket is polled for packets.
+    pub fn frame_progress(&mut self) -> FrameProgress {
+        self.progress.get_or_insert_with(Default::default).clone()
+    }
+
+    fn update_frame_progress(&self) {
+        let progress = match &self.progress {
+            Some(progress) => progress,
+            None => return,
+        };
+        let buf = self.read.read_buffer();
+        let partial = buf.get(4..FRAME_LEN_FIELD_END).map(|len| PartialFrame {
+            received: buf.len(),
+            announced: u32::from_be_bytes(len.try_into().unwrap()) as usize,
+        });
+        *progress.current.lock().unwrap() = partial;
+    }
+
+    /// When the last frame was fully read from the IO, before it was decoded.
+    ///
+    /// This is taken as soon as the frame is split from the read buffer, before any
+    /// [`PayloadTransform`](PayloadTransform), decompression, or logging, so it's a better basis
+    /// for latency measurements than timestamps taken by the application after receiving a packet.
+    /// It is still a user-space timestamp: it includes the time the frame waited in kernel
+    /// and TLS buffers until this socket was polled.
+    pub fn last_frame_received_at(&self) -> Option<FrameTimestamp> {
+        self.last_frame_received_at
+    }
+
+    /// Number of received frames that were over the [`soft_frame_length`](CloudProtoSocketBuilder::soft_frame_length)
+    pub fn soft_limit_hits(&self) -> u64 {
+        self.read.decoder().soft_limit_hits()
+    }
+
+    /// Log a copy of every frame sent and received on this socket, see [`WireLogger`](WireLogger).
+    ///
+    /// If the logger fails, a warning is logged and the logger is removed,
+    /// so that a full disk doesn't take down the connection.
+    pub fn set_wire_logger(&mut self, logger: Option<Box<dyn WireLogger>>) {
+        self.wire_logger = logger;
+    }
+
+    /// Remember the address of the peer, to identify this connection in the errors of higher-level sockets.
+    ///
+    /// The socket can't find it by itself, since `IO` is usually a TLS stream.
+    pub fn set_peer_addr(&mut self, peer_addr: Option<SocketAddr>) {
+        self.peer_addr = peer_addr;
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    // The framing layer doesn't interpret payloads, but services can tell it which event a packet
+    // carries, so that trace logs honor the crate-wide payload suppression settings
+    pub(crate) fn set_dump_event_id(&mut self, f: Option<fn(&CloudProtoPacket) -> Option<u32>>) {
+        self.dump_event_id = f;
+    }
+
+    fn payload_dump<'a>(&self, pkt: &'a CloudProtoPacket) -> PayloadDump<'a> {
+        let event_id = self.dump_event_id.and_then(|f| f(pkt));
+        PayloadDump::for_event(&pkt.payload, event_id)
+    }
+
+    /// Report [`Anomaly`](Anomaly)s of this socket to this channel, e.g. received frames that are
+    /// too large or fail the [`PayloadTransform`](PayloadTransform).
+    pub fn set_anomaly_sender(&mut self, tx: Option<mpsc::UnboundedSender<Anomaly>>) {
+        self.set_anomaly_reporter(AnomalyReporter::new(tx));
+    }
+
+    pub(crate) fn set_anomaly_reporter(&mut self, anomalies: AnomalyReporter) {
+        self.read
+            .decoder_mut()
+            .set_anomaly_reporter(anomalies.clone());
+        self.anomalies = anomalies;
+    }
+
+    /// How much data is waiting in the write buffer.
+    ///
+    /// Frames are buffered until the socket is flushed, or until the buffer grows past
+    /// the [`write_backpressure_boundary`](CloudProtoSocketBuilder::write_backpressure_boundary).
+    /// Applications sending in bulk can check this, or use [`wait_for_drain`](Self::wait_for_drain),
+    /// to pace themselves against a slow peer.
+    pub fn write_queue_depth(&self) -> WriteQueueDepth {
+        let bytes = self.write_queue.len();
+        let written = self.queued_bytes - bytes as u64;
+        let frames = self
+            .queued_frame_ends
+            .iter()
+            .rev()
+            .take_while(|&&end| end > written)
+            .count();
+        WriteQueueDepth { frames, bytes }
+    }
+
+    /// Memory currently held by this socket's buffers
+    pub fn memory_report(&self) -> MemoryReport {
+        let depth = self.write_queue_depth();
+        MemoryReport {
+            sockets: 1,
+            read_buffer_capacity: self.read.read_buffer().capacity(),
+            write_buffer_capacity: self.write_queue.capacity(),
+            queued_frames: depth.frames,
+            queued_bytes: depth.bytes,
+            ..Default::default()
+        }
+    }
+
+    fn prune_written_frames(&mut self) {
+        let written = self.queued_bytes - self.write_queue.len() as u64;
+        while matches!(self.queued_frame_ends.front(), Some(&end) if end <= written) {
+            self.queued_frame_ends.pop_front();
+        }
+    }
+
+    /// Writes buffered data to the IO until at most `level` bytes are left in the write buffer.
+    ///
+    /// With a `level` of 0 this is the same as flushing, but without flushing the IO itself.
+    pub async fn wait_for_drain(&mut self, level: usize) -> std::io::Result<()> {
+        futures_util::future::poll_fn(|cx| loop {
+            if self.write_queue_depth().bytes <= level {
+                self.prune_written_frames();
+                return Poll::Ready(Ok(()));
+            }
+            ready!(self.write_queue.poll_write_all(&mut self.write, cx))?;
+        })
+        .await
+    }
+
+    /// Transform all packets sent and received on this socket, see [`PayloadTransform`](PayloadTransform).
+    ///
+    /// Higher-level sockets built on top of this one inherit the transform.
+    /// Both peers must use a compatible transform.
+    pub fn set_payload_transform(&mut self, transform: Option<Box<dyn PayloadTransform>>) {
+        self.transform = transform;
+    }
+}
+
+/// Tuning knobs for the buffers of a [`CloudProtoSocket`](CloudProtoSocket).
+///
+/// With many concurrent connections, per-connection buffers dominate memory usage.
+/// The defaults suit a few busy connections, smaller buffers suit many mostly idle ones.
+#[derive(Debug, Clone)]
+pub struct CloudProtoSocketBuilder {
+    max_frame_length: usize,
+    soft_frame_length: Option<usize>,
+    read_buffer_capacity: usize,
+    write_backpressure_boundary: usize,
+    write_backpressure_frames: Option<usize>,
+}
+
+impl CloudProtoSocketBuilder {
+    pub fn new() -> Self {
+        Self {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            soft_frame_length: None,
+            read_buffer_capacity: DEFAULT_READ_BUFFER_CAPACITY,
+            write_backpressure_boundary: DEFAULT_WRITE_BACKPRESSURE_BOUNDARY,
+            write_backpressure_frames: None,
+        }
+    }
+
+    /// Maximum accepted size of received packets, including header.
+    /// Defaults to [`DEFAULT_MAX_FRAME_LENGTH`](DEFAULT_MAX_FRAME_LENGTH).
+    pub fn max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// Log a warning for received packets larger than this, including header.
+    /// Disabled by default, see [`CloudProtoCodec::set_soft_frame_length`](CloudProtoCodec::set_soft_frame_length).
+    pub fn soft_frame_length(mut self, soft_frame_length: usize) -> Self {
+        self.soft_frame_length = Some(soft_frame_length);
+        self
+    }
+
+    /// Initial capacity of the read buffer, which grows as needed to fit a whole frame.
+    /// Defaults to 8KiB.
+    pub fn read_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.read_buffer_capacity = capacity;
+        self
+    }
+
+    /// Number of buffered bytes past which sending waits for the write buffer to be flushed.
+    /// Defaults to 8KiB.
+    ///
+    /// Once the boundary is reached, [`poll_ready`](Sink::poll_ready) returns `Pending` until
+    /// the peer accepts the buffered data, so a stalled peer can't make the buffer grow past it
+    /// by more than one frame.
+    pub fn write_backpressure_boundary(mut self, boundary: usize) -> Self {
+        self.write_backpressure_boundary = boundary;
+        self
+    }
+
+    /// Number of buffered frames past which sending waits for the write buffer to be flushed,
+    /// like the [`write_backpressure_boundary`](Self::write_backpressure_boundary) in bytes.
+    /// Unlimited by default.
+    pub fn write_backpressure_frames(mut self, frames: usize) -> Self {
+        self.write_backpressure_frames = Some(frames);
+        self
+    }
+
+    /// CloudProtoSocket is usually layered over a TLS session over TCP port 443,
+    /// so in practice `IO` should usually be `TlsStream<TcpStream>`.
+    pub fn build<IO: AsyncRead + AsyncWrite>(&self, io: IO) -> CloudProtoSocket<IO> {
+        let (read, write) = tokio::io::split(io);
+        let mut codec = CloudProtoCodec::with_max_frame_length(self.max_frame_length);
+        codec.set_soft_frame_length(self.soft_frame_length);
+        let read = FramedRead::with_capacity(read, codec, self.read_buffer_capacity);
+        CloudProtoSocket {
+            read,
+            write,
+            write_queue: WriteQueue::default(),
+            write_backpressure_boundary: self.write_backpressure_boundary,
+            write_backpressure_frames: self.write_backpressure_frames,
+            transform: None,
+            progress: None,
+            last_frame_received_at: None,
+            wire_logger: None,
+            dump_event_id: None,
+            peer_addr: None,
+            anomalies: AnomalyReporter::default(),
+            queued_bytes: 0,
+            queued_frame_ends: VecDeque::new(),
+        }
+    }
+}
+
+impl Default for CloudProtoSocketBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn log_frame(
+    logger: &mut Option<Box<dyn WireLogger>>,
+    direction: FrameDirection,
+    frame: &[u8],
+    timestamp: FrameTimestamp,
+) {
+    if let Some(l) = logger {
+        if let Err(e) = l.log_frame(direction, frame, timestamp) {
+            warn!("Wire logger failed, disabling it: {}", e);
+            *logger = None;
+        }
     }
 }
 
@@ -59,11 +439,30 @@ where
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
-        let pkt = match ready!(this.read.poll_next_unpin(cx)) {
-            Some(Ok(frame)) => CloudProtoPacket::from_buf(&frame),
-            Some(Err(e)) => {
-                return Poll::Ready(Some(Err(CloudProtoError::Io { source: e })));
+        let frame = this.read.poll_next_unpin(cx);
+        this.update_frame_progress();
+        let pkt = match ready!(frame) {
+            Some(Ok(mut pkt)) => {
+                let received_at = FrameTimestamp::now();
+                this.last_frame_received_at = Some(received_at);
+                if this.wire_logger.is_some() {
+                    log_frame(
+                        &mut this.wire_logger,
+                        FrameDirection::Received,
+                        &pkt.to_buf(),
+                        received_at,
+                    );
+                }
+                match &mut this.transform {
+                    Some(transform) => transform.decode(&mut pkt).map(|_| pkt).map_err(|e| {
+                        this.anomalies.report(AnomalyKind::BadFrame(e.to_string()));
+                        e
+                    }),
+                    None => Ok(pkt),
+                }
             }
+            Some(Err(e @ CloudProtoError::Io { .. })) => return Poll::Ready(Some(Err(e))),
+            Some(Err(e)) => Err(e),
             None => return Poll::Ready(None),
         };
         match pkt {
@@ -72,7 +471,7 @@ where
                     "Received kind 0x{:x} packet with 0x{:x} bytes payload: {}",
                     pkt.kind,
                     pkt.payload.len(),
-                    hex::encode(&pkt.payload),
+                    this.payload_dump(&pkt),
                 );
                 Poll::Ready(Some(Ok(pkt)))
             }
@@ -91,37 +490,73 @@ where
     type Error = std::io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        SinkExt::<Bytes>::poll_ready_unpin(&mut self.get_mut().write, cx)
+        let this = self.get_mut();
+        let too_many_frames = this
+            .write_backpressure_frames
+            .map_or(false, |limit| this.write_queue_depth().frames >= limit);
+        if this.write_queue.len() >= this.write_backpressure_boundary || too_many_frames {
+            ready!(this.write_queue.poll_write_all(&mut this.write, cx))?;
+        }
+        Poll::Ready(Ok(()))
     }
 
-    fn start_send(self: Pin<&mut Self>, pkt: CloudProtoPacket) -> Result<(), Self::Error> {
+    fn start_send(self: Pin<&mut Self>, mut pkt: CloudProtoPacket) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        let buf = Bytes::from(pkt.to_buf());
+        if let Some(transform) = &mut this.transform {
+            transform
+                .encode(&mut pkt)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
         trace!(
             "Sending kind 0x{:x} packet with 0x{:x} bytes payload: {}",
             pkt.kind,
             pkt.payload.len(),
-            hex::encode(&pkt.payload),
+            this.payload_dump(&pkt),
         );
-        this.write.start_send_unpin(buf)
+        this.prune_written_frames();
+        this.write_queue.push(&pkt);
+        this.queued_bytes += pkt.frame_len() as u64;
+        this.queued_frame_ends.push_back(this.queued_bytes);
+        if this.wire_logger.is_some() {
+            log_frame(
+                &mut this.wire_logger,
+                FrameDirection::Sent,
+                &pkt.to_buf(),
+                FrameTimestamp::now(),
+            );
+        }
+        Ok(())
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        SinkExt::<Bytes>::poll_flush_unpin(&mut self.get_mut().write, cx)
+        let this = self.get_mut();
+        ready!(this.write_queue.poll_write_all(&mut this.write, cx))?;
+        ready!(Pin::new(&mut this.write).poll_flush(cx))?;
+        this.queued_frame_ends.clear();
+        Poll::Ready(Ok(()))
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        SinkExt::<Bytes>::poll_close_unpin(&mut self.get_mut().write, cx)
+        let this = self.get_mut();
+        ready!(this.write_queue.poll_write_all(&mut this.write, cx))?;
+        ready!(Pin::new(&mut this.write).poll_flush(cx))?;
+        Pin::new(&mut this.write).poll_shutdown(cx)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::framing::{CloudProtoPacket, CloudProtoSocket, CloudProtoVersion};
+    use crate::framing::{
+        CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoSocketBuilder,
+        CloudProtoVersion, FrameTimestamp, PartialFrame, PayloadTransform, WriteQueueDepth,
+    };
     use crate::services::CloudProtoMagic;
     use anyhow::Result;
-    use futures_util::{SinkExt, StreamExt};
+    use futures_util::{FutureExt, SinkExt, StreamExt};
     use rand::Rng;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
 
     #[test_log::test(tokio::test)]
     async fn single_send_recv() -> Result<()> {
@@ -137,7 +572,7 @@ mod test {
             magic: CloudProtoMagic::TS,
             kind: 0,
             version: CloudProtoVersion::Normal,
-            payload,
+            payload: payload.into(),
         };
         client.send(pkt.clone()).await?;
         let reply = server.next().await.unwrap()?;
@@ -145,4 +580,237 @@ mod test {
 
         Ok(())
     }
+
+    #[test_log::test(tokio::test)]
+    async fn frame_shorter_than_header() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = CloudProtoSocket::new(server);
+
+        // The frame length is shorter than the header itself
+        client.write_all(&hex::decode("8f03000100000004")?).await?;
+        let err = server.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, CloudProtoError::BadFrameSize(8, 4)));
+        Ok(())
+    }
+
+    const XOR_VERSION: u16 = 0x7F01;
+
+    // Toy transform, a real one would add an HMAC or encrypt
+    struct XorTransform(u8);
+
+    impl PayloadTransform for XorTransform {
+        fn encode(&mut self, pkt: &mut CloudProtoPacket) -> Result<(), CloudProtoError> {
+            pkt.payload = pkt.payload.iter().map(|b| b ^ self.0).collect();
+            pkt.version = CloudProtoVersion::Other(XOR_VERSION);
+            Ok(())
+        }
+
+        fn decode(&mut self, pkt: &mut CloudProtoPacket) -> Result<(), CloudProtoError> {
+            if pkt.version != CloudProtoVersion::Other(XOR_VERSION) {
+                return Err(CloudProtoError::TransformFailed(format!(
+                    "Packet is not transformed, version {:#x}",
+                    pkt.version
+                )));
+            }
+            pkt.payload = pkt.payload.iter().map(|b| b ^ self.0).collect();
+            pkt.version = CloudProtoVersion::Normal;
+            Ok(())
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn small_buffers() -> Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let builder = CloudProtoSocketBuilder::new()
+            .read_buffer_capacity(64)
+            .write_backpressure_boundary(64)
+            .max_frame_length(0x1000);
+        let mut client = builder.build(client);
+        let mut server = builder.build(server);
+
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 0,
+            version: CloudProtoVersion::Normal,
+            payload: vec![0x42; 0x800].into(),
+        };
+        let sent = pkt.clone();
+        let send_task = tokio::spawn(async move { client.send(sent).await });
+        assert_eq!(server.next().await.unwrap()?, pkt);
+        send_task.await??;
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn write_queue_drain() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = CloudProtoSocketBuilder::new()
+            .write_backpressure_boundary(1024 * 1024)
+            .build(client);
+        let mut server = CloudProtoSocket::new(server);
+        assert_eq!(client.write_queue_depth(), WriteQueueDepth::default());
+
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 0,
+            version: CloudProtoVersion::Normal,
+            payload: vec![0x42; 92].into(),
+        };
+        for _ in 0..3 {
+            client.feed(pkt.clone()).await?;
+        }
+        assert_eq!(
+            client.write_queue_depth(),
+            WriteQueueDepth {
+                frames: 3,
+                bytes: 300
+            }
+        );
+
+        let server_task = tokio::spawn(async move {
+            for _ in 0..3 {
+                server.next().await.unwrap()?;
+            }
+            Ok::<_, CloudProtoError>(())
+        });
+        client.wait_for_drain(150).await?;
+        let depth = client.write_queue_depth();
+        assert!(depth.bytes <= 150 && depth.bytes > 0);
+        assert!(depth.frames <= 2 && depth.frames > 0);
+        client.wait_for_drain(0).await?;
+        assert_eq!(client.write_queue_depth(), WriteQueueDepth::default());
+        server_task.await??;
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn write_backpressure() -> Result<()> {
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 0,
+            version: CloudProtoVersion::Normal,
+            payload: vec![0x42; 92].into(),
+        };
+        // The peer never reads, so the IO accepts a single frame
+        let (client, _server) = tokio::io::duplex(100);
+        let mut client = CloudProtoSocketBuilder::new()
+            .write_backpressure_boundary(1024 * 1024)
+            .write_backpressure_frames(3)
+            .build(client);
+        for _ in 0..3 {
+            client.feed(pkt.clone()).await?;
+        }
+        assert_eq!(client.write_queue_depth().frames, 3);
+        assert!(client.feed(pkt.clone()).now_or_never().is_none());
+        assert_eq!(client.write_queue_depth().frames, 2);
+
+        let (client, _server) = tokio::io::duplex(100);
+        let mut client = CloudProtoSocketBuilder::new()
+            .write_backpressure_boundary(250)
+            .build(client);
+        for _ in 0..3 {
+            client.feed(pkt.clone()).await?;
+        }
+        assert!(client.feed(pkt).now_or_never().is_none());
+        assert_eq!(client.write_queue_depth().bytes, 200);
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn partial_frame_progress() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(100 * 1024);
+        let mut server = CloudProtoSocket::new(server);
+        let progress = server.frame_progress();
+
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 0,
+            version: CloudProtoVersion::Normal,
+            payload: vec![0xAA; 0x100].into(),
+        };
+        let buf = pkt.to_buf();
+        client.write_all(&buf[..0x40]).await?;
+        assert!(server.next().now_or_never().is_none());
+        assert_eq!(
+            progress.current(),
+            Some(PartialFrame {
+                received: 0x40,
+                announced: 0x108
+            })
+        );
+
+        client.write_all(&buf[0x40..]).await?;
+        assert_eq!(server.next().await.unwrap()?, pkt);
+        assert_eq!(progress.current(), None);
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn payload_transform() -> Result<()> {
+        let (client, server) = tokio::io::duplex(100 * 1024);
+        let (raw_client, raw_server) = tokio::io::duplex(100 * 1024);
+        let mut client = CloudProtoSocket::new(client);
+        let mut server = CloudProtoSocket::new(server);
+        client.set_payload_transform(Some(Box::new(XorTransform(0x5A))));
+        server.set_payload_transform(Some(Box::new(XorTransform(0x5A))));
+        let mut raw_client = CloudProtoSocket::new(raw_client);
+        let mut raw_server = CloudProtoSocket::new(raw_server);
+        raw_server.set_payload_transform(Some(Box::new(XorTransform(0x5A))));
+
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 0,
+            version: CloudProtoVersion::Normal,
+            payload: vec![1, 2, 3].into(),
+        };
+        client.send(pkt.clone()).await?;
+        assert_eq!(server.next().await.unwrap()?, pkt);
+
+        // A peer without the transform is rejected
+        raw_client.send(pkt).await?;
+        assert!(matches!(
+            raw_server.next().await,
+            Some(Err(CloudProtoError::TransformFailed(_)))
+        ));
+        Ok(())
+    }
+
+    // Records when payloads are decoded, and makes decoding slow
+    struct SlowDecode(Arc<Mutex<Option<FrameTimestamp>>>);
+
+    impl PayloadTransform for SlowDecode {
+        fn encode(&mut self, _pkt: &mut CloudProtoPacket) -> Result<(), CloudProtoError> {
+            Ok(())
+        }
+
+        fn decode(&mut self, _pkt: &mut CloudProtoPacket) -> Result<(), CloudProtoError> {
+            std::thread::sleep(Duration::from_millis(20));
+            *self.0.lock().unwrap() = Some(FrameTimestamp::now());
+            Ok(())
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn frame_timestamps() -> Result<()> {
+        let (client, server) = tokio::io::duplex(100 * 1024);
+        let mut client = CloudProtoSocket::new(client);
+        let mut server = CloudProtoSocket::new(server);
+        let decoded_at = Arc::new(Mutex::new(None));
+        server.set_payload_transform(Some(Box::new(SlowDecode(decoded_at.clone()))));
+        assert_eq!(server.last_frame_received_at(), None);
+
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 0,
+            version: CloudProtoVersion::Normal,
+            payload: vec![1, 2, 3].into(),
+        };
+        client.send(pkt.clone()).await?;
+        assert_eq!(server.next().await.unwrap()?, pkt);
+
+        let received_at = server.last_frame_received_at().unwrap();
+        let decoded_at = decoded_at.lock().unwrap().unwrap();
+        assert!(decoded_at.monotonic - received_at.monotonic >= Duration::from_millis(20));
+        Ok(())
+    }
 }
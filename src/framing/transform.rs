@@ -0,0 +1,18 @@
+use crate::framing::{CloudProtoError, CloudProtoPacket};
+
+/// Hook to transform packets at the framing layer, e.g. to encrypt or sign payloads.
+///
+/// The official client and server know nothing about transforms, so this is only useful
+/// between your own peers, for instance to wrap payloads in an HMAC envelope over an untrusted link.
+/// Transforms should mark the packets they produce, typically with a reserved
+/// [`CloudProtoVersion::Other`](super::CloudProtoVersion::Other) value,
+/// and reject unmarked packets on receive, so that both sides can't silently disagree.
+///
+/// See [`CloudProtoSocket::set_payload_transform`](super::CloudProtoSocket::set_payload_transform).
+pub trait PayloadTransform: Send {
+    /// Called on every packet before it is sent
+    fn encode(&mut self, pkt: &mut CloudProtoPacket) -> Result<(), CloudProtoError>;
+
+    /// Called on every received packet, before it is returned to higher layers
+    fn decode(&mut self, pkt: &mut CloudProtoPacket) -> Result<(), CloudProtoError>;
+}
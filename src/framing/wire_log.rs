@@ -0,0 +1,187 @@
+use crate::framing::FrameTimestamp;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+// pcap link type for Wireshark's "exported PDU" pseudo-protocol
+const LINKTYPE_WIRESHARK_UPPER_PDU: u32 = 252;
+// Wireshark refuses to open files with larger packets, so longer frames are truncated
+const PCAP_SNAPLEN: usize = 256 * 1024;
+
+// Tags from Wireshark's epan/exported_pdu.h
+const EXP_PDU_TAG_END_OF_OPT: u16 = 0;
+const EXP_PDU_TAG_DISSECTOR_NAME: u16 = 12;
+const EXP_PDU_TAG_P2P_DIRECTION: u16 = 35;
+// Values of P2P_DIRECTION
+const P2P_DIR_SENT: u32 = 0;
+const P2P_DIR_RECV: u32 = 1;
+
+/// Whether a frame was sent or received by the local socket
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameDirection {
+    Sent,
+    Received,
+}
+
+/// Receives a copy of every frame sent or received by a [`CloudProtoSocket`](super::CloudProtoSocket),
+/// see [`set_wire_logger`](super::CloudProtoSocket::set_wire_logger).
+///
+/// Frames are complete CLOUDPROTO frames with their header, as they are on the wire
+/// inside TLS (so after any [`PayloadTransform`](super::PayloadTransform) encoding).
+pub trait WireLogger: Send {
+    fn log_frame(
+        &mut self,
+        direction: FrameDirection,
+        frame: &[u8],
+        timestamp: FrameTimestamp,
+    ) -> io::Result<()>;
+}
+
+/// Lets several sockets log to the same destination
+impl<L: WireLogger> WireLogger for Arc<Mutex<L>> {
+    fn log_frame(
+        &mut self,
+        direction: FrameDirection,
+        frame: &[u8],
+        timestamp: FrameTimestamp,
+    ) -> io::Result<()> {
+        self.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .log_frame(direction, frame, timestamp)
+    }
+}
+
+/// Writes frames to a pcap file as Wireshark "exported PDU" records.
+///
+/// Each record is tagged with the dissector name `cloudproto` and the P2P direction
+/// of the frame (sent or received), so a Lua dissector registered with that name
+/// (`register_dissector("cloudproto", ...)`) is handed the raw frames directly, without
+/// having to reassemble TCP or decrypt TLS. Frames larger than 256KiB are truncated in the file.
+pub struct ExportedPduWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> ExportedPduWriter<W> {
+    /// Writes the pcap file header, then returns a writer for the records
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?; // Version 2.4
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&0i32.to_le_bytes())?; // UTC
+        out.write_all(&0u32.to_le_bytes())?; // Timestamp accuracy
+        out.write_all(&(PCAP_SNAPLEN as u32).to_le_bytes())?;
+        out.write_all(&LINKTYPE_WIRESHARK_UPPER_PDU.to_le_bytes())?;
+        Ok(Self { out })
+    }
+
+    /// Flushes and returns the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn push_tag(record: &mut Vec<u8>, tag: u16, value: &[u8]) {
+    // Values are padded to a multiple of 4 bytes, and the padding counts in the length
+    let padded_len = (value.len() + 3) & !3;
+    record.extend_from_slice(&tag.to_be_bytes());
+    record.extend_from_slice(&(padded_len as u16).to_be_bytes());
+    record.extend_from_slice(value);
+    record.resize(record.len() + padded_len - value.len(), 0);
+}
+
+impl<W: Write + Send> WireLogger for ExportedPduWriter<W> {
+    fn log_frame(
+        &mut self,
+        direction: FrameDirection,
+        frame: &[u8],
+        timestamp: FrameTimestamp,
+    ) -> io::Result<()> {
+        let direction = match direction {
+            FrameDirection::Sent => P2P_DIR_SENT,
+            FrameDirection::Received => P2P_DIR_RECV,
+        };
+        let mut record = Vec::with_capacity(32 + frame.len());
+        push_tag(&mut record, EXP_PDU_TAG_DISSECTOR_NAME, b"cloudproto");
+        push_tag(
+            &mut record,
+            EXP_PDU_TAG_P2P_DIRECTION,
+            &direction.to_be_bytes(),
+        );
+        push_tag(&mut record, EXP_PDU_TAG_END_OF_OPT, &[]);
+        record.extend_from_slice(frame);
+
+        let since_epoch = timestamp
+            .wall
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured_len = record.len().min(PCAP_SNAPLEN);
+        self.out
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.out
+            .write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        self.out.write_all(&(captured_len as u32).to_le_bytes())?;
+        self.out.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.out.write_all(&record[..captured_len])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::{CloudProtoPacket, CloudProtoSocket, CloudProtoVersion};
+    use crate::services::CloudProtoMagic;
+    use futures_util::{SinkExt, StreamExt};
+
+    #[test_log::test(tokio::test)]
+    async fn exported_pdu_capture() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = CloudProtoSocket::new(client);
+        let mut server = CloudProtoSocket::new(server);
+        let pcap = Arc::new(Mutex::new(ExportedPduWriter::new(Vec::new())?));
+        client.set_wire_logger(Some(Box::new(pcap.clone())));
+        server.set_wire_logger(Some(Box::new(pcap.clone())));
+
+        let pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: 3,
+            version: CloudProtoVersion::Normal,
+            payload: vec![0xAA; 4].into(),
+        };
+        client.send(pkt.clone()).await?;
+        assert_eq!(server.next().await.unwrap()?, pkt);
+        drop((client, server));
+
+        let pcap = Arc::try_unwrap(pcap).ok().unwrap();
+        let pcap = pcap.into_inner().unwrap().into_inner()?;
+        assert_eq!(
+            hex::encode(&pcap[..24]),
+            "d4c3b2a1020004000000000000000000\
+             00000400fc000000"
+        );
+
+        // Two records: sent by the client, then received by the server
+        let record = |direction: &str| {
+            format!(
+                "000c000c{}0000\
+                 00230004{}\
+                 00000000\
+                 8f0300010000000caaaaaaaa",
+                hex::encode("cloudproto"),
+                direction
+            )
+        };
+        let (sent, received) = pcap[24..].split_at(16 + 40);
+        assert_eq!(hex::encode(&sent[8..16]), "2800000028000000");
+        assert_eq!(hex::encode(&sent[16..]), record("00000000"));
+        assert_eq!(hex::encode(&received[16..]), record("00000001"));
+
+        // Huge frames are truncated
+        let mut writer = ExportedPduWriter::new(Vec::new())?;
+        let huge = vec![0u8; PCAP_SNAPLEN + 100];
+        writer.log_frame(FrameDirection::Sent, &huge, FrameTimestamp::now())?;
+        let out = writer.into_inner()?;
+        assert_eq!(out.len(), 24 + 16 + PCAP_SNAPLEN);
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use crate::framing::packet::CloudProtoPacket;
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+
+// Payloads up to this size are cheaper to copy next to their header than to write as a separate slice
+const INLINE_PAYLOAD_LENGTH: usize = 1024;
+// Most platforms accept at least this many slices in a single writev
+const MAX_IO_SLICES: usize = 64;
+
+/// Frames waiting to be written by a [`CloudProtoSocket`](super::CloudProtoSocket).
+///
+/// Headers and small payloads are copied into a staging buffer. Larger payloads are queued
+/// as they are, and written next to their header with vectored IO, so they are never copied.
+#[derive(Debug, Default)]
+pub(crate) struct WriteQueue {
+    staging: BytesMut,
+    chunks: VecDeque<Bytes>,
+    len: usize,
+}
+
+impl WriteQueue {
+    /// Bytes queued and not yet written
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Memory held by the queue. This counts large payloads, which may be shared with the application.
+    pub(crate) fn capacity(&self) -> usize {
+        self.staging.capacity() + self.chunks.iter().map(Bytes::len).sum::<usize>()
+    }
+
+    pub(crate) fn push(&mut self, pkt: &CloudProtoPacket) {
+        self.len += pkt.frame_len();
+        if pkt.payload.len() <= INLINE_PAYLOAD_LENGTH {
+            pkt.encode_into(&mut self.staging);
+            return;
+        }
+        pkt.encode_header_into(&mut self.staging);
+        self.chunks.push_back(self.staging.split().freeze());
+        self.chunks.push_back(pkt.payload.clone());
+    }
+
+    /// Writes queued data until the queue is empty. Does not flush the IO.
+    pub(crate) fn poll_write_all<IO: AsyncWrite + Unpin>(
+        &mut self,
+        io: &mut IO,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.staging.is_empty() {
+            self.chunks.push_back(self.staging.split().freeze());
+        }
+        while self.len > 0 {
+            let slices: Vec<IoSlice> = self
+                .chunks
+                .iter()
+                .take(MAX_IO_SLICES)
+                .map(|chunk| IoSlice::new(chunk))
+                .collect();
+            let written = match Pin::new(&mut *io).poll_write_vectored(cx, &slices) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "failed to write frame to socket",
+                    )))
+                }
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn advance(&mut self, mut written: usize) {
+        self.len -= written;
+        while written > 0 {
+            let chunk = self.chunks.front_mut().expect("Wrote more than was queued");
+            if chunk.len() > written {
+                chunk.advance(written);
+                return;
+            }
+            written -= chunk.len();
+            self.chunks.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::CloudProtoVersion;
+    use crate::services::CloudProtoMagic;
+    use tokio::io::AsyncReadExt;
+
+    #[test_log::test(tokio::test)]
+    async fn vectored_writes() {
+        let pkt = |len: usize| CloudProtoPacket {
+            magic: CloudProtoMagic::LFO,
+            kind: 2,
+            version: CloudProtoVersion::Normal,
+            payload: vec![len as u8; len].into(),
+        };
+        let pkts = [pkt(0x10), pkt(0x10000), pkt(0), pkt(0x2000)];
+        let mut queue = WriteQueue::default();
+        let mut expected = BytesMut::new();
+        for pkt in &pkts {
+            queue.push(pkt);
+            pkt.encode_into(&mut expected);
+        }
+        assert_eq!(queue.len(), expected.len());
+
+        // Large payloads are queued without copies
+        let payload_ptr = pkts[1].payload.as_ptr();
+        assert!(queue
+            .chunks
+            .iter()
+            .any(|chunk| chunk.as_ptr() == payload_ptr));
+
+        // A small pipe forces partial writes in the middle of chunks
+        let (mut client, mut server) = tokio::io::duplex(0x333);
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.map(|_| received)
+        });
+        futures_util::future::poll_fn(|cx| queue.poll_write_all(&mut client, cx))
+            .await
+            .unwrap();
+        assert_eq!(queue.len(), 0);
+        drop(client);
+        assert_eq!(reader.await.unwrap().unwrap(), expected);
+    }
+}
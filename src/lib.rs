@@ -3,5 +3,15 @@
 
 extern crate core;
 
+mod anomaly;
+mod error;
 pub mod framing;
+mod limits;
+pub mod redaction;
 pub mod services;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use anomaly::{Anomaly, AnomalyKind, AnomalySeverity};
+pub use error::Error;
+pub use limits::WalkLimits;
@@ -0,0 +1,34 @@
+pub(crate) const DEFAULT_MAX_OUTPUT_LEN: usize = 1024 * 1024;
+
+/// Bounds on the work and output of the tools that walk untrusted payloads.
+///
+/// A hostile event can nest messages deeply or pack many tiny fields, which would make
+/// decoded output much larger than the event itself, or take a long time to walk.
+/// These limits are honored by the JSON decoder ([`event_to_json_with_limits`](crate::services::ts::event_to_json_with_limits)),
+/// string extraction ([`Event::extract_strings_with_limits`](crate::services::ts::Event::extract_strings_with_limits)),
+/// schema inference and validation, and payload dumps in trace logs
+/// ([`set_payload_dump_limits`](crate::redaction::set_payload_dump_limits)).
+///
+/// In JSON, when a limit is reached the rest of the message is replaced by a `"truncated"` marker key,
+/// whose value is `"depth"`, `"fields"` or `"output_size"`, and the output stays valid JSON.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WalkLimits {
+    /// Nested messages deeper than this are not walked. Defaults to 16.
+    pub max_depth: usize,
+    /// Maximum number of fields walked in an event, including nested ones. Defaults to 10000.
+    pub max_fields: usize,
+    /// Approximate maximum size of the JSON for one event, of the strings extracted from one event,
+    /// or of a payload dump. Defaults to 1MiB.
+    /// In JSON the hex `data` is cut to this size too, and then followed by `"data_truncated":true`.
+    pub max_output_len: usize,
+}
+
+impl Default for WalkLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 16,
+            max_fields: 10_000,
+            max_output_len: DEFAULT_MAX_OUTPUT_LEN,
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Crate-wide policy controlling whether sensitive values appear in logs and error messages.
+//!
+//! CIDs and AIDs identify a Crowdstrike customer and one of their machines,
+//! and event payloads routinely contain hostnames, usernames or command lines.
+//! When redaction is enabled, identifiers are shortened to a short prefix (enough to tell
+//! a few machines apart in a log) and payloads are replaced by their length.
+//!
+//! Redaction is disabled by default, and can be toggled at any time with [`set_redaction`].
+//!
+//! Full payload dumps in trace logs are also a performance problem for busy collectors,
+//! so they can be sampled ([`set_payload_dump_sample_rate`]), truncated ([`set_payload_dump_max_len`]),
+//! or suppressed for specific event IDs ([`set_suppressed_payload_event_ids`]).
+//! Dumps are always bounded by the output size of the [`WalkLimits`](WalkLimits), see [`set_payload_dump_limits`].
+
+use crate::limits::DEFAULT_MAX_OUTPUT_LEN;
+use crate::WalkLimits;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+/// Number of bytes of an identifier that are still shown when redaction is enabled
+pub const REDACTED_ID_PREFIX_LEN: usize = 2;
+
+static REDACTION_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable redaction of CIDs, AIDs and payloads in logs and error messages
+pub fn set_redaction(enabled: bool) {
+    REDACTION_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether CIDs, AIDs and payloads are currently redacted in logs and error messages
+pub fn redaction_enabled() -> bool {
+    REDACTION_ENABLED.load(Ordering::Relaxed)
+}
+
+static PAYLOAD_DUMP_SAMPLE_RATE: AtomicU32 = AtomicU32::new(1);
+static PAYLOAD_DUMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+static PAYLOAD_DUMP_MAX_LEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+static PAYLOAD_DUMP_MAX_OUTPUT_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_OUTPUT_LEN);
+static SUPPRESSED_PAYLOAD_EVENT_IDS: RwLock<Vec<u32>> = RwLock::new(Vec::new());
+
+/// Only dump one in every `one_in` payloads in trace logs. Values of 0 and 1 dump every payload.
+pub fn set_payload_dump_sample_rate(one_in: u32) {
+    PAYLOAD_DUMP_SAMPLE_RATE.store(one_in.max(1), Ordering::Relaxed);
+}
+
+/// Only dump the first `max_len` bytes of payloads in trace logs, or everything if `None`
+pub fn set_payload_dump_max_len(max_len: Option<usize>) {
+    PAYLOAD_DUMP_MAX_LEN.store(max_len.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// Bound the size of payload dumps in trace logs to the `max_output_len` of these limits,
+/// on top of [`set_payload_dump_max_len`]. Dumps do not walk payloads, so other limits are ignored.
+pub fn set_payload_dump_limits(limits: &WalkLimits) {
+    PAYLOAD_DUMP_MAX_OUTPUT_LEN.store(limits.max_output_len, Ordering::Relaxed);
+}
+
+fn payload_dump_max_len() -> usize {
+    combined_max_len(
+        PAYLOAD_DUMP_MAX_LEN.load(Ordering::Relaxed),
+        PAYLOAD_DUMP_MAX_OUTPUT_LEN.load(Ordering::Relaxed),
+    )
+}
+
+// Each payload byte is dumped as two hex digits
+fn combined_max_len(max_len: usize, max_output_len: usize) -> usize {
+    max_len.min(max_output_len / 2)
+}
+
+/// Never dump the payloads of events with these raw event IDs in trace logs
+pub fn set_suppressed_payload_event_ids(raw_event_ids: &[u32]) {
+    let mut ids = SUPPRESSED_PAYLOAD_EVENT_IDS
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    *ids = raw_event_ids.to_vec();
+}
+
+fn payload_event_id_suppressed(raw_event_id: u32) -> bool {
+    SUPPRESSED_PAYLOAD_EVENT_IDS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(&raw_event_id)
+}
+
+/// Formats an identifier (CID, AID, ...) as hex, or only its first bytes when redacting
+pub(crate) struct SensitiveId<'a>(pub &'a [u8]);
+
+impl Display for SensitiveId<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if redaction_enabled() && self.0.len() > REDACTED_ID_PREFIX_LEN {
+            write!(f, "{}...", hex::encode(&self.0[..REDACTED_ID_PREFIX_LEN]))
+        } else {
+            f.write_str(&hex::encode(self.0))
+        }
+    }
+}
+
+impl Debug for SensitiveId<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// Formats a payload as hex, or only its length when redacting
+pub(crate) struct SensitivePayload<'a>(pub &'a [u8]);
+
+impl Display for SensitivePayload<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if redaction_enabled() {
+            write!(f, "<{:#x} bytes redacted>", self.0.len())
+        } else {
+            f.write_str(&hex::encode(self.0))
+        }
+    }
+}
+
+impl Debug for SensitivePayload<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if redaction_enabled() {
+            Display::fmt(self, f)
+        } else {
+            Debug::fmt(self.0, f)
+        }
+    }
+}
+
+/// Formats a payload for trace logs, honoring the redaction, sampling, length and event ID settings.
+///
+/// Each time a dump is actually formatted counts towards the sampling rate,
+/// so payloads that are filtered out by the log level do not skew sampling.
+pub(crate) struct PayloadDump<'a> {
+    pub payload: &'a [u8],
+    pub raw_event_id: Option<u32>,
+}
+
+impl<'a> PayloadDump<'a> {
+    pub fn new(payload: &'a [u8]) -> Self {
+        Self {
+            payload,
+            raw_event_id: None,
+        }
+    }
+
+    pub fn for_event(payload: &'a [u8], raw_event_id: Option<u32>) -> Self {
+        Self {
+            payload,
+            raw_event_id,
+        }
+    }
+}
+
+// A snapshot of the global dump settings for one payload,
+// so that formatting can be tested without changing them
+struct DumpSettings {
+    suppressed: bool,
+    sample_rate: u64,
+    max_len: usize,
+}
+
+impl DumpSettings {
+    fn current(raw_event_id: Option<u32>) -> Self {
+        Self {
+            suppressed: raw_event_id.map_or(false, payload_event_id_suppressed),
+            sample_rate: PAYLOAD_DUMP_SAMPLE_RATE.load(Ordering::Relaxed) as u64,
+            max_len: payload_dump_max_len(),
+        }
+    }
+}
+
+impl PayloadDump<'_> {
+    // `next_count` is only called for payloads that are not suppressed
+    fn write_with(
+        &self,
+        f: &mut Formatter<'_>,
+        settings: &DumpSettings,
+        next_count: impl FnOnce() -> u64,
+    ) -> std::fmt::Result {
+        if settings.suppressed {
+            return write!(
+                f,
+                "<{:#x} bytes suppressed for event {:#X}>",
+                self.payload.len(),
+                self.raw_event_id.unwrap_or_default()
+            );
+        }
+        if next_count() % settings.sample_rate != 0 {
+            return write!(f, "<{:#x} bytes sampled out>", self.payload.len());
+        }
+        if self.payload.len() > settings.max_len && !redaction_enabled() {
+            write!(
+                f,
+                "{}... ({:#x} bytes total)",
+                SensitivePayload(&self.payload[..settings.max_len]),
+                self.payload.len()
+            )
+        } else {
+            Display::fmt(&SensitivePayload(self.payload), f)
+        }
+    }
+}
+
+impl Display for PayloadDump<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let settings = DumpSettings::current(self.raw_event_id);
+        self.write_with(f, &settings, || {
+            PAYLOAD_DUMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    // These tests change global settings, so they must not run in parallel
+    static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+    // Formats a dump with explicit settings, leaving the global ones alone
+    struct WithSettings<'a>(PayloadDump<'a>, DumpSettings, u64);
+
+    impl Display for WithSettings<'_> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            self.0.write_with(f, &self.1, || self.2)
+        }
+    }
+
+    fn settings(sample_rate: u64, max_len: usize) -> DumpSettings {
+        DumpSettings {
+            suppressed: false,
+            sample_rate,
+            max_len,
+        }
+    }
+
+    #[test]
+    fn payload_dump_settings() {
+        // Redaction is still read from the global setting
+        let _guard = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let payload = [1, 2, 3, 4];
+
+        assert_eq!(
+            WithSettings(PayloadDump::new(&payload), settings(1, 2), 0).to_string(),
+            "0102... (0x4 bytes total)"
+        );
+        assert_eq!(
+            WithSettings(PayloadDump::new(&payload), settings(1, usize::MAX), 0).to_string(),
+            "01020304"
+        );
+
+        let suppressed = DumpSettings {
+            suppressed: true,
+            ..settings(1, usize::MAX)
+        };
+        assert_eq!(
+            WithSettings(
+                PayloadDump::for_event(&payload, Some(0xAABBCCDD)),
+                suppressed,
+                0
+            )
+            .to_string(),
+            "<0x4 bytes suppressed for event 0xAABBCCDD>"
+        );
+
+        let dumps: Vec<_> = (0..6)
+            .map(|count| {
+                WithSettings(PayloadDump::new(&payload), settings(3, 4), count).to_string()
+            })
+            .collect();
+        assert_eq!(dumps[0], "01020304");
+        assert_eq!(dumps[1], "<0x4 bytes sampled out>");
+        assert_eq!(dumps.iter().filter(|d| *d == "01020304").count(), 2);
+    }
+
+    #[test]
+    fn suppressed_event_ids() {
+        let _guard = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_suppressed_payload_event_ids(&[0xAABBCCDD]);
+        assert!(DumpSettings::current(Some(0xAABBCCDD)).suppressed);
+        assert!(!DumpSettings::current(Some(0x1)).suppressed);
+        assert!(!DumpSettings::current(None).suppressed);
+        set_suppressed_payload_event_ids(&[]);
+    }
+
+    #[test]
+    fn payload_dump_max_len_limits() {
+        assert_eq!(combined_max_len(usize::MAX, 2), 1);
+        assert_eq!(combined_max_len(2, 16), 2);
+        assert_eq!(combined_max_len(usize::MAX, 3), 1);
+    }
+
+    #[test]
+    fn redacted_formatting() {
+        let _guard = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let id = [0xAA, 0xBB, 0xCC, 0xDD];
+        let payload = [1, 2, 3];
+
+        set_redaction(false);
+        assert_eq!(SensitiveId(&id).to_string(), "aabbccdd");
+        assert_eq!(SensitivePayload(&payload).to_string(), "010203");
+        assert_eq!(format!("{:?}", SensitivePayload(&payload)), "[1, 2, 3]");
+
+        set_redaction(true);
+        assert_eq!(SensitiveId(&id).to_string(), "aabb...");
+        assert_eq!(
+            SensitivePayload(&payload).to_string(),
+            "<0x3 bytes redacted>"
+        );
+        assert_eq!(
+            format!("{:?}", SensitivePayload(&payload)),
+            "<0x3 bytes redacted>"
+        );
+        set_redaction(false);
+    }
+}
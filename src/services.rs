@@ -15,6 +15,22 @@ pub const DEFAULT_BOOTID_HEX: &str = "6c959680d4945d45924301a720debc88";
 /// Arbitrary machine-specific value generated on an isolated VM
 pub const DEFAULT_UNK0_HEX: &str = "54645dacc392cb43b4803094141e0087";
 
+// FNV-1a, to fold an arbitrary seed into the state of splitmix64
+pub(crate) fn fold_seed(seed: &[u8]) -> u64 {
+    seed.iter().fold(0xcbf29ce484222325u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Output n of the splitmix64 generator started from `state`.
+// This is a bijection of n, so different positions never give the same output.
+pub(crate) fn splitmix64(state: u64, n: u64) -> u64 {
+    let mut z = state.wrapping_add(n.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
 #[repr(u8)]
 #[derive(Eq, PartialEq, Debug, Copy, Clone, Display, EnumCount, FromRepr)]
 pub enum CloudProtoMagic {
@@ -1,16 +1,30 @@
 //! High-level support for the LFO file server
 
+pub mod blocking;
 mod client;
+mod compression;
+mod content;
 mod file_header;
+mod identity;
+mod metadata;
 mod pkt_kind;
+mod report;
 mod request;
 mod response;
 
 use bytes::Bytes;
-pub use client::LfoClient;
+pub use client::{LfoClient, LfoClientHandle};
+#[cfg(feature = "lfo-compress-xz")]
+pub use compression::XzCompression;
+pub use compression::{register_lfo_compression, LfoCompression};
+pub use content::ContentFormat;
 pub use file_header::{CompressionFormats, LfoFileHeader};
+pub use identity::LfoIdentity;
+pub use metadata::LfoMetadata;
+pub use pkt_kind::LfoPacketKind;
+pub use report::TransferReport;
 pub use request::LfoRequest;
-pub use response::LfoResponse;
+pub use response::{DecompressionLimits, LfoResponse};
 
 use crate::framing::CloudProtoError;
 use thiserror::Error;
@@ -29,6 +43,8 @@ pub enum LfoError {
     ReplyParseError { reason: String, raw_payload: Bytes },
     #[error("LFO data has final size {actual}, but expected {expected}")]
     InvalidFinalSize { expected: usize, actual: usize },
+    #[error("LFO data announced to decompress to {announced} bytes, but the limit is {limit}")]
+    DecompressionLimitExceeded { announced: usize, limit: usize },
     #[error("LFO data has an invalid hash, it may be corrupt")]
     InvalidHash {
         expected: [u8; 32],
@@ -0,0 +1,96 @@
+//! A synchronous [`LfoClient`](LfoClient), for small tools that don't want a tokio runtime.
+
+use crate::framing::blocking::CloudProtoSocket;
+use crate::services::lfo::client::{request_packet, response_from_reply};
+use crate::services::lfo::response::DEFAULT_OFFLOAD_THRESHOLD;
+use crate::services::lfo::{DecompressionLimits, LfoError, LfoIdentity, LfoRequest, LfoResponse};
+use std::io::{Read, Write};
+use std::time::Instant;
+
+/// Blocking version of the async [`LfoClient`](super::LfoClient).
+///
+/// Use [`LfoResponse::data`](LfoResponse::data) to get the file contents of the responses.
+pub struct LfoClient<IO: Read + Write> {
+    sock: CloudProtoSocket<IO>,
+    limits: DecompressionLimits,
+    default_identity: LfoIdentity,
+}
+
+impl<IO: Read + Write> LfoClient<IO> {
+    pub fn new(sock: CloudProtoSocket<IO>) -> Self {
+        Self {
+            sock,
+            limits: Default::default(),
+            default_identity: LfoIdentity::anonymous(),
+        }
+    }
+
+    /// Set the identity sent with requests that don't have their own,
+    /// see [`LfoRequest::set_identity`](LfoRequest::set_identity).
+    /// Defaults to [`LfoIdentity::anonymous`](LfoIdentity::anonymous).
+    pub fn set_default_identity(&mut self, identity: LfoIdentity) {
+        self.default_identity = identity;
+    }
+
+    /// Set the [`DecompressionLimits`](DecompressionLimits) of future responses
+    pub fn set_decompression_limits(&mut self, limits: DecompressionLimits) {
+        self.limits = limits;
+    }
+
+    pub fn into_inner(self) -> CloudProtoSocket<IO> {
+        self.sock
+    }
+
+    /// Download the file at the remote path specified in the [`LfoRequest`](LfoRequest).
+    pub fn get(&mut self, request: &LfoRequest) -> Result<LfoResponse, LfoError> {
+        let start = Instant::now();
+        self.sock
+            .send(request_packet(request, &self.default_identity))?;
+        let reply = self.sock.recv()?;
+        response_from_reply(
+            reply,
+            start.elapsed(),
+            self.limits,
+            Some(DEFAULT_OFFLOAD_THRESHOLD),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::{CloudProtoPacket, CloudProtoVersion};
+    use crate::services::lfo::test::TEST_REPLY_DATA;
+    use crate::services::lfo::LfoPacketKind;
+    use crate::services::CloudProtoMagic;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test_log::test]
+    fn blocking_request() -> Result<(), LfoError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = std::thread::spawn(move || {
+            let mut server = CloudProtoSocket::new(listener.accept()?.0);
+            let req = server.recv()?.unwrap();
+            assert_eq!(req.kind, LfoPacketKind::GetFileRequest);
+            let req = LfoRequest::try_from_payload(&req.payload)?;
+            server.send(CloudProtoPacket {
+                magic: CloudProtoMagic::LFO,
+                kind: LfoPacketKind::ReplyOk.into(),
+                version: CloudProtoVersion::Normal,
+                payload: hex::decode(TEST_REPLY_DATA).unwrap().into(),
+            })?;
+            Ok::<_, LfoError>(req.remote_path)
+        });
+
+        let mut client = LfoClient::new(CloudProtoSocket::new(TcpStream::connect(addr)?));
+        let reply = client.get(&LfoRequest::new_simple("/test/foo".to_string()))?;
+        assert_eq!(hex::encode(reply.raw_lfo_payload()), TEST_REPLY_DATA);
+        assert!(!reply.data()?.is_empty());
+        assert_eq!(server.join().unwrap()?, "/test/foo");
+        assert!(client
+            .get(&LfoRequest::new_simple("/test/foo".to_string()))
+            .is_err());
+        Ok(())
+    }
+}
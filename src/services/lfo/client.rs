@@ -1,15 +1,30 @@
-use crate::framing::{CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoVersion};
+use crate::framing::{
+    CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoVersion, FrameProgress,
+    MemoryReport,
+};
+use crate::redaction::PayloadDump;
 use crate::services::lfo::pkt_kind::LfoPacketKind;
 use crate::services::lfo::request::LfoRequest;
-use crate::services::lfo::{LfoError, LfoResponse};
+use crate::services::lfo::response::DEFAULT_OFFLOAD_THRESHOLD;
+use crate::services::lfo::{DecompressionLimits, LfoError, LfoIdentity, LfoResponse};
 use crate::services::CloudProtoMagic;
+use crate::Anomaly;
 use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::trace;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+// Maximum number of requests waiting for the connection in a LfoClientHandle
+const HANDLE_QUEUE_LEN: usize = 32;
 
 /// Request files stored on an LFO file server.
 pub struct LfoClient<IO: AsyncRead + AsyncWrite> {
     sock: CloudProtoSocket<IO>,
+    limits: DecompressionLimits,
+    offload_threshold: Option<usize>,
+    default_identity: LfoIdentity,
 }
 
 impl<IO> LfoClient<IO>
@@ -17,37 +32,168 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     pub fn new(sock: CloudProtoSocket<IO>) -> Self {
-        Self { sock }
+        Self {
+            sock,
+            limits: Default::default(),
+            offload_threshold: Some(DEFAULT_OFFLOAD_THRESHOLD),
+            default_identity: LfoIdentity::anonymous(),
+        }
+    }
+
+    /// Set the identity sent with requests that don't have their own,
+    /// see [`LfoRequest::set_identity`](LfoRequest::set_identity).
+    /// Defaults to [`LfoIdentity::anonymous`](LfoIdentity::anonymous).
+    pub fn set_default_identity(&mut self, identity: LfoIdentity) {
+        self.default_identity = identity;
+    }
+
+    /// Set the [`offload threshold`](LfoResponse::set_offload_threshold) of future responses.
+    ///
+    /// Replies at least this large also have their CRC checked on the blocking thread pool by [`get`](Self::get).
+    pub fn set_offload_threshold(&mut self, threshold: Option<usize>) {
+        self.offload_threshold = threshold;
+    }
+
+    /// Returns a handle to follow the progress of replies as they are received.
+    ///
+    /// See [`CloudProtoSocket::frame_progress`](CloudProtoSocket::frame_progress).
+    pub fn frame_progress(&mut self) -> FrameProgress {
+        self.sock.frame_progress()
+    }
+
+    /// Set the [`DecompressionLimits`](DecompressionLimits) of future responses
+    pub fn set_decompression_limits(&mut self, limits: DecompressionLimits) {
+        self.limits = limits;
+    }
+
+    /// Report [`Anomaly`](Anomaly)s of the client's socket to this channel
+    pub fn set_anomaly_sender(&mut self, tx: Option<mpsc::UnboundedSender<Anomaly>>) {
+        self.sock.set_anomaly_sender(tx);
+    }
+
+    /// Memory held by the client's socket, see [`CloudProtoSocket::memory_report`](CloudProtoSocket::memory_report)
+    pub fn memory_report(&self) -> MemoryReport {
+        self.sock.memory_report()
     }
 
     /// Download the file at the remote path specified in the [`LfoRequest`](super::LfoRequest).
     pub async fn get(&mut self, request: &LfoRequest) -> Result<LfoResponse, LfoError> {
-        let payload = request.to_payload();
-        trace!("Sending LFO request payload: {}", hex::encode(&payload));
-        let req_pkt = CloudProtoPacket {
-            magic: CloudProtoMagic::LFO,
-            kind: LfoPacketKind::GetFileRequest.into(),
-            version: CloudProtoVersion::Connect,
-            payload,
-        };
-        self.sock.send(req_pkt).await?;
-
-        if let Some(reply) = self.sock.next().await {
-            Ok(reply?.try_into()?)
-        } else {
-            Err(LfoError::CloudProto(CloudProtoError::ClosedByPeer(
-                "LFO server closed connection".to_owned(),
-            )))
+        let start = Instant::now();
+        self.sock
+            .send(request_packet(request, &self.default_identity))
+            .await?;
+        let reply = self.sock.next().await.transpose()?;
+        let duration = start.elapsed();
+        let (limits, threshold) = (self.limits, self.offload_threshold);
+        match (&reply, threshold) {
+            // Parsing the reply checks the CRC of the whole payload
+            (Some(pkt), Some(threshold)) if pkt.payload.len() >= threshold => {
+                tokio::task::spawn_blocking(move || {
+                    response_from_reply(reply, duration, limits, Some(threshold))
+                })
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            }
+            _ => response_from_reply(reply, duration, limits, threshold),
         }
     }
 }
 
+/// The packet requesting a file, with the client's default identity if the request has none
+pub(super) fn request_packet(
+    request: &LfoRequest,
+    default_identity: &LfoIdentity,
+) -> CloudProtoPacket {
+    let payload = request.to_payload(default_identity);
+    trace!(
+        "Sending LFO request payload: {}",
+        PayloadDump::new(&payload)
+    );
+    CloudProtoPacket {
+        magic: CloudProtoMagic::LFO,
+        kind: LfoPacketKind::GetFileRequest.into(),
+        version: CloudProtoVersion::Connect,
+        payload: payload.into(),
+    }
+}
+
+/// Parses the server's reply, or fails if it closed the connection instead
+pub(super) fn response_from_reply(
+    reply: Option<CloudProtoPacket>,
+    transfer_duration: Duration,
+    limits: DecompressionLimits,
+    offload_threshold: Option<usize>,
+) -> Result<LfoResponse, LfoError> {
+    let reply = reply.ok_or_else(|| {
+        LfoError::CloudProto(CloudProtoError::ClosedByPeer(
+            "LFO server closed connection".to_owned(),
+        ))
+    })?;
+    let mut response: LfoResponse = reply.try_into()?;
+    response.set_transfer_duration(transfer_duration);
+    response.set_decompression_limits(limits);
+    response.set_offload_threshold(offload_threshold);
+    Ok(response)
+}
+
+impl<IO> LfoClient<IO>
+where
+    IO: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Moves the client into a background task, and returns a clonable handle to it.
+    ///
+    /// This lets multiple tasks call [`get`](LfoClientHandle::get) on a shared connection
+    /// without wrapping the client in a Mutex. Requests are still processed one at a time,
+    /// since LFO replies are not tagged with the request they answer.
+    /// The background task stops when all handles are dropped.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub fn into_handle(mut self) -> LfoClientHandle {
+        let (tx, mut rx) = mpsc::channel::<HandleRequest>(HANDLE_QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some((request, reply)) = rx.recv().await {
+                // The caller may have given up waiting, that's fine
+                let _ = reply.send(self.get(&request).await);
+            }
+            debug!("All LfoClientHandles dropped, stopping LFO client task");
+        });
+        LfoClientHandle { tx }
+    }
+}
+
+type HandleRequest = (LfoRequest, oneshot::Sender<Result<LfoResponse, LfoError>>);
+
+/// A clonable handle to an [`LfoClient`](LfoClient) running in a background task.
+///
+/// See [`LfoClient::into_handle`](LfoClient::into_handle).
+#[derive(Clone)]
+pub struct LfoClientHandle {
+    tx: mpsc::Sender<HandleRequest>,
+}
+
+impl LfoClientHandle {
+    /// Download the file at the remote path specified in the [`LfoRequest`](super::LfoRequest).
+    pub async fn get(&self, request: &LfoRequest) -> Result<LfoResponse, LfoError> {
+        let stopped = || {
+            LfoError::CloudProto(CloudProtoError::ClosedByPeer(
+                "LFO client task stopped".to_owned(),
+            ))
+        };
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .send((request.clone(), reply_tx))
+            .await
+            .map_err(|_| stopped())?;
+        reply_rx.await.map_err(|_| stopped())?
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::framing::{CloudProtoPacket, CloudProtoSocket, CloudProtoVersion};
     use crate::services::lfo::pkt_kind::LfoPacketKind;
     use crate::services::lfo::test::TEST_REPLY_DATA;
-    use crate::services::lfo::{LfoClient, LfoError, LfoRequest};
+    use crate::services::lfo::{CompressionFormats, LfoClient, LfoError, LfoIdentity, LfoRequest};
     use crate::services::CloudProtoMagic;
     use futures_util::{SinkExt, StreamExt};
     use tokio::spawn;
@@ -74,7 +220,7 @@ mod test {
                     magic: CloudProtoMagic::LFO,
                     kind: LfoPacketKind::ReplyOk.into(),
                     version: CloudProtoVersion::Normal,
-                    payload: hex::decode(TEST_REPLY_DATA).unwrap(),
+                    payload: hex::decode(TEST_REPLY_DATA).unwrap().into(),
                 })
                 .await?;
             Ok::<(), LfoError>(())
@@ -82,6 +228,131 @@ mod test {
         let reply = client.get(&req).await?;
         assert_eq!(hex::encode(reply.raw_lfo_payload()), TEST_REPLY_DATA);
 
+        let (data, report) = reply.data_with_report()?;
+        assert_eq!(report.bytes_on_wire, TEST_REPLY_DATA.len() / 2);
+        assert_eq!(report.data_len, data.len());
+        assert_eq!(report.comp_format, CompressionFormats::None as u16);
+
+        server_task.await.unwrap()?;
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn offloaded_reply_parsing() -> Result<(), LfoError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = LfoClient::new(CloudProtoSocket::new(client));
+        client.set_offload_threshold(Some(0));
+        let mut server = CloudProtoSocket::new(server);
+
+        let mut bad_crc = hex::decode(TEST_REPLY_DATA).unwrap();
+        *bad_crc.last_mut().unwrap() ^= 0xFF;
+        let replies = [hex::decode(TEST_REPLY_DATA).unwrap(), bad_crc];
+        let server_task = spawn(async move {
+            for payload in replies {
+                server.next().await.unwrap()?;
+                server
+                    .send(CloudProtoPacket {
+                        magic: CloudProtoMagic::LFO,
+                        kind: LfoPacketKind::ReplyOk.into(),
+                        version: CloudProtoVersion::Normal,
+                        payload: payload.into(),
+                    })
+                    .await?;
+            }
+            Ok::<(), LfoError>(())
+        });
+        let req = LfoRequest::new_simple("/test/foo".to_string());
+        let reply = client.get(&req).await?;
+        assert_eq!(hex::encode(reply.raw_lfo_payload()), TEST_REPLY_DATA);
+        assert!(matches!(
+            client.get(&req).await,
+            Err(LfoError::ReplyParseError { .. })
+        ));
+
+        server_task.await.unwrap()?;
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn request_identities() -> Result<(), LfoError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = LfoClient::new(CloudProtoSocket::new(client));
+        let mut server = CloudProtoSocket::new(server);
+        let default_identity = LfoIdentity::pseudonymous(b"fleet");
+        let custom_identity = LfoIdentity::new([0x11; 16], [0x22; 16]);
+        client.set_default_identity(default_identity);
+
+        let server_task = spawn(async move {
+            let mut identities = Vec::new();
+            while let Some(req) = server.next().await {
+                let req = LfoRequest::try_from_payload(&req?.payload)?;
+                identities.push(*req.identity().unwrap());
+                server
+                    .send(CloudProtoPacket {
+                        magic: CloudProtoMagic::LFO,
+                        kind: LfoPacketKind::ReplyOk.into(),
+                        version: CloudProtoVersion::Normal,
+                        payload: hex::decode(TEST_REPLY_DATA).unwrap().into(),
+                    })
+                    .await?;
+            }
+            Ok::<_, LfoError>(identities)
+        });
+        client
+            .get(&LfoRequest::new_simple("/a".to_string()))
+            .await?;
+        client
+            .get(&LfoRequest::with_identity(
+                custom_identity,
+                "/b".to_string(),
+            ))
+            .await?;
+        drop(client);
+
+        let identities = server_task.await.unwrap()?;
+        assert_eq!(identities, vec![default_identity, custom_identity]);
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn concurrent_handle_requests() -> Result<(), LfoError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let handle = LfoClient::new(CloudProtoSocket::new(client)).into_handle();
+        let mut server = CloudProtoSocket::new(server);
+
+        let server_task = spawn(async move {
+            for _ in 0..4 {
+                let req = server.next().await.unwrap()?;
+                let req = LfoRequest::try_from_payload(&req.payload)?;
+                assert!(req.remote_path.starts_with("/test/"));
+                server
+                    .send(CloudProtoPacket {
+                        magic: CloudProtoMagic::LFO,
+                        kind: LfoPacketKind::ReplyOk.into(),
+                        version: CloudProtoVersion::Normal,
+                        payload: hex::decode(TEST_REPLY_DATA).unwrap().into(),
+                    })
+                    .await?;
+            }
+            Ok::<(), LfoError>(())
+        });
+
+        let tasks: Vec<_> = (0..4)
+            .map(|i| {
+                let handle = handle.clone();
+                spawn(async move {
+                    let req = LfoRequest::new_simple(format!("/test/{}", i));
+                    handle.get(&req).await?.data()
+                })
+            })
+            .collect();
+        for task in tasks {
+            let data = task.await.unwrap()?;
+            assert_eq!(
+                hex::encode(&data),
+                &TEST_REPLY_DATA[0x2A * 2..][..data.len() * 2]
+            );
+        }
         server_task.await.unwrap()?;
         Ok(())
     }
@@ -0,0 +1,152 @@
+use bytes::Bytes;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "lfo-compress-xz")]
+use bytes::Buf;
+
+/// A compression format that LFO file data can be transmitted in.
+///
+/// Formats are identified by the `comp_format` value of the [`LfoFileHeader`](super::LfoFileHeader).
+/// Additional formats can be supported at runtime with [`register_lfo_compression`](register_lfo_compression).
+pub trait LfoCompression: Send + Sync {
+    /// The `comp_format` value identifying this format on the wire
+    fn id(&self) -> u16;
+
+    /// Streaming decompression of `compressed`
+    fn decoder(&self, compressed: Bytes) -> Box<dyn Read + Send>;
+
+    /// Compress `data` in one go
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Decompress `compressed` in one go.
+    ///
+    /// This does not enforce any size limit, [`LfoResponse`](super::LfoResponse) uses
+    /// the streaming [`decoder`](Self::decoder) instead to respect its [`DecompressionLimits`](super::DecompressionLimits).
+    fn decode(&self, compressed: Bytes) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.decoder(compressed).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// The XZ format (LZMA algorithm), used by the official servers for large files
+#[cfg(feature = "lfo-compress-xz")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct XzCompression;
+
+#[cfg(feature = "lfo-compress-xz")]
+impl LfoCompression for XzCompression {
+    fn id(&self) -> u16 {
+        super::CompressionFormats::Xz as u16
+    }
+
+    fn decoder(&self, compressed: Bytes) -> Box<dyn Read + Send> {
+        Box::new(xz2::read::XzDecoder::new(compressed.reader()))
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        xz2::read::XzEncoder::new(data, 6).read_to_end(&mut compressed)?;
+        Ok(compressed)
+    }
+}
+
+static REGISTERED_COMPRESSIONS: RwLock<Vec<Arc<dyn LfoCompression>>> = RwLock::new(Vec::new());
+
+/// Support an additional LFO compression format in all future responses.
+///
+/// This replaces any format previously registered with the same [`id`](LfoCompression::id),
+/// including the built-in ones.
+pub fn register_lfo_compression(compression: Arc<dyn LfoCompression>) {
+    let mut registered = REGISTERED_COMPRESSIONS
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    registered.retain(|c| c.id() != compression.id());
+    registered.push(compression);
+}
+
+/// Looks up a compressed format by ID, `None` is not a compression format
+pub(crate) fn lfo_compression(id: u16) -> Option<Arc<dyn LfoCompression>> {
+    let registered = REGISTERED_COMPRESSIONS
+        .read()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(compression) = registered.iter().find(|c| c.id() == id) {
+        return Some(compression.clone());
+    }
+    #[cfg(feature = "lfo-compress-xz")]
+    if id == super::CompressionFormats::Xz as u16 {
+        return Some(Arc::new(XzCompression));
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::{CloudProtoPacket, CloudProtoVersion};
+    use crate::services::lfo::pkt_kind::LfoPacketKind;
+    use crate::services::lfo::{LfoError, LfoResponse};
+    use crate::services::CloudProtoMagic;
+    use sha2::Digest;
+
+    // Toy format, not seen on real servers
+    struct XorCompression;
+
+    impl LfoCompression for XorCompression {
+        fn id(&self) -> u16 {
+            0x42
+        }
+
+        fn decoder(&self, compressed: Bytes) -> Box<dyn Read + Send> {
+            let data: Vec<u8> = compressed.iter().map(|b| b ^ 0xFF).collect();
+            Box::new(std::io::Cursor::new(data))
+        }
+
+        fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(data.iter().map(|b| b ^ 0xFF).collect())
+        }
+    }
+
+    fn reply_packet(compression: &dyn LfoCompression, data: &[u8]) -> CloudProtoPacket {
+        let compressed = compression.encode(data).unwrap();
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&sha2::Sha256::digest(data));
+        payload.extend_from_slice(&compression.id().to_be_bytes());
+        payload.extend_from_slice(&compressed);
+        payload.extend_from_slice(&crc32fast::hash(&compressed).to_be_bytes());
+        CloudProtoPacket {
+            magic: CloudProtoMagic::LFO,
+            kind: LfoPacketKind::ReplyOk.into(),
+            version: CloudProtoVersion::Normal,
+            payload: payload.into(),
+        }
+    }
+
+    #[test]
+    fn custom_compression() -> Result<(), LfoError> {
+        let data = b"Hello, custom compression!";
+        let pkt = reply_packet(&XorCompression, data);
+        assert!(LfoResponse::try_from(pkt.clone()).is_err());
+
+        register_lfo_compression(Arc::new(XorCompression));
+        let mut resp = LfoResponse::try_from(pkt)?;
+        assert_eq!(resp.data()?, &data[..]);
+        let mut streamed = Vec::new();
+        resp.read_to_end(&mut streamed)?;
+        assert_eq!(streamed, data);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "lfo-compress-xz")]
+    fn xz_roundtrip() -> Result<(), LfoError> {
+        let data = vec![0xAB; 0x1000];
+        let resp = LfoResponse::try_from(reply_packet(&XzCompression, &data))?;
+        assert!(resp.raw_lfo_payload().len() < data.len());
+        assert_eq!(resp.data()?, data);
+        Ok(())
+    }
+}
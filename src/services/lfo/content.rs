@@ -0,0 +1,81 @@
+// Tar archives have their magic in the first header block, after the file name and attributes
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// The format of a file's contents, guessed from its magic bytes.
+///
+/// Some files are served already compressed or archived, with no LFO compression on top.
+/// Knowing the inner format lets mirroring tools name and post-process files without
+/// looking at magic bytes themselves.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ContentFormat {
+    Xz,
+    Gzip,
+    Zip,
+    Tar,
+    /// No known magic, probably not an archive
+    Unknown,
+}
+
+impl ContentFormat {
+    /// Guess the format of `data` from its first bytes
+    pub fn sniff(data: &[u8]) -> Self {
+        if data.starts_with(b"\xFD7zXZ\x00") {
+            Self::Xz
+        } else if data.starts_with(b"\x1F\x8B") {
+            Self::Gzip
+        } else if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            Self::Zip
+        } else if data
+            .get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5)
+            .map_or(false, |magic| magic == b"ustar")
+        {
+            Self::Tar
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// The usual file extension for this format, without a dot
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Self::Xz => Some("xz"),
+            Self::Gzip => Some("gz"),
+            Self::Zip => Some("zip"),
+            Self::Tar => Some("tar"),
+            Self::Unknown => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sniff_formats() {
+        assert_eq!(
+            ContentFormat::sniff(b"\xFD7zXZ\x00\x00\x04"),
+            ContentFormat::Xz
+        );
+        assert_eq!(
+            ContentFormat::sniff(b"\x1F\x8B\x08\x00"),
+            ContentFormat::Gzip
+        );
+        assert_eq!(
+            ContentFormat::sniff(b"PK\x03\x04\x14\x00"),
+            ContentFormat::Zip
+        );
+        // Zlib has no real magic, so it is not guessed: text can pass its header checksum
+        assert_eq!(
+            ContentFormat::sniff(b"80,foo,bar\n"),
+            ContentFormat::Unknown
+        );
+        let mut tar = vec![0u8; 512];
+        tar[..8].copy_from_slice(b"file.txt");
+        tar[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 6].copy_from_slice(b"ustar\0");
+        assert_eq!(ContentFormat::sniff(&tar), ContentFormat::Tar);
+        assert_eq!(ContentFormat::sniff(b"#!/bin/sh\n"), ContentFormat::Unknown);
+        assert_eq!(ContentFormat::sniff(b""), ContentFormat::Unknown);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::redaction::SensitiveId;
+use crate::services::{fold_seed, splitmix64, DEFAULT_AID_HEX, DEFAULT_CID_HEX};
+
+/// The CID/AID pair sent in [`LfoRequest`](super::LfoRequest)s.
+///
+/// LFO does not authenticate clients, so the identity only matters for what the server may log.
+/// It can be set on each request, or as a default for all requests of a [`LfoClient`](super::LfoClient).
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub struct LfoIdentity {
+    // The CID assigned to a Crowdstrike customer (same as the CCID without the last -N number)
+    pub(crate) cid: [u8; 16],
+    // Agent ID assigned to the sensor by TS
+    pub(crate) aid: [u8; 16],
+}
+
+impl std::fmt::Debug for LfoIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LfoIdentity")
+            .field("cid", &SensitiveId(&self.cid))
+            .field("aid", &SensitiveId(&self.aid))
+            .finish()
+    }
+}
+
+impl LfoIdentity {
+    /// An explicit CID and AID, e.g. to match the identity of a real sensor
+    pub fn new(cid: [u8; 16], aid: [u8; 16]) -> Self {
+        Self { cid, aid }
+    }
+
+    /// All zeroes CID and AID. LFO doesn't mind.
+    pub fn anonymous() -> Self {
+        Self {
+            cid: hex::decode(DEFAULT_CID_HEX).unwrap().try_into().unwrap(),
+            aid: hex::decode(DEFAULT_AID_HEX).unwrap().try_into().unwrap(),
+        }
+    }
+
+    /// Random-looking CID and AID, derived deterministically from `seed`.
+    ///
+    /// The same seed always gives the same identity, so tools can keep a consistent identity
+    /// across runs (e.g. seeded by a host name) without storing it.
+    /// These values belong to no customer, and are not guaranteed to be structurally valid CIDs.
+    pub fn pseudonymous(seed: &[u8]) -> Self {
+        let state = fold_seed(seed);
+        let mut ids = [0u8; 32];
+        for (n, chunk) in ids.chunks_mut(8).enumerate() {
+            chunk.copy_from_slice(&splitmix64(state, n as u64).to_be_bytes());
+        }
+        Self {
+            cid: ids[..16].try_into().unwrap(),
+            aid: ids[16..].try_into().unwrap(),
+        }
+    }
+
+    pub fn cid(&self) -> &[u8; 16] {
+        &self.cid
+    }
+
+    pub fn aid(&self) -> &[u8; 16] {
+        &self.aid
+    }
+}
+
+impl Default for LfoIdentity {
+    fn default() -> Self {
+        Self::anonymous()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pseudonymous_identities() {
+        let a = LfoIdentity::pseudonymous(b"host-a");
+        assert_eq!(a, LfoIdentity::pseudonymous(b"host-a"));
+        assert_ne!(a, LfoIdentity::pseudonymous(b"host-b"));
+        assert_ne!(a.cid(), a.aid());
+        assert_ne!(a, LfoIdentity::anonymous());
+        assert_eq!(LfoIdentity::anonymous().cid(), &[0; 16]);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::services::lfo::ContentFormat;
+
+/// Everything the server claims about a file, available before reading any data.
+///
+/// Returned by [`LfoResponse::metadata`](super::LfoResponse::metadata), so that callers can decide
+/// to skip or cache a file before decompressing it. The CRC has already been checked, but the size
+/// and hash are only checked once the data is read.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LfoMetadata {
+    /// Size of the file data after any decompression
+    pub payload_size: u32,
+    /// Sha256 hash of the file data after any decompression
+    pub data_hash: [u8; 32],
+    /// See [`CompressionFormats`](super::CompressionFormats) for known values
+    pub comp_format: u16,
+    /// Offset of this chunk in the file. Always 0, since chunked downloads are not supported.
+    pub chunk_start: u32,
+    /// End offset of this chunk in the file
+    pub chunk_end: u32,
+    /// Size of the (possibly compressed) data in this reply
+    pub transmitted_len: usize,
+    /// CRC32 of the transmitted data
+    pub crc: u32,
+    /// The format of the file data guessed from its first bytes, when transmitted uncompressed.
+    ///
+    /// This is `None` for compressed replies, since the data would have to be decompressed first.
+    /// In that case you can still call [`ContentFormat::sniff`](super::ContentFormat::sniff) on the data.
+    pub content_format: Option<ContentFormat>,
+}
@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+/// Provenance of a completed LFO download, for logging and auditing.
+///
+/// Returned by [`LfoResponse::data_with_report`](super::LfoResponse::data_with_report),
+/// so that mirroring tools can record exactly what was received.
+///
+/// A report is only returned for data that passed every check: the CRC of the LFO reply payload
+/// is always checked when the reply is parsed, the size of the final data is checked against
+/// the LFO header, and so is its Sha256 with the `lfo-check-hash` feature.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TransferReport {
+    /// Size of the raw LFO reply payload, including the LFO header and trailing CRC
+    pub bytes_on_wire: usize,
+    /// Size of the file data after any decompression
+    pub data_len: usize,
+    /// See [`CompressionFormats`](super::CompressionFormats) for known values
+    pub comp_format: u16,
+    /// Time between sending the request and receiving the full reply.
+    /// Zero if the response was not received by an [`LfoClient`](super::LfoClient).
+    pub duration: Duration,
+}
@@ -1,5 +1,4 @@
-use crate::services::lfo::CompressionFormats;
-use crate::services::{DEFAULT_AID_HEX, DEFAULT_CID_HEX};
+use crate::services::lfo::{CompressionFormats, LfoIdentity};
 
 /// Ask for a single file on a remote LFO server by path.
 ///
@@ -9,14 +8,14 @@ use crate::services::{DEFAULT_AID_HEX, DEFAULT_CID_HEX};
 ///
 /// Requests contain the CID (Customer ID) and AID (Agent ID) of the client, but the LFO server
 /// will accept any value for these, so in practice no authentication is required.
-#[derive(Eq, PartialEq, Debug, Clone)]
+/// See [`LfoIdentity`](LfoIdentity).
+#[derive(Eq, PartialEq, Clone)]
 pub struct LfoRequest {
-    // The CID assigned to a Crowdstrike customer (same as the CCID without the last -N number)
-    // The LFO server doesn't really check if it belongs to anyone. Just try to pass a valid CID.
-    pub(crate) cid: [u8; 16],
-    // Agent ID. LFO isn't uptight like TS if the AID is not an active customer.
+    // The LFO server doesn't really check if the CID belongs to anyone,
+    // and LFO isn't uptight like TS if the AID is not an active customer.
     // In fact, you can give it all zeroes. LFO is friendly like that.
-    pub(crate) aid: [u8; 16],
+    // When None, the client's default identity is used.
+    pub(crate) identity: Option<LfoIdentity>,
     // The real client supports values 0 or 1. We only support 0.
     pub(crate) compression: u16,
     // The file to download
@@ -27,13 +26,25 @@ pub struct LfoRequest {
     pub(crate) offset: u32,
 }
 
+impl std::fmt::Debug for LfoRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LfoRequest")
+            .field("identity", &self.identity)
+            .field("compression", &self.compression)
+            .field("remote_path", &self.remote_path)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
 impl LfoRequest {
-    /// Create a request for `remote_path` with default values
+    /// Create a request for `remote_path` with default values.
+    ///
+    /// The request uses the default identity of the client that sends it,
+    /// which is [`LfoIdentity::anonymous`](LfoIdentity::anonymous) unless configured otherwise.
     pub fn new_simple(remote_path: String) -> Self {
         Self {
-            // LFO doesn't mind all zeroes
-            cid: hex::decode(DEFAULT_CID_HEX).unwrap().try_into().unwrap(),
-            aid: hex::decode(DEFAULT_AID_HEX).unwrap().try_into().unwrap(),
+            identity: None,
             compression: 0,
             remote_path,
             offset: 0,
@@ -47,8 +58,7 @@ impl LfoRequest {
         remote_path: String,
     ) -> Self {
         Self {
-            cid,
-            aid,
+            identity: Some(LfoIdentity::new(cid, aid)),
             compression: compression as u16,
             remote_path,
             // Only 0 if supported for now
@@ -57,10 +67,29 @@ impl LfoRequest {
         }
     }
 
-    pub(crate) fn to_payload(&self) -> Vec<u8> {
+    /// Create a request for `remote_path` with default values, except for the identity
+    pub fn with_identity(identity: LfoIdentity, remote_path: String) -> Self {
+        Self {
+            identity: Some(identity),
+            ..Self::new_simple(remote_path)
+        }
+    }
+
+    /// Set the identity of this request, or `None` to use the client's default identity
+    pub fn set_identity(&mut self, identity: Option<LfoIdentity>) {
+        self.identity = identity;
+    }
+
+    /// The identity of this request, if it doesn't use the client's default
+    pub fn identity(&self) -> Option<&LfoIdentity> {
+        self.identity.as_ref()
+    }
+
+    pub(crate) fn to_payload(&self, default_identity: &LfoIdentity) -> Vec<u8> {
+        let identity = self.identity.as_ref().unwrap_or(default_identity);
         let mut payload = vec![];
-        payload.extend_from_slice(&self.cid); // CU "simple store" value
-        payload.extend_from_slice(&self.aid); // AG "simple store" value
+        payload.extend_from_slice(&identity.cid); // CU "simple store" value
+        payload.extend_from_slice(&identity.aid); // AG "simple store" value
         payload.extend_from_slice(8u32.to_be_bytes().as_slice());
         payload.extend_from_slice(&self.offset.to_be_bytes());
         payload.extend_from_slice(&self.compression.to_be_bytes());
@@ -85,8 +114,7 @@ impl LfoRequest {
         let remote_path = String::from_utf8(payload[cursor.position() as usize..].into())
             .map_err(|_| LfoError::InvalidRequest)?;
         Ok(Self {
-            cid,
-            aid,
+            identity: Some(LfoIdentity::new(cid, aid)),
             compression,
             remote_path,
             offset,
@@ -1,27 +1,53 @@
 use crate::framing::CloudProtoPacket;
+use crate::services::lfo::compression::lfo_compression;
 use crate::services::lfo::file_header::{CRC_LEN, LFO_RESP_HDR_LEN};
 use crate::services::lfo::pkt_kind::LfoPacketKind;
-use crate::services::lfo::{CompressionFormats, LfoError, LfoFileHeader};
+use crate::services::lfo::{
+    CompressionFormats, ContentFormat, LfoCompression, LfoError, LfoFileHeader, LfoMetadata,
+    TransferReport,
+};
 use bytes::Bytes;
 use std::cmp;
 use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::trace;
 
-#[cfg(feature = "lfo-compress-xz")]
-use bytes::Buf;
-#[cfg(feature = "lfo-compress-xz")]
-use xz2::read::XzDecoder;
-
 enum ResponseReadState {
     Direct {
         read_pos: usize,
     },
-    #[cfg(feature = "lfo-compress-xz")]
     Compressed {
-        stream: XzDecoder<bytes::buf::Reader<Bytes>>,
+        compression: Arc<dyn LfoCompression>,
+        stream: Box<dyn Read + Send>,
+        total_out: u64,
     },
 }
 
+/// Limits on decompressed LFO data, enforced before decompressing anything.
+///
+/// The `payload_size` announced in the LFO header is chosen by the server,
+/// so on its own it does not protect against decompression bombs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DecompressionLimits {
+    /// Maximum size of the data after decompression
+    pub max_size: usize,
+    /// Maximum ratio of decompressed size to compressed size
+    pub max_ratio: usize,
+}
+
+impl Default for DecompressionLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 2 << 30,
+            max_ratio: 1024,
+        }
+    }
+}
+
+// Responses larger than this are verified on the blocking thread pool by default
+pub(crate) const DEFAULT_OFFLOAD_THRESHOLD: usize = 1 << 20;
+
 /// The reply from the server corresponding to a single [`LfoRequest`](super::LfoRequest).
 pub struct LfoResponse {
     raw_lfo_payload: Bytes,
@@ -29,6 +55,9 @@ pub struct LfoResponse {
     // This could be the plain file data, or compressed
     lfo_data: Bytes,
     read_state: ResponseReadState,
+    transfer_duration: Duration,
+    limits: DecompressionLimits,
+    offload_threshold: Option<usize>,
     #[cfg(feature = "lfo-check-hash")]
     read_hasher: sha2::Sha256,
     #[cfg(not(feature = "lfo-check-hash"))]
@@ -40,11 +69,14 @@ impl LfoResponse {
     /// May fail if the received data (after any decompression) has the wrong size or hash.
     /// This ignores the [`Read`](std::io::Read) cursor and always returns the entire data.
     pub fn data(&self) -> Result<Bytes, LfoError> {
-        let full_data = match self.read_state {
+        let full_data = match &self.read_state {
             ResponseReadState::Direct { .. } => self.lfo_data.clone(),
-            #[cfg(feature = "lfo-compress-xz")]
-            ResponseReadState::Compressed { .. } => {
-                let mut stream = XzDecoder::new(self.lfo_data.clone().reader());
+            ResponseReadState::Compressed { compression, .. } => {
+                self.check_decompression_limits()?;
+                // Stop one byte past the expected size, check_full_data_len reports the error
+                let mut stream = compression
+                    .decoder(self.lfo_data.clone())
+                    .take(self.header.payload_size as u64 + 1);
                 let mut buf = Vec::with_capacity(self.header.payload_size as usize);
                 stream.read_to_end(&mut buf)?;
                 buf.into()
@@ -56,6 +88,65 @@ impl LfoResponse {
         Ok(full_data)
     }
 
+    /// Same as [`Self::data()`](Self::data), but large responses are decompressed and verified
+    /// with [`spawn_blocking`](tokio::task::spawn_blocking) instead of stalling the async runtime.
+    ///
+    /// See [`set_offload_threshold`](Self::set_offload_threshold).
+    /// This must be called from within a Tokio runtime.
+    pub async fn into_data(self) -> Result<Bytes, LfoError> {
+        match self.offload_threshold {
+            Some(threshold) if self.raw_lfo_payload.len() >= threshold => {
+                tokio::task::spawn_blocking(move || self.data())
+                    .await
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            }
+            _ => self.data(),
+        }
+    }
+
+    /// Responses with a raw payload at least this large are verified on the blocking thread pool
+    /// by [`into_data`](Self::into_data). `None` always verifies inline. Defaults to 1MiB.
+    pub fn set_offload_threshold(&mut self, threshold: Option<usize>) {
+        self.offload_threshold = threshold;
+    }
+
+    /// Same as [`Self::data()`](Self::data), but also reports what was received.
+    pub fn data_with_report(&self) -> Result<(Bytes, TransferReport), LfoError> {
+        let data = self.data()?;
+        let report = TransferReport {
+            bytes_on_wire: self.raw_lfo_payload.len(),
+            data_len: data.len(),
+            comp_format: self.header.comp_format,
+            duration: self.transfer_duration,
+        };
+        Ok((data, report))
+    }
+
+    /// Replaces the default [`DecompressionLimits`](DecompressionLimits) for this response.
+    /// This has no effect on uncompressed data.
+    pub fn set_decompression_limits(&mut self, limits: DecompressionLimits) {
+        self.limits = limits;
+    }
+
+    /// Refuse to decompress data announced to be larger than the limits allow
+    fn check_decompression_limits(&self) -> Result<(), LfoError> {
+        let limit = self
+            .limits
+            .max_size
+            .min(self.lfo_data.len().saturating_mul(self.limits.max_ratio));
+        if self.header.payload_size as usize > limit {
+            return Err(LfoError::DecompressionLimitExceeded {
+                announced: self.header.payload_size as usize,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_transfer_duration(&mut self, duration: Duration) {
+        self.transfer_duration = duration;
+    }
+
     /// This returns the raw, still serialized LFO server's response.
     /// You most likely want to use [`Self::data()`](Self::data) instead.
     /// Only use this if you would like to parse some fields of the LFO header yourself.
@@ -63,6 +154,23 @@ impl LfoResponse {
         self.raw_lfo_payload.clone()
     }
 
+    /// All the metadata the server sent about the file, see [`LfoMetadata`](LfoMetadata)
+    pub fn metadata(&self) -> LfoMetadata {
+        let raw = &self.raw_lfo_payload;
+        let be_u32 = |off: usize| u32::from_be_bytes(raw[off..off + 4].try_into().unwrap());
+        LfoMetadata {
+            payload_size: self.header.payload_size,
+            data_hash: self.header.data_hash,
+            comp_format: self.header.comp_format,
+            chunk_start: be_u32(0),
+            chunk_end: be_u32(4),
+            transmitted_len: self.lfo_data.len(),
+            crc: be_u32(raw.len() - CRC_LEN),
+            content_format: (self.header.comp_format == CompressionFormats::None as u16)
+                .then(|| ContentFormat::sniff(&self.lfo_data)),
+        }
+    }
+
     /// The LFO file header mostly contains low-level details about the file being downloaded.
     /// You can use it check the size the decompressed file, before actually decompressing it.
     pub fn lfo_file_header(&self) -> &LfoFileHeader {
@@ -119,8 +227,7 @@ impl LfoResponse {
         Ok(())
     }
 
-    fn try_from_raw_lfo_payload(raw_payload: Vec<u8>) -> Result<Self, LfoError> {
-        let raw_payload = Bytes::from(raw_payload);
+    fn try_from_raw_lfo_payload(raw_payload: Bytes) -> Result<Self, LfoError> {
         let header = match LfoFileHeader::try_from(raw_payload.as_ref()) {
             Ok(h) => h,
             Err(e) => {
@@ -133,14 +240,11 @@ impl LfoResponse {
         let chunk_data = raw_payload.slice(LFO_RESP_HDR_LEN..raw_payload.len() - CRC_LEN);
         let read_state = if header.comp_format == CompressionFormats::None as u16 {
             ResponseReadState::Direct { read_pos: 0 }
-        } else if cfg!(feature = "lfo-compress-xz")
-            && header.comp_format == CompressionFormats::Xz as u16
-        {
-            #[cfg(not(feature = "lfo-compress-xz"))]
-            unreachable!();
-            #[cfg(feature = "lfo-compress-xz")]
+        } else if let Some(compression) = lfo_compression(header.comp_format) {
             ResponseReadState::Compressed {
-                stream: XzDecoder::new(chunk_data.clone().reader()),
+                stream: compression.decoder(chunk_data.clone()),
+                compression,
+                total_out: 0,
             }
         } else {
             return Err(LfoError::ReplyParseError {
@@ -153,6 +257,9 @@ impl LfoResponse {
             header,
             lfo_data: chunk_data,
             read_state,
+            transfer_duration: Duration::ZERO,
+            limits: Default::default(),
+            offload_threshold: Some(DEFAULT_OFFLOAD_THRESHOLD),
             read_hasher: Default::default(),
         })
     }
@@ -186,6 +293,10 @@ impl TryFrom<CloudProtoPacket> for LfoResponse {
 
 impl Read for LfoResponse {
     fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
+        if let ResponseReadState::Compressed { total_out: 0, .. } = &self.read_state {
+            self.check_decompression_limits()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
         let hasher = &mut self.read_hasher;
         match &mut self.read_state {
             ResponseReadState::Direct { read_pos } => {
@@ -202,20 +313,22 @@ impl Read for LfoResponse {
                 *read_pos += count;
                 Ok(count)
             }
-            #[cfg(feature = "lfo-compress-xz")]
-            ResponseReadState::Compressed { stream } => {
+            ResponseReadState::Compressed {
+                stream, total_out, ..
+            } => {
                 let count = stream.read(buf)?;
+                *total_out += count as u64;
                 Self::update_running_hash(hasher, &buf[..count]);
 
-                if stream.total_out() > self.header.payload_size as u64 {
+                if *total_out > self.header.payload_size as u64 {
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
                         LfoError::InvalidFinalSize {
                             expected: self.header.payload_size as usize,
-                            actual: stream.total_out() as usize,
+                            actual: *total_out as usize,
                         },
                     ));
-                } else if count != 0 && stream.total_out() == self.header.payload_size as u64 {
+                } else if count != 0 && *total_out == self.header.payload_size as u64 {
                     Self::check_hash_matches(&self.header.data_hash, hasher)
                         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
                 }
@@ -231,7 +344,9 @@ mod test {
     use crate::framing::{CloudProtoPacket, CloudProtoVersion};
     use crate::services::lfo::pkt_kind::LfoPacketKind;
     use crate::services::lfo::test::TEST_REPLY_DATA;
-    use crate::services::lfo::{LfoError, LfoResponse};
+    #[cfg(feature = "lfo-compress-xz")]
+    use crate::services::lfo::DecompressionLimits;
+    use crate::services::lfo::{ContentFormat, LfoError, LfoResponse};
     use crate::services::CloudProtoMagic;
     use std::io::Read;
 
@@ -241,7 +356,7 @@ mod test {
             magic: CloudProtoMagic::TS,
             kind: LfoPacketKind::ReplyOk.into(),
             version: CloudProtoVersion::Normal,
-            payload: lfo_reply.clone(),
+            payload: lfo_reply.clone().into(),
         };
         let mut resp = LfoResponse::try_from(reply_pkt)?;
         assert_eq!(resp.raw_lfo_payload(), &lfo_reply);
@@ -269,6 +384,43 @@ mod test {
         Ok(())
     }
 
+    #[test_log::test(tokio::test)]
+    async fn offloaded_verification() -> Result<(), LfoError> {
+        let reply_pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: LfoPacketKind::ReplyOk.into(),
+            version: CloudProtoVersion::Normal,
+            payload: hex::decode(TEST_REPLY_DATA).unwrap().into(),
+        };
+        let mut resp = LfoResponse::try_from(reply_pkt)?;
+        let expected = resp.data()?;
+        resp.set_offload_threshold(Some(0));
+        assert_eq!(resp.into_data().await?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn response_metadata() -> Result<(), LfoError> {
+        let reply_pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: LfoPacketKind::ReplyOk.into(),
+            version: CloudProtoVersion::Normal,
+            payload: hex::decode(TEST_REPLY_DATA).unwrap().into(),
+        };
+        let resp = LfoResponse::try_from(reply_pkt)?;
+        let meta = resp.metadata();
+        let header = resp.lfo_file_header();
+        assert_eq!(meta.payload_size, header.payload_size);
+        assert_eq!(meta.data_hash, header.data_hash);
+        assert_eq!(meta.comp_format, header.comp_format);
+        assert_eq!(meta.chunk_start, 0);
+        assert_eq!(meta.chunk_end, header.payload_size);
+        assert_eq!(meta.transmitted_len, resp.data()?.len());
+        assert_eq!(meta.crc, crc32fast::hash(&resp.data()?));
+        assert_eq!(meta.content_format, Some(ContentFormat::Unknown));
+        Ok(())
+    }
+
     #[test]
     fn simple_test_vector() -> Result<(), LfoError> {
         let expected_hash = "a330869acb341ad81b4b64f92ed7b85e0a361ab0449017a9f7a5f09276a43655";
@@ -285,4 +437,39 @@ mod test {
         let expected_hash = "58dd00985ef1c304b973374fad8726aeac9769fe45d1bea2335630b0899b9ef6";
         check_test_vector(hex, expected_hash)
     }
+
+    #[test]
+    #[cfg(feature = "lfo-compress-xz")]
+    fn xz_decompression_limits() -> Result<(), LfoError> {
+        let hex = "000000000000015658dd00985ef1c304b973374fad8726aeac9769fe45d1bea2335630b0899b9ef60001fd377a585a0000016922de36020021011c00000010cf\
+                         58cce0015500645d0055687c400160306c2cec9513bc4360c68796e3b982a76ad18024af592b8f044aae3937e42bec03336fa43a3ecd228463d4545ae8cf99a9\
+                         6368bfc3d7137b5f1fe5cb4201c3928e6a07895cba5f7220d2a3f5400768f1a63acc53ae5abbf13d5b6b84000000c3d9916a00017cd602000000155b09133e30\
+                         0d8b020000000001595a75e2d281";
+        let reply_pkt = CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: LfoPacketKind::ReplyOk.into(),
+            version: CloudProtoVersion::Normal,
+            payload: hex::decode(hex).unwrap().into(),
+        };
+        let mut resp = LfoResponse::try_from(reply_pkt)?;
+        resp.set_decompression_limits(DecompressionLimits {
+            max_size: 0x100,
+            max_ratio: 1,
+        });
+        assert!(matches!(
+            resp.data(),
+            Err(LfoError::DecompressionLimitExceeded {
+                announced: 0x156,
+                ..
+            })
+        ));
+        assert!(resp.read_to_end(&mut Vec::new()).is_err());
+
+        resp.set_decompression_limits(DecompressionLimits {
+            max_size: 0x156,
+            max_ratio: 3,
+        });
+        assert_eq!(resp.data()?.len(), 0x156);
+        Ok(())
+    }
 }
@@ -1,16 +1,171 @@
 //! High-level support for the TS event server
 
 mod acceptor;
+pub mod blocking;
+mod drift;
 mod event;
+mod fleet;
+mod flusher;
+mod jsonl;
 mod pkt_kind;
+mod profile;
+mod protobuf;
+mod replay;
+mod schema;
 mod socket;
+mod stream_ext;
+mod txid;
+mod welcome;
 
+pub use crate::WalkLimits;
 pub use acceptor::TsEventAcceptor;
+pub use drift::{DriftReport, SchemaMismatch, SchemaValidator};
 pub use event::{Event, EventId};
+pub use fleet::{FleetIdentityGenerator, FleetNodeIdentity};
+pub use flusher::TsFlushingHandle;
+pub use jsonl::{capture_to_jsonl, event_to_json, event_to_json_with_limits};
 pub use pkt_kind::TsPacketKind;
-pub use socket::TsEventSocket;
+pub use profile::SensorProfile;
+pub use protobuf::{ProtobufError, WireField, WireFieldIter, WireType, WireValue};
+pub use replay::{ConnectReplayDetector, ReplaySuspicion};
+pub use schema::{InferredField, InferredSchema};
+pub use socket::{
+    EventTimestamp, HandshakeTimings, InvalidEventHandler, InvalidEventPolicy, ReceivedEvent,
+    TsEventSocket, TsSocketState, UnexpectedPackets,
+};
+pub use stream_ext::TsEventStreamExt;
+pub use txid::{TxidAnomaly, TxidAnomalyDetector};
+pub use welcome::{TemplatePart, WelcomeSequence, WelcomeStep};
 
-use crate::services::{DEFAULT_BOOTID_HEX, DEFAULT_UNK0_HEX};
+use crate::framing::CloudProtoError;
+use crate::redaction::SensitiveId;
+use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors of the TS sockets, when connecting, sending or receiving.
+///
+/// This separates the peer violating the protocol (e.g. a malformed packet) from the
+/// connection being lost, so that callers can react without looking at error messages.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum TsError {
+    /// The peer sent something that does not follow the framing or TS protocol
+    #[error("TS protocol error: {0}")]
+    Protocol(CloudProtoError),
+    #[error("{reason} ({origin})")]
+    ClosedByPeer {
+        reason: String,
+        origin: TsConnectionOrigin,
+    },
+    #[error("Connection was already closed")]
+    AlreadyClosed,
+    #[error("Nothing received from peer for {timeout:?}, connection may be half-open ({origin})")]
+    IdleTimeout {
+        timeout: Duration,
+        origin: TsConnectionOrigin,
+    },
+    #[error("Timed out after {0:?} waiting for events")]
+    Timeout(Duration),
+    #[error("Received event {0:#x}, but expected {1:#x}")]
+    UnexpectedEvent(u32, u32),
+    /// Packets of this kind are sent and received by the socket itself
+    #[error("TS packet kind {0:#x} is reserved for the event layer")]
+    ReservedPacketKind(u8),
+    #[error("TS socket IO error")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+}
+
+impl TsError {
+    /// Whether the peer violated the protocol, rather than the connection failing or timing out
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(self, Self::Protocol(_))
+    }
+
+    /// The connection this error happened on, for errors that end a session
+    pub fn origin(&self) -> Option<&TsConnectionOrigin> {
+        match self {
+            Self::ClosedByPeer { origin, .. } | Self::IdleTimeout { origin, .. } => Some(origin),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn closed_by_peer(reason: impl Into<String>, origin: TsConnectionOrigin) -> Self {
+        Self::ClosedByPeer {
+            reason: reason.into(),
+            origin,
+        }
+    }
+}
+
+/// Which connection a [`TsError`](TsError) happened on, so that the errors of a server
+/// with many clients can be told apart without looking at the surrounding logs.
+///
+/// Fields are `None` when they are not known yet where the error happens.
+#[derive(Eq, PartialEq, Clone, Default)]
+#[non_exhaustive]
+pub struct TsConnectionOrigin {
+    /// See [`TsEventSocket::connection_id`](TsEventSocket::connection_id),
+    /// only assigned once the TS session is established
+    pub connection_id: Option<u64>,
+    /// See [`CloudProtoSocket::set_peer_addr`](crate::framing::CloudProtoSocket::set_peer_addr)
+    pub peer_addr: Option<SocketAddr>,
+    pub cid: Option<[u8; 16]>,
+    /// The negotiated Agent ID
+    pub aid: Option<[u8; 16]>,
+}
+
+impl std::fmt::Display for TsConnectionOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut sep = "";
+        if let Some(id) = self.connection_id {
+            write!(f, "conn_id={}", id)?;
+            sep = " ";
+        }
+        if let Some(addr) = self.peer_addr {
+            write!(f, "{}peer={}", sep, addr)?;
+            sep = " ";
+        }
+        if let Some(cid) = &self.cid {
+            write!(f, "{}cid={}", sep, SensitiveId(cid))?;
+            sep = " ";
+        }
+        if let Some(aid) = &self.aid {
+            write!(f, "{}aid={}", sep, SensitiveId(aid))?;
+            sep = " ";
+        }
+        if sep.is_empty() {
+            f.write_str("unknown connection")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for TsConnectionOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TsConnectionOrigin")
+            .field("connection_id", &self.connection_id)
+            .field("peer_addr", &self.peer_addr)
+            .field("cid", &self.cid.as_ref().map(|cid| SensitiveId(cid)))
+            .field("aid", &self.aid.as_ref().map(|aid| SensitiveId(aid)))
+            .finish()
+    }
+}
+
+impl From<CloudProtoError> for TsError {
+    fn from(e: CloudProtoError) -> Self {
+        match e {
+            CloudProtoError::ClosedByPeer(reason) => {
+                Self::closed_by_peer(reason, Default::default())
+            }
+            CloudProtoError::Io { source } => Self::Io { source },
+            e => Self::Protocol(e),
+        }
+    }
+}
 
 /// Whether the server expects the client to keep its Agent ID or be assigned a new one
 #[repr(u8)]
@@ -21,7 +176,7 @@ pub enum AgentIdStatus {
 }
 
 /// Connection information required to open a session with the TS server
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Clone)]
 pub struct TsConnectInfo {
     // The CID assigned to a Crowdstrike customer (same as the CCID without the last -N number)
     // These are not random, there's a sort of checksum that must pass for a CID to be valid.
@@ -41,15 +196,9 @@ impl TsConnectInfo {
     /// Connect using the provided Crowdstrike customer ID
     /// The CID must belong to an active customer.
     /// Unlike for the LSO server and falcon-sensor it's not enough to use a structurally valid but inactive CID.
-    /// Uses hardcoded default values for the other non-critical fields.
+    /// Uses the default values of [`SensorProfile::v13601`](SensorProfile::v13601) for the other non-critical fields.
     pub fn new_simple(cid: [u8; 16]) -> Self {
-        Self {
-            cid,
-            unk0: hex::decode(DEFAULT_UNK0_HEX).unwrap().try_into().unwrap(),
-            aid: [0; 16],
-            bootid: hex::decode(DEFAULT_BOOTID_HEX).unwrap().try_into().unwrap(),
-            pt: [0; 8],
-        }
+        SensorProfile::v13601().connect_info(cid)
     }
 
     pub fn new_custom(
@@ -69,8 +218,20 @@ impl TsConnectInfo {
     }
 }
 
+impl std::fmt::Debug for TsConnectInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TsConnectInfo")
+            .field("cid", &SensitiveId(&self.cid))
+            .field("unk0", &SensitiveId(&self.unk0))
+            .field("aid", &SensitiveId(&self.aid))
+            .field("bootid", &SensitiveId(&self.bootid))
+            .field("pt", &SensitiveId(&self.pt))
+            .finish()
+    }
+}
+
 /// Response to a connection from the TS server
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Clone)]
 pub struct TsConnectResponse {
     // Whether the server expects us to keep our existing agent ID, or to update it
     pub agent_id_status: AgentIdStatus,
@@ -78,15 +239,25 @@ pub struct TsConnectResponse {
     pub aid: [u8; 16],
 }
 
+impl std::fmt::Debug for TsConnectResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TsConnectResponse")
+            .field("agent_id_status", &self.agent_id_status)
+            .field("aid", &SensitiveId(&self.aid))
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::framing::{CloudProtoError, CloudProtoSocket};
+    use crate::framing::{CloudProtoPacket, CloudProtoSocket, CloudProtoVersion};
+    use crate::services::CloudProtoMagic;
     use futures_util::{SinkExt, StreamExt};
     use tokio::spawn;
 
     #[tokio::test]
-    async fn test_simple_client_server() -> Result<(), CloudProtoError> {
+    async fn test_simple_client_server() -> Result<(), TsError> {
         let (client, server) = tokio::io::duplex(16 * 1024);
         let cid = [1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8];
         let old_aid = [4, 4, 4, 4, 2, 2, 2, 2, 8, 8, 8, 8, 1, 1, 1, 1];
@@ -102,6 +273,8 @@ mod tests {
                     aid: new_aid,
                 })
                 .await?;
+            assert_eq!(sock.connect_info().cid, cid);
+            assert_eq!(sock.connect_info().aid, new_aid);
             let ev = sock.next().await.unwrap()?;
             assert_eq!(ev.event_id, Some(EventId::AgentOnline));
             sock.send(Event::new(
@@ -110,7 +283,7 @@ mod tests {
             ))
             .await?;
 
-            Ok::<_, CloudProtoError>(sock) // Keep sock alive!
+            Ok::<_, TsError>(sock) // Keep sock alive!
         });
 
         let mut client = TsEventSocket::connect(
@@ -118,6 +291,7 @@ mod tests {
             TsConnectInfo::new_custom(cid, [0; 16], old_aid, [0; 16], [0; 8]),
         )
         .await?;
+        assert_eq!(client.connect_info().aid, new_aid);
         client
             .send(Event::new(EventId::AgentOnline, vec![]))
             .await?;
@@ -127,4 +301,64 @@ mod tests {
         server_task.await.expect("Server task join error!")?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_profile_txids() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut profile = SensorProfile::v13601();
+        profile.first_txid = 0x1000;
+        profile.txid_increment = 0x10;
+
+        let server_task = spawn(async move {
+            let mut server = CloudProtoSocket::new(server);
+            let connect = server.next().await.unwrap()?;
+            assert_eq!(connect.kind, TsPacketKind::Connect);
+            let mut payload = vec![AgentIdStatus::Unchanged as u8];
+            payload.extend_from_slice(&connect.payload[32..48]);
+            server
+                .send(CloudProtoPacket {
+                    magic: CloudProtoMagic::TS,
+                    kind: TsPacketKind::ConnectionEstablished.into(),
+                    version: CloudProtoVersion::Normal,
+                    payload: payload.into(),
+                })
+                .await?;
+            let mut txids = Vec::new();
+            for _ in 0..2 {
+                let pkt = server.next().await.unwrap()?;
+                txids.push(u64::from_be_bytes(pkt.payload[..8].try_into().unwrap()));
+            }
+            Ok::<_, TsError>(txids)
+        });
+
+        let info = profile.connect_info([1; 16]);
+        let mut client =
+            TsEventSocket::connect_with_profile(CloudProtoSocket::new(client), info, &profile)
+                .await?;
+        for _ in 0..2 {
+            client
+                .send(Event::new(EventId::AgentOnline, vec![]))
+                .await?;
+        }
+        let txids = server_task.await.expect("Server task join error!")?;
+        assert_eq!(txids, vec![0x1000, 0x1010]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closed_error_origin() {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let peer_addr = "192.0.2.1:443".parse().unwrap();
+        let mut server = CloudProtoSocket::new(server);
+        server.set_peer_addr(Some(peer_addr));
+        drop(client);
+
+        let err = TsEventAcceptor::listen(server).await.err().unwrap();
+        assert_eq!(err.origin().unwrap().peer_addr, Some(peer_addr));
+        assert_eq!(err.origin().unwrap().connection_id, None);
+        assert_eq!(
+            err.to_string(),
+            "TS client closed connection (peer=192.0.2.1:443)"
+        );
+    }
 }
@@ -1,15 +1,22 @@
-use crate::framing::CloudProtoError::ClosedByPeer;
-use crate::framing::{CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoVersion};
-use crate::services::ts::{TsConnectInfo, TsConnectResponse, TsEventSocket, TsPacketKind};
+use crate::framing::{
+    sniff_protocol, CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoVersion,
+    PrefixedIo, SniffedProtocol,
+};
+use crate::services::ts::{
+    SensorProfile, TsConnectInfo, TsConnectResponse, TsConnectionOrigin, TsError, TsEventSocket,
+    TsPacketKind,
+};
 use crate::services::CloudProtoMagic;
 use bytes::Buf;
 use futures_util::{SinkExt, StreamExt};
 use std::io::Read;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
 
 /// Accept [`TsEventSocket`](TsEventSocket) connections
 pub struct TsEventAcceptor<IO: AsyncRead + AsyncWrite> {
     io: CloudProtoSocket<IO>,
+    info: TsConnectInfo,
 }
 
 impl<IO> TsEventAcceptor<IO>
@@ -17,35 +24,39 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     /// Wait for an incoming TS client connection, and return the received [`TsConnectInfo`](TsConnectInfo)
-    pub async fn listen(
-        mut io: CloudProtoSocket<IO>,
-    ) -> Result<(Self, TsConnectInfo), CloudProtoError> {
+    pub async fn listen(mut io: CloudProtoSocket<IO>) -> Result<(Self, TsConnectInfo), TsError> {
         let pkt = match io.next().await {
-            None => return Err(ClosedByPeer("TS client closed connection".into())),
-            Some(Err(e)) => return Err(e),
+            None => {
+                let origin = TsConnectionOrigin {
+                    peer_addr: io.peer_addr(),
+                    ..Default::default()
+                };
+                return Err(TsError::closed_by_peer(
+                    "TS client closed connection",
+                    origin,
+                ));
+            }
+            Some(Err(e)) => return Err(e.into()),
             Some(Ok(pkt)) => pkt,
         };
         if pkt.magic != CloudProtoMagic::TS {
-            return Err(CloudProtoError::BadMagic(pkt.magic, CloudProtoMagic::TS));
+            return Err(CloudProtoError::BadMagic(pkt.magic, CloudProtoMagic::TS).into());
         }
         if pkt.kind != TsPacketKind::Connect {
             return Err(CloudProtoError::WrongConnectionPacketKind(
                 pkt.kind,
                 TsPacketKind::Connect.into(),
-            ));
+            )
+            .into());
         }
         if pkt.version != CloudProtoVersion::Connect {
-            return Err(CloudProtoError::BadVersion(
-                pkt.version,
-                CloudProtoVersion::Connect,
-            ));
+            return Err(
+                CloudProtoError::BadVersion(pkt.version, CloudProtoVersion::Connect).into(),
+            );
         }
 
         if pkt.payload.len() != 4 * 16 + 8 {
-            return Err(CloudProtoError::PayloadInvalidSize(
-                pkt.payload.len(),
-                4 * 16 + 8,
-            ));
+            return Err(CloudProtoError::PayloadInvalidSize(pkt.payload.len(), 4 * 16 + 8).into());
         }
         let mut info = TsConnectInfo {
             cid: [0; 16],
@@ -61,14 +72,17 @@ where
         rd.read_exact(&mut info.bootid)?;
         rd.read_exact(&mut info.pt)?;
 
-        Ok((Self { io }, info))
+        Ok((
+            Self {
+                io,
+                info: info.clone(),
+            },
+            info,
+        ))
     }
 
     /// Accept an incoming TS client, establishing a connected socket
-    pub async fn accept(
-        mut self,
-        reply: TsConnectResponse,
-    ) -> Result<TsEventSocket<IO>, CloudProtoError> {
+    pub async fn accept(mut self, reply: TsConnectResponse) -> Result<TsEventSocket<IO>, TsError> {
         let mut payload = Vec::with_capacity(1 + 16);
         payload.push(reply.agent_id_status as u8);
         payload.extend_from_slice(&reply.aid);
@@ -76,10 +90,35 @@ where
             magic: CloudProtoMagic::TS,
             kind: TsPacketKind::ConnectionEstablished.into(),
             version: CloudProtoVersion::Normal,
-            payload,
+            payload: payload.into(),
         };
         self.io.send(pkt).await?;
 
-        Ok(TsEventSocket::new(self.io))
+        let mut info = self.info;
+        info.aid = reply.aid;
+        Ok(TsEventSocket::new(self.io, info, &SensorProfile::default()))
+    }
+}
+
+impl<IO> TsEventAcceptor<PrefixedIo<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Same as [`listen`](Self::listen), but connections that start like a plain HTTP request
+    /// are passed to `http_handler` instead of failing, and `None` is returned.
+    ///
+    /// This lets a TS server share a single port with an HTTP service.
+    /// See [`sniff_protocol`](sniff_protocol).
+    pub async fn listen_with_http_fallback(
+        io: IO,
+        http_handler: impl FnOnce(PrefixedIo<IO>),
+    ) -> Result<Option<(Self, TsConnectInfo)>, TsError> {
+        let (protocol, io) = sniff_protocol(io).await?;
+        if protocol == SniffedProtocol::Http {
+            debug!("Received HTTP request on TS acceptor, passing it to the HTTP handler");
+            http_handler(io);
+            return Ok(None);
+        }
+        Self::listen(CloudProtoSocket::new(io)).await.map(Some)
     }
 }
@@ -0,0 +1,161 @@
+//! A synchronous [`TsEventSocket`](TsEventSocket), for small tools that don't want a tokio runtime.
+
+use crate::framing::blocking::CloudProtoSocket;
+use crate::framing::CloudProtoError;
+use crate::redaction::PayloadDump;
+use crate::services::ts::socket::{ack_packet, check_connect_reply, connect_packet};
+use crate::services::ts::{Event, SensorProfile, TsConnectInfo, TsError, TsPacketKind};
+use crate::services::CloudProtoMagic;
+use std::io::{Read, Write};
+use tracing::{error, trace, warn};
+
+/// Blocking version of the async [`TsEventSocket`](super::TsEventSocket).
+///
+/// Received events are ACKed before [`recv`](Self::recv) returns them,
+/// and received ACKs are ignored, like the async socket does without an ACK window.
+/// Packets of other kinds are logged and skipped.
+pub struct TsEventSocket<IO: Read + Write> {
+    io: CloudProtoSocket<IO>,
+    connect_info: TsConnectInfo,
+    next_txid: u64,
+    txid_increment: u64,
+}
+
+impl<IO: Read + Write> TsEventSocket<IO> {
+    /// Connect to a TS server, behaving like the default [`SensorProfile`](SensorProfile)
+    pub fn connect(io: CloudProtoSocket<IO>, info: TsConnectInfo) -> Result<Self, TsError> {
+        Self::connect_with_profile(io, info, &SensorProfile::default())
+    }
+
+    /// Connect to a TS server, mimicking the wire behavior described by `profile`
+    pub fn connect_with_profile(
+        mut io: CloudProtoSocket<IO>,
+        mut info: TsConnectInfo,
+        profile: &SensorProfile,
+    ) -> Result<Self, TsError> {
+        io.send(connect_packet(&info))?;
+        let reply = match io.recv()? {
+            Some(pkt) => pkt,
+            None => {
+                return Err(TsError::closed_by_peer(
+                    "TS server closed connection",
+                    Default::default(),
+                ))
+            }
+        };
+        check_connect_reply(&mut info, &reply)?;
+        Ok(Self {
+            io,
+            connect_info: info,
+            next_txid: profile.first_txid,
+            txid_increment: profile.txid_increment,
+        })
+    }
+
+    /// The identity of the client on this connection, with the Agent ID negotiated during connection
+    pub fn connect_info(&self) -> &TsConnectInfo {
+        &self.connect_info
+    }
+
+    /// The txid that will be used for the next event sent
+    pub fn next_txid(&self) -> u64 {
+        self.next_txid
+    }
+
+    pub fn into_inner(self) -> CloudProtoSocket<IO> {
+        self.io
+    }
+
+    /// Send an event, without waiting for its ACK
+    pub fn send(&mut self, ev: Event) -> Result<(), TsError> {
+        let pkt = ev.into_packet(self.next_txid);
+        self.next_txid = self.next_txid.wrapping_add(self.txid_increment);
+        self.io.send(pkt)?;
+        Ok(())
+    }
+
+    /// Blocks until an event is received, and ACKs it.
+    /// Returns `None` if the server closed the connection cleanly.
+    pub fn recv(&mut self) -> Result<Option<Event>, TsError> {
+        loop {
+            let pkt = match self.io.recv()? {
+                Some(pkt) => pkt,
+                None => return Ok(None),
+            };
+            if pkt.magic != CloudProtoMagic::TS {
+                return Err(CloudProtoError::BadMagic(pkt.magic, CloudProtoMagic::TS).into());
+            }
+            if pkt.kind == TsPacketKind::Ack {
+                if pkt.payload.len() != 8 {
+                    error!(
+                        "Received ACK packet with invalid size: {:#x}",
+                        pkt.payload.len()
+                    );
+                }
+                continue;
+            } else if pkt.kind == TsPacketKind::Event {
+                let (txid, ev) = Event::from_packet(&pkt)?;
+                trace!("Received event with txid {:#x}, sending ACK", txid);
+                self.io.send(ack_packet(txid))?;
+                return Ok(Some(ev));
+            } else {
+                warn!(
+                    "Received unexpected CloudProto packet kind: {:#x}",
+                    pkt.kind
+                );
+                trace!(
+                    "Unexpected packet payload: {}",
+                    PayloadDump::new(&pkt.payload)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::{CloudProtoPacket, CloudProtoVersion};
+    use crate::services::ts::{AgentIdStatus, EventId};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test_log::test]
+    fn blocking_client() -> Result<(), TsError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = std::thread::spawn(move || {
+            let mut server = CloudProtoSocket::new(listener.accept()?.0);
+            let connect = server.recv()?.unwrap();
+            assert_eq!(connect.kind, TsPacketKind::Connect);
+            assert_eq!(connect.payload[..16], [1; 16]);
+            let mut reply = vec![AgentIdStatus::Changed as u8];
+            reply.extend_from_slice(&[0x42; 16]);
+            server.send(CloudProtoPacket {
+                magic: CloudProtoMagic::TS,
+                kind: TsPacketKind::ConnectionEstablished.into(),
+                version: CloudProtoVersion::Normal,
+                payload: reply.into(),
+            })?;
+
+            let (txid, ev) = Event::from_packet(&server.recv()?.unwrap())?;
+            server.send(ack_packet(txid))?;
+            server.send(ev.into_packet(0x1234))?;
+            let ack = server.recv()?.unwrap();
+            assert_eq!(ack, ack_packet(0x1234));
+            Ok::<_, TsError>(txid)
+        });
+
+        let mut client = TsEventSocket::connect(
+            CloudProtoSocket::new(TcpStream::connect(addr)?),
+            TsConnectInfo::new_simple([1; 16]),
+        )?;
+        assert_eq!(client.connect_info().aid, [0x42; 16]);
+        let first_txid = client.next_txid();
+        let ev = Event::new(EventId::AgentOnline, vec![0x08, 0x01]);
+        client.send(ev.clone())?;
+        assert_eq!(client.recv()?, Some(ev));
+        assert_eq!(server.join().unwrap()?, first_txid);
+        assert_eq!(client.recv()?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use crate::services::ts::protobuf::{WireField, WireFieldIter, WireType, WireValue};
+use crate::services::ts::{Event, InferredField, InferredSchema};
+use crate::WalkLimits;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// A difference between an event's `data` and the registered schema for its event ID.
+///
+/// Fields are identified by their path of field numbers from the top-level message,
+/// e.g. `[3, 1]` is field 1 of the nested message in field 3.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SchemaMismatch {
+    /// The message (or a nested message) is not valid Protobuf
+    InvalidProtobuf { path: Vec<u32> },
+    /// A field that never appeared in the schema's samples
+    UnknownField { path: Vec<u32>, wire_type: WireType },
+    /// A known field with a wire type that never appeared in the schema's samples
+    WrongWireType {
+        path: Vec<u32>,
+        expected: Vec<WireType>,
+        actual: WireType,
+    },
+    /// A field that was present in every sample of the schema is missing
+    MissingField { path: Vec<u32> },
+}
+
+/// Summary of the schema mismatches seen by a [`SchemaValidator`](SchemaValidator)
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DriftReport {
+    /// Number of events checked against a registered schema
+    pub checked_events: usize,
+    /// Number of checked events with at least one mismatch
+    pub drifted_events: usize,
+    /// Number of events without a registered schema, which were not checked
+    pub unregistered_events: usize,
+    /// Number of events with each mismatch, by raw event ID
+    pub mismatches: BTreeMap<u32, BTreeMap<SchemaMismatch, usize>>,
+}
+
+impl DriftReport {
+    pub fn has_drift(&self) -> bool {
+        self.drifted_events > 0
+    }
+}
+
+/// Checks received events against registered per-event-ID schemas, to detect schema drift.
+///
+/// Schemas are [`InferredSchema`](InferredSchema)s, typically inferred from events of a known
+/// sensor version. Events of a newer sensor can then be validated to find which fields were added,
+/// removed, or changed type. Since inferred schemas only know the wire format, a field that
+/// changed between two types with the same wire type (e.g. `int32` to `bool`) is not detected.
+///
+/// Call [`validate`](Self::validate) on each received event, e.g. from [`StreamExt::inspect`](futures_util::StreamExt::inspect).
+#[derive(Debug, Clone, Default)]
+pub struct SchemaValidator {
+    schemas: HashMap<u32, InferredSchema>,
+    report: DriftReport,
+    limits: WalkLimits,
+}
+
+impl SchemaValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits on the walk of each validated event. Nested messages past the depth limit
+    /// are not validated, and fields past the field limit are ignored.
+    pub fn set_walk_limits(&mut self, limits: WalkLimits) {
+        self.limits = limits;
+    }
+
+    /// Register the expected schema of the events with this raw event ID, replacing any previous one
+    pub fn register(&mut self, raw_event_id: impl Into<u32>, schema: InferredSchema) {
+        self.schemas.insert(raw_event_id.into(), schema);
+    }
+
+    /// Check an event against the schema registered for its ID, and record the result in the report.
+    ///
+    /// Returns the mismatches found in this event, which is empty if it matches its schema
+    /// or if no schema is registered for its ID.
+    pub fn validate(&mut self, ev: &Event) -> Vec<SchemaMismatch> {
+        let schema = match self.schemas.get(&ev.raw_event_id) {
+            Some(schema) => schema,
+            None => {
+                self.report.unregistered_events += 1;
+                return Vec::new();
+            }
+        };
+        let mut walk = Walk {
+            limits: &self.limits,
+            fields_left: self.limits.max_fields,
+            path: Vec::new(),
+            mismatches: BTreeSet::new(),
+        };
+        walk.check_message(schema, &ev.data, 0);
+        let mismatches = walk.mismatches;
+
+        self.report.checked_events += 1;
+        if !mismatches.is_empty() {
+            self.report.drifted_events += 1;
+            let counts = self.report.mismatches.entry(ev.raw_event_id).or_default();
+            for mismatch in &mismatches {
+                *counts.entry(mismatch.clone()).or_default() += 1;
+            }
+        }
+        mismatches.into_iter().collect()
+    }
+
+    pub fn report(&self) -> &DriftReport {
+        &self.report
+    }
+
+    /// Returns the report so far, and starts a new one
+    pub fn take_report(&mut self) -> DriftReport {
+        std::mem::take(&mut self.report)
+    }
+}
+
+fn expected_wire_types(field: &InferredField) -> Vec<WireType> {
+    let mut types = Vec::new();
+    if field.varint_range.is_some() {
+        types.push(WireType::Varint);
+    }
+    if field.fixed64_count > 0 {
+        types.push(WireType::Fixed64);
+    }
+    if field.len_delimited_count > 0 {
+        types.push(WireType::LengthDelimited);
+    }
+    if field.fixed32_count > 0 {
+        types.push(WireType::Fixed32);
+    }
+    types
+}
+
+// Only fields that always parsed as messages in the samples are validated as messages
+fn nested_schema(field: &InferredField) -> Option<&InferredSchema> {
+    field
+        .nested
+        .as_deref()
+        .filter(|nested| field.string_count == 0 && nested.invalid_samples == 0)
+}
+
+struct Walk<'a> {
+    limits: &'a WalkLimits,
+    fields_left: usize,
+    path: Vec<u32>,
+    mismatches: BTreeSet<SchemaMismatch>,
+}
+
+impl Walk<'_> {
+    fn mismatch(&mut self, mismatch: SchemaMismatch) {
+        self.mismatches.insert(mismatch);
+    }
+
+    fn check_message(&mut self, schema: &InferredSchema, data: &[u8], depth: usize) {
+        if !WireFieldIter::new(data).all(|f| f.is_ok()) {
+            let path = self.path.clone();
+            self.mismatch(SchemaMismatch::InvalidProtobuf { path });
+            return;
+        }
+
+        let mut seen = BTreeSet::new();
+        for WireField { number, value } in WireFieldIter::new(data).flatten() {
+            // Fields past the limit are unknown, so missing fields can't be told apart either
+            if self.fields_left == 0 {
+                return;
+            }
+            self.fields_left -= 1;
+            seen.insert(number);
+            self.path.push(number);
+            match schema.fields.get(&number) {
+                None => {
+                    let path = self.path.clone();
+                    self.mismatch(SchemaMismatch::UnknownField {
+                        path,
+                        wire_type: value.wire_type(),
+                    });
+                }
+                Some(field) => {
+                    let expected = expected_wire_types(field);
+                    if !expected.contains(&value.wire_type()) {
+                        let path = self.path.clone();
+                        self.mismatch(SchemaMismatch::WrongWireType {
+                            path,
+                            expected,
+                            actual: value.wire_type(),
+                        });
+                    } else if let (WireValue::LengthDelimited(bytes), Some(nested)) =
+                        (value, nested_schema(field))
+                    {
+                        if depth < self.limits.max_depth {
+                            self.check_message(nested, bytes, depth + 1);
+                        }
+                    }
+                }
+            }
+            self.path.pop();
+        }
+
+        for (&number, field) in &schema.fields {
+            if schema.samples > 0 && field.presence == schema.samples && !seen.contains(&number) {
+                self.path.push(number);
+                let path = self.path.clone();
+                self.mismatch(SchemaMismatch::MissingField { path });
+                self.path.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::ts::EventId;
+
+    #[test]
+    fn schema_drift() {
+        // 1: 1, 2: "foo", 3: { 1: 300 }
+        let baseline = hex::decode("08011203666f6f1a0308ac02").unwrap();
+        let mut validator = SchemaValidator::new();
+        validator.register(
+            EventId::OsVersionInfo,
+            InferredSchema::from_samples([baseline.as_slice()]),
+        );
+
+        let ev = Event::new(EventId::OsVersionInfo, baseline.clone());
+        assert_eq!(validator.validate(&ev), vec![]);
+        let unregistered = Event::new(EventId::AgentOnline, vec![0xFF]);
+        assert_eq!(validator.validate(&unregistered), vec![]);
+
+        // 1: "x", 2: "foo", 3: { 1: 300, 2: 1 }, missing nothing, but field 1 changed type
+        let drifted = Event::new(
+            EventId::OsVersionInfo,
+            hex::decode("0a01781203666f6f1a0508ac021001").unwrap(),
+        );
+        assert_eq!(
+            validator.validate(&drifted),
+            vec![
+                SchemaMismatch::UnknownField {
+                    path: vec![3, 2],
+                    wire_type: WireType::Varint
+                },
+                SchemaMismatch::WrongWireType {
+                    path: vec![1],
+                    expected: vec![WireType::Varint],
+                    actual: WireType::LengthDelimited
+                },
+            ]
+        );
+        // Only 2: "foo"
+        let truncated = Event::new(EventId::OsVersionInfo, hex::decode("1203666f6f").unwrap());
+        assert_eq!(
+            validator.validate(&truncated),
+            vec![
+                SchemaMismatch::MissingField { path: vec![1] },
+                SchemaMismatch::MissingField { path: vec![3] },
+            ]
+        );
+
+        // The nested mismatch is past the depth limit
+        validator.set_walk_limits(WalkLimits {
+            max_depth: 0,
+            ..Default::default()
+        });
+        assert_eq!(validator.validate(&drifted).len(), 1);
+        // Same depth convention as inference: a limit of 1 still checks the first nested level
+        validator.set_walk_limits(WalkLimits {
+            max_depth: 1,
+            ..Default::default()
+        });
+        assert_eq!(validator.validate(&drifted).len(), 2);
+        validator.set_walk_limits(WalkLimits::default());
+
+        let report = validator.take_report();
+        assert!(report.has_drift());
+        assert_eq!(report.checked_events, 5);
+        assert_eq!(report.drifted_events, 4);
+        assert_eq!(report.unregistered_events, 1);
+        assert_eq!(report.mismatches[&(EventId::OsVersionInfo as u32)].len(), 4);
+        assert_eq!(validator.report(), &DriftReport::default());
+    }
+}
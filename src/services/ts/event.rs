@@ -1,10 +1,27 @@
-use crate::framing::CloudProtoError;
+use crate::framing::{CloudProtoError, CloudProtoPacket, CloudProtoVersion};
+use crate::redaction::SensitivePayload;
+use crate::services::ts::protobuf::{extract_strings, ProtobufError, WireFieldIter};
+use crate::services::ts::TsPacketKind;
+use crate::services::CloudProtoMagic;
+use crate::WalkLimits;
 use byteorder::{ReadBytesExt, WriteBytesExt, BE};
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 use strum_macros::{AsRefStr, Display, FromRepr};
 
 // Does not count the txid, which is handled transparently in the TsEventSocket
 pub(crate) const EVT_HDR_LEN: usize = 4;
+pub(crate) const HDR_TXID_SIZE: usize = std::mem::size_of::<u64>();
+
+// Lets the framing layer apply payload suppression settings to TS events in its trace logs
+pub(crate) fn packet_event_id(pkt: &CloudProtoPacket) -> Option<u32> {
+    if pkt.magic != CloudProtoMagic::TS || pkt.kind != TsPacketKind::Event {
+        return None;
+    }
+    let id = pkt
+        .payload
+        .get(HDR_TXID_SIZE..HDR_TXID_SIZE + EVT_HDR_LEN)?;
+    Some(u32::from_be_bytes(id.try_into().unwrap()))
+}
 
 /// The `data` field usually contains a serialized Protobuf structure.
 ///
@@ -14,13 +31,23 @@ pub(crate) const EVT_HDR_LEN: usize = 4;
 /// A few event IDs do not correspond to protobuf data at all, using a variety of other simple binary formats.
 ///
 /// The `event_id` field is `None` for values of `raw_event_id` that are not in the [`EventId`](EventId) enum.
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Clone)]
 pub struct Event {
     pub raw_event_id: u32,
     pub event_id: Option<EventId>,
     pub data: Vec<u8>,
 }
 
+impl std::fmt::Debug for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Event")
+            .field("raw_event_id", &self.raw_event_id)
+            .field("event_id", &self.event_id)
+            .field("data", &SensitivePayload(&self.data))
+            .finish()
+    }
+}
+
 impl Event {
     pub fn new(event_id: EventId, data: Vec<u8>) -> Self {
         Self {
@@ -47,6 +74,65 @@ impl Event {
         }
     }
 
+    /// Cheap sanity check that the `data` is well-formed serialized Protobuf.
+    ///
+    /// This only walks the wire format, it does not check fields against any schema.
+    /// It can be used to quarantine garbage payloads before they reach heavier processing,
+    /// but remember that a few event IDs carry non-Protobuf data, which may fail this check.
+    pub fn validate(&self) -> Result<(), ProtobufError> {
+        WireFieldIter::new(&self.data).try_for_each(|field| field.map(|_| ()))
+    }
+
+    /// Best effort extraction of the printable strings embedded in the event's Protobuf `data`.
+    ///
+    /// Many events whose schema is unknown still carry interesting strings, like the command lines
+    /// of the `UNK_ProcessInfo` events or the user names of the `VarRunUtmpUsers` events.
+    /// Strings are returned in the order they appear, including those in nested messages.
+    pub fn extract_strings(&self) -> Vec<String> {
+        self.extract_strings_with_limits(&WalkLimits::default())
+    }
+
+    /// Same as [`extract_strings`](Self::extract_strings), with explicit limits
+    pub fn extract_strings_with_limits(&self, limits: &WalkLimits) -> Vec<String> {
+        extract_strings(&self.data, limits)
+    }
+
+    /// Parses the payload of a TS Event packet, returning its txid and the event.
+    ///
+    /// This is the conversion used by [`TsEventSocket`](super::TsEventSocket), exposed for tools
+    /// that handle packets without a socket. The packet kind and magic are not checked.
+    ///
+    /// An Event packet always carries exactly one event. The event header is only a txid
+    /// and an event ID, with no inner length field, so the event's `data` runs to the end of the frame
+    /// and there would be no way to delimit several events. The official client also builds
+    /// one frame per event. Any trailing bytes in your captures are part of the `data`.
+    pub fn from_packet(pkt: &CloudProtoPacket) -> Result<(u64, Self), CloudProtoError> {
+        if pkt.payload.len() < HDR_TXID_SIZE + EVT_HDR_LEN {
+            return Err(CloudProtoError::PayloadTooShort(
+                pkt.payload.len(),
+                HDR_TXID_SIZE + EVT_HDR_LEN,
+            ));
+        }
+        let txid = u64::from_be_bytes(pkt.payload[..HDR_TXID_SIZE].try_into().unwrap());
+        let ev = Self::from_read(&mut Cursor::new(&pkt.payload[HDR_TXID_SIZE..]))?;
+        Ok((txid, ev))
+    }
+
+    /// Builds the TS Event packet carrying this event with the given txid,
+    /// as sent by [`TsEventSocket`](super::TsEventSocket)
+    pub fn into_packet(self, txid: u64) -> CloudProtoPacket {
+        let mut payload = Vec::with_capacity(HDR_TXID_SIZE + EVT_HDR_LEN + self.data.len());
+        payload.extend_from_slice(&txid.to_be_bytes());
+        self.into_write(&mut payload)
+            .expect("Writing to a Vec can't fail");
+        CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: TsPacketKind::Event.into(),
+            version: CloudProtoVersion::Normal,
+            payload: payload.into(),
+        }
+    }
+
     pub(crate) fn from_read(reader: &mut dyn Read) -> Result<Self, CloudProtoError> {
         let raw_event_id = reader.read_u32::<BE>()?;
         let event_id = EventId::from_repr(raw_event_id);
@@ -126,6 +212,12 @@ pub enum EventId {
     UNK_ProcessInfo_0x340000ee =        0x340000EE, // No search results. Contains a cmdline that was run with some proces info
 }
 
+impl From<EventId> for u32 {
+    fn from(id: EventId) -> Self {
+        id as u32
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,6 +235,32 @@ mod tests {
         assert_eq!(ev.ev_id_string(), "0xAABBCCDD")
     }
 
+    #[test]
+    fn test_validate() {
+        assert!(Event::new(EventId::AgentOnline, vec![]).validate().is_ok());
+        assert!(Event::new(EventId::AgentOnline, vec![0x08, 0x01])
+            .validate()
+            .is_ok());
+        assert!(Event::new(EventId::AgentOnline, vec![0x12, 0x08, 0x00])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_event_packet_roundtrip() {
+        let ev = Event::new(EventId::AgentOnline, vec![0x08, 0x01]);
+        let pkt = ev.clone().into_packet(0x1234);
+        assert_eq!(hex::encode(&pkt.payload), "0000000000001234338000ac0801");
+        assert_eq!(Event::from_packet(&pkt).unwrap(), (0x1234, ev));
+
+        let short = Event::new_raw(0, vec![]).into_packet(0);
+        let short = CloudProtoPacket {
+            payload: short.payload[..10].to_vec().into(),
+            ..short
+        };
+        assert!(Event::from_packet(&short).is_err());
+    }
+
     #[test]
     fn test_event_serde_rountrip() {
         let ev = Event::new_raw(0xAABBCCDD, vec![]);
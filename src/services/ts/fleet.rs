@@ -0,0 +1,128 @@
+use crate::redaction::SensitiveId;
+use crate::services::ts::TsConnectInfo;
+use crate::services::{fold_seed, splitmix64};
+
+// Number of 64bit values drawn for each node
+const VALUES_PER_NODE: u64 = 6;
+
+/// Derives stable machine identities for the nodes of an emulated fleet.
+///
+/// Each node is identified by its index, and the same seed and index always give the same identity,
+/// so that tests and load generators are reproducible across runs without storing identities.
+/// The values of different nodes of a given generator never collide, for indices up to
+/// [`MAX_INDEX`](Self::MAX_INDEX).
+/// Like [`LfoIdentity::pseudonymous`](crate::services::lfo::LfoIdentity::pseudonymous),
+/// these values are random-looking but belong to no real machine.
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct FleetIdentityGenerator {
+    state: u64,
+}
+
+/// The per-machine values of one node of a [`FleetIdentityGenerator`](FleetIdentityGenerator)
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub struct FleetNodeIdentity {
+    pub index: u64,
+    /// See [`TsConnectInfo::bootid`](TsConnectInfo)
+    pub bootid: [u8; 16],
+    /// See [`TsConnectInfo::unk0`](TsConnectInfo)
+    pub unk0: [u8; 16],
+    /// The AID the node presents on its first connection. TS may assign it a different one.
+    pub aid: [u8; 16],
+}
+
+impl std::fmt::Debug for FleetNodeIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FleetNodeIdentity")
+            .field("index", &self.index)
+            .field("bootid", &SensitiveId(&self.bootid))
+            .field("unk0", &SensitiveId(&self.unk0))
+            .field("aid", &SensitiveId(&self.aid))
+            .finish()
+    }
+}
+
+impl FleetIdentityGenerator {
+    /// The largest node index, past which the values of nodes would collide
+    pub const MAX_INDEX: u64 = (u64::MAX - (VALUES_PER_NODE - 1)) / VALUES_PER_NODE;
+
+    pub fn new(seed: &[u8]) -> Self {
+        Self {
+            state: fold_seed(seed),
+        }
+    }
+
+    /// The identity of the node at `index`.
+    ///
+    /// # Panics
+    /// If `index` is larger than [`MAX_INDEX`](Self::MAX_INDEX).
+    pub fn node(&self, index: u64) -> FleetNodeIdentity {
+        assert!(
+            index <= Self::MAX_INDEX,
+            "Fleet node index {index} is over the maximum of {}",
+            Self::MAX_INDEX
+        );
+        let mut values = [0u8; VALUES_PER_NODE as usize * 8];
+        for (n, chunk) in values.chunks_mut(8).enumerate() {
+            let pos = index * VALUES_PER_NODE + n as u64;
+            chunk.copy_from_slice(&splitmix64(self.state, pos).to_be_bytes());
+        }
+        FleetNodeIdentity {
+            index,
+            bootid: values[..16].try_into().unwrap(),
+            unk0: values[16..32].try_into().unwrap(),
+            aid: values[32..].try_into().unwrap(),
+        }
+    }
+
+    /// The identities of the nodes at indices `0..count`
+    pub fn nodes(&self, count: u64) -> impl Iterator<Item = FleetNodeIdentity> + '_ {
+        (0..count).map(|index| self.node(index))
+    }
+}
+
+impl FleetNodeIdentity {
+    /// Connection info for this node, for a customer's CID
+    pub fn connect_info(&self, cid: [u8; 16]) -> TsConnectInfo {
+        TsConnectInfo::new_custom(cid, self.unk0, self.aid, self.bootid, [0; 8])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn reproducible_fleet() {
+        let fleet = FleetIdentityGenerator::new(b"fleet-a");
+        assert_eq!(
+            fleet.node(42),
+            FleetIdentityGenerator::new(b"fleet-a").node(42)
+        );
+        assert_ne!(
+            fleet.node(42),
+            FleetIdentityGenerator::new(b"fleet-b").node(42)
+        );
+
+        let mut values = HashSet::new();
+        for node in fleet.nodes(1000) {
+            assert!(values.insert(node.bootid));
+            assert!(values.insert(node.unk0));
+            assert!(values.insert(node.aid));
+        }
+
+        let info = fleet.node(7).connect_info([0x11; 16]);
+        assert_eq!(info.bootid, fleet.node(7).bootid);
+        assert_eq!(info.cid, [0x11; 16]);
+
+        // The last node still fits in the positions of the generator
+        let last = fleet.node(FleetIdentityGenerator::MAX_INDEX);
+        assert!(values.insert(last.aid));
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_past_max() {
+        FleetIdentityGenerator::new(b"fleet-a").node(FleetIdentityGenerator::MAX_INDEX + 1);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::services::ts::{Event, TsConnectionOrigin, TsError, TsEventSocket};
+use futures_util::{SinkExt, Stream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+
+// Maximum number of events waiting in each direction between a TsFlushingHandle and its task
+const HANDLE_QUEUE_LEN: usize = 32;
+
+impl<IO> TsEventSocket<IO>
+where
+    IO: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Moves the socket into a background task that flushes it every `interval`,
+    /// and returns a handle to send and receive events through it.
+    ///
+    /// Events sent with [`feed`](TsFlushingHandle::feed) are buffered like with
+    /// [`SinkExt::feed`](SinkExt::feed), but they can't get stranded in the write buffer
+    /// when the caller forgets to flush or stops polling the receive side.
+    /// Received events are delivered by the handle's [`Stream`](Stream), which also returns
+    /// the error that stopped the task, if any. Like with the socket itself, the handle's
+    /// stream should be polled regularly: the task doesn't flush while it waits for room there.
+    ///
+    /// The write side is flushed one last time when the handle is dropped, then the task stops.
+    /// This must be called from within a Tokio runtime.
+    pub fn into_flushing_handle(self, interval: Duration) -> TsFlushingHandle {
+        let (send_tx, mut send_rx) = mpsc::channel::<Event>(HANDLE_QUEUE_LEN);
+        let (recv_tx, recv_rx) = mpsc::channel(HANDLE_QUEUE_LEN);
+        let connection_id = self.connection_id();
+        let origin = self.origin();
+        let (mut sink, mut stream) = self.split();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                let result = tokio::select! {
+                    ev = send_rx.recv() => match ev {
+                        Some(ev) => sink.feed(ev).await,
+                        None => {
+                            let _ = sink.flush().await;
+                            break;
+                        }
+                    },
+                    received = stream.next() => match received {
+                        Some(received) => {
+                            // Waits for the handle to read, or fails once it is dropped
+                            let _ = recv_tx.send(received).await;
+                            Ok(())
+                        }
+                        None => break,
+                    },
+                    _ = ticker.tick() => sink.flush().await,
+                };
+                if let Err(e) = result {
+                    let _ = recv_tx.send(Err(e)).await;
+                    break;
+                }
+            }
+            debug!(conn_id = connection_id, "Stopping TS flusher task");
+        });
+        TsFlushingHandle {
+            tx: send_tx,
+            rx: recv_rx,
+            origin,
+        }
+    }
+}
+
+/// A [`TsEventSocket`](TsEventSocket) running in a background task that flushes it periodically.
+///
+/// See [`TsEventSocket::into_flushing_handle`](TsEventSocket::into_flushing_handle).
+pub struct TsFlushingHandle {
+    tx: mpsc::Sender<Event>,
+    rx: mpsc::Receiver<Result<Event, TsError>>,
+    origin: TsConnectionOrigin,
+}
+
+impl TsFlushingHandle {
+    /// Queue an event to be sent, it is flushed at the latest after the handle's interval.
+    pub async fn feed(&self, ev: Event) -> Result<(), TsError> {
+        self.tx
+            .send(ev)
+            .await
+            .map_err(|_| TsError::closed_by_peer("TS flusher task stopped", self.origin.clone()))
+    }
+}
+
+impl Stream for TsFlushingHandle {
+    type Item = Result<Event, TsError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::services::ts::socket::test::connected_pair;
+    use crate::services::ts::{Event, EventId, TsError};
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn periodic_flush() -> Result<(), TsError> {
+        let (client, mut server) = connected_pair().await?;
+        let mut client = client.into_flushing_handle(Duration::from_millis(100));
+
+        // The client is never flushed explicitly
+        client
+            .feed(Event::new(EventId::AgentOnline, vec![1]))
+            .await?;
+        let ev = server.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::AgentOnline));
+
+        server
+            .feed(Event::new(EventId::ChannelRundown, vec![]))
+            .await?;
+        server.flush().await?;
+        let ev = client.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::ChannelRundown));
+
+        // The task stops when the connection ends
+        drop(server);
+        assert!(client.next().await.is_none());
+        assert!(client
+            .feed(Event::new(EventId::DiskCapacity, vec![]))
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+use crate::framing::{CloudProtoCodec, CloudProtoError};
+use crate::services::ts::protobuf::{is_printable, WireField, WireFieldIter, WireValue};
+use crate::services::ts::{Event, TsPacketKind};
+use crate::services::CloudProtoMagic;
+use crate::WalkLimits;
+use bytes::{Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::codec::Decoder;
+
+// Frame header bytes before and including the length field
+const FRAME_HDR_LEN: usize = 8;
+
+struct Walker<'a> {
+    json: &'a mut String,
+    limits: &'a WalkLimits,
+    start_len: usize,
+    fields: usize,
+}
+
+/// Serialize an event as a single line of JSON, with its Protobuf `data` walked without a schema.
+///
+/// The object has the `txid`, `raw_event_id`, `event_id` (the [`EventId`](super::EventId) name,
+/// or `null`), the walked `fields`, and the raw `data` in hex. If `timestamp` is given,
+/// it is included as `timestamp_ms` since the Unix epoch.
+///
+/// Fields are an object keyed by field number, with an array of values for each field.
+/// Without a schema length-delimited values are ambiguous, so each value is tagged with its guessed
+/// type: `{"varint": 1}`, `{"fixed32": 1}`, `{"fixed64": 1}`, `{"string": "..."}`,
+/// `{"message": {...}}` or `{"bytes": "<hex>"}`. Data that is not valid Protobuf has `null` fields.
+///
+/// This uses the default [`WalkLimits`](WalkLimits).
+pub fn event_to_json(ev: &Event, txid: u64, timestamp: Option<SystemTime>) -> String {
+    event_to_json_with_limits(ev, txid, timestamp, &WalkLimits::default())
+}
+
+/// Same as [`event_to_json`](event_to_json), with explicit limits
+pub fn event_to_json_with_limits(
+    ev: &Event,
+    txid: u64,
+    timestamp: Option<SystemTime>,
+    limits: &WalkLimits,
+) -> String {
+    let mut json = String::new();
+    write!(json, "{{\"txid\":{}", txid).unwrap();
+    if let Some(ts) = timestamp {
+        let ms = ts
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        write!(json, ",\"timestamp_ms\":{}", ms).unwrap();
+    }
+    write!(json, ",\"raw_event_id\":{},\"event_id\":", ev.raw_event_id).unwrap();
+    match ev.event_id {
+        Some(id) => write_json_str(&mut json, id.as_ref()),
+        None => json.push_str("null"),
+    }
+    json.push_str(",\"fields\":");
+    let mut walker = Walker {
+        start_len: json.len(),
+        json: &mut json,
+        limits,
+        fields: 0,
+    };
+    if !walker.write_message(&ev.data, 0) {
+        json.push_str("null");
+    }
+    let data_len = ev.data.len().min(limits.max_output_len / 2);
+    write!(json, ",\"data\":\"{}\"", hex::encode(&ev.data[..data_len])).unwrap();
+    if data_len < ev.data.len() {
+        json.push_str(",\"data_truncated\":true");
+    }
+    json.push('}');
+    json
+}
+
+/// Decode a capture of one direction of a TS session into newline-delimited JSON.
+///
+/// The capture is the raw CLOUDPROTO byte stream (after any TLS), e.g. recorded with
+/// `RecordingIo` from the `testing` feature. Event packets are written with [`event_to_json_with_limits`],
+/// with an extra `offset` of their frame in the capture. Other packets are written as objects
+/// with their `offset`, `magic`, `kind` and `payload_len`, and so are event packets that can't be parsed,
+/// with an extra `error`. Captures do not contain timestamps.
+///
+/// Frames are split with a [`CloudProtoCodec`](CloudProtoCodec), and their payloads are slices
+/// of the capture. Returns the number of lines written. A truncated or invalid frame is an error,
+/// since the frames after it can't be found, but the lines for the frames before it are still written.
+pub fn capture_to_jsonl(
+    capture: Bytes,
+    out: &mut dyn Write,
+    limits: &WalkLimits,
+) -> Result<usize, CloudProtoError> {
+    let capture_len = capture.len();
+    let mut codec = CloudProtoCodec::with_max_frame_length(capture_len);
+    let mut buf = BytesMut::from(capture);
+    let mut lines = 0;
+    loop {
+        let offset = capture_len - buf.len();
+        let pkt = match codec.decode(&mut buf)? {
+            Some(pkt) => pkt,
+            None => break,
+        };
+        let event = (pkt.magic == CloudProtoMagic::TS && pkt.kind == TsPacketKind::Event)
+            .then(|| Event::from_packet(&pkt));
+        let line = match event {
+            Some(Ok((txid, ev))) => {
+                let json = event_to_json_with_limits(&ev, txid, None, limits);
+                format!("{{\"offset\":{},{}", offset, &json[1..])
+            }
+            other => {
+                let mut line = format!(
+                    "{{\"offset\":{},\"magic\":{},\"kind\":{},\"payload_len\":{}",
+                    offset,
+                    u8::from(pkt.magic),
+                    pkt.kind,
+                    pkt.payload.len()
+                );
+                if let Some(Err(e)) = other {
+                    line.push_str(",\"error\":");
+                    write_json_str(&mut line, &e.to_string());
+                }
+                line.push('}');
+                line
+            }
+        };
+        writeln!(out, "{}", line)?;
+        lines += 1;
+    }
+    // The codec waits for the rest of a truncated frame
+    match buf.get(4..FRAME_HDR_LEN) {
+        _ if buf.is_empty() => Ok(lines),
+        Some(len) => {
+            let frame_len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            Err(CloudProtoError::BadFrameSize(buf.len(), frame_len))
+        }
+        None => Err(CloudProtoError::PayloadTooShort(buf.len(), FRAME_HDR_LEN)),
+    }
+}
+
+impl Walker<'_> {
+    fn output_len(&self) -> usize {
+        self.json.len() - self.start_len
+    }
+
+    // Returns false without writing anything if the data is not valid Protobuf
+    fn write_message(&mut self, data: &[u8], depth: usize) -> bool {
+        if !WireFieldIter::new(data).all(|f| f.is_ok()) {
+            return false;
+        }
+        // One field past the budget is enough to know the message gets truncated
+        let budget = self.limits.max_fields.saturating_sub(self.fields) + 1;
+        let mut fields: BTreeMap<u32, Vec<WireValue>> = BTreeMap::new();
+        for WireField { number, value } in WireFieldIter::new(data).flatten().take(budget) {
+            fields.entry(number).or_default().push(value);
+        }
+
+        self.json.push('{');
+        let mut truncated = None;
+        let mut written = 0;
+        'fields: for (number, values) in &fields {
+            for (j, value) in values.iter().enumerate() {
+                if self.fields >= self.limits.max_fields {
+                    truncated = Some("fields");
+                } else if self.output_len() >= self.limits.max_output_len {
+                    truncated = Some("output_size");
+                }
+                if truncated.is_some() {
+                    // Only close the array if its key was written, so no empty field shows up
+                    if j > 0 {
+                        self.json.push(']');
+                    }
+                    break 'fields;
+                }
+                if j > 0 {
+                    self.json.push(',');
+                } else {
+                    if written > 0 {
+                        self.json.push(',');
+                    }
+                    write!(self.json, "\"{}\":[", number).unwrap();
+                    written += 1;
+                }
+                self.fields += 1;
+                self.write_value(value, depth);
+            }
+            self.json.push(']');
+        }
+        if let Some(reason) = truncated {
+            if written > 0 {
+                self.json.push(',');
+            }
+            write!(self.json, "\"truncated\":\"{}\"", reason).unwrap();
+        }
+        self.json.push('}');
+        true
+    }
+
+    fn write_value(&mut self, value: &WireValue, depth: usize) {
+        match value {
+            WireValue::Varint(v) => write!(self.json, "{{\"varint\":{}}}", v).unwrap(),
+            WireValue::Fixed32(v) => write!(self.json, "{{\"fixed32\":{}}}", v).unwrap(),
+            WireValue::Fixed64(v) => write!(self.json, "{{\"fixed64\":{}}}", v).unwrap(),
+            WireValue::LengthDelimited(bytes) => {
+                let text = std::str::from_utf8(bytes).ok().filter(|s| is_printable(s));
+                if let Some(text) = text {
+                    let room = self.limits.max_output_len.saturating_sub(self.output_len());
+                    let mut shown_len = text.len().min(room);
+                    while !text.is_char_boundary(shown_len) {
+                        shown_len -= 1;
+                    }
+                    self.json.push_str("{\"string\":");
+                    write_json_str(self.json, &text[..shown_len]);
+                    if shown_len < text.len() {
+                        self.json.push_str(",\"truncated\":\"output_size\"");
+                    }
+                    self.json.push('}');
+                    return;
+                }
+                if !bytes.is_empty() {
+                    if depth >= self.limits.max_depth {
+                        if WireFieldIter::new(bytes).all(|f| f.is_ok()) {
+                            self.json
+                                .push_str("{\"message\":{\"truncated\":\"depth\"}}");
+                            return;
+                        }
+                    } else {
+                        let start = self.json.len();
+                        self.json.push_str("{\"message\":");
+                        if self.write_message(bytes, depth + 1) {
+                            self.json.push('}');
+                            return;
+                        }
+                        self.json.truncate(start);
+                    }
+                }
+                let room = self.limits.max_output_len.saturating_sub(self.output_len()) / 2;
+                let shown = &bytes[..bytes.len().min(room)];
+                write!(self.json, "{{\"bytes\":\"{}\"", hex::encode(shown)).unwrap();
+                if shown.len() < bytes.len() {
+                    self.json.push_str(",\"truncated\":\"output_size\"");
+                }
+                self.json.push('}');
+            }
+        }
+    }
+}
+
+fn write_json_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::{CloudProtoPacket, CloudProtoVersion};
+    use crate::services::ts::EventId;
+    use std::time::Duration;
+
+    #[test]
+    fn event_json() {
+        // 1: 1, 2: "fo\"o", 2: "x", 3: { 1: 300 }, 4: [0xff]
+        let data = hex::decode("08011204666f226f1201781a0308ac022201ff").unwrap();
+        let ev = Event::new(EventId::OsVersionInfo, data);
+        let ts = UNIX_EPOCH + Duration::from_millis(1234);
+        assert_eq!(
+            event_to_json(&ev, 0x200, Some(ts)),
+            "{\"txid\":512,\"timestamp_ms\":1234,\"raw_event_id\":838861134,\"event_id\":\"OsVersionInfo\",\
+             \"fields\":{\"1\":[{\"varint\":1}],\"2\":[{\"string\":\"fo\\\"o\"},{\"string\":\"x\"}],\
+             \"3\":[{\"message\":{\"1\":[{\"varint\":300}]}}],\"4\":[{\"bytes\":\"ff\"}]},\
+             \"data\":\"08011204666f226f1201781a0308ac022201ff\"}"
+        );
+
+        let invalid = Event::new_raw(0x1234, vec![0xFF]);
+        assert_eq!(
+            event_to_json(&invalid, 0, None),
+            "{\"txid\":0,\"raw_event_id\":4660,\"event_id\":null,\"fields\":null,\"data\":\"ff\"}"
+        );
+    }
+
+    #[test]
+    fn capture_jsonl() {
+        let mut capture = Event::new(EventId::AgentOnline, vec![0x08, 0x01])
+            .into_packet(0x200)
+            .to_buf()
+            .to_vec();
+        capture.extend(
+            CloudProtoPacket {
+                magic: CloudProtoMagic::TS,
+                kind: TsPacketKind::Ack.into(),
+                version: CloudProtoVersion::Normal,
+                payload: 0x200u64.to_be_bytes().to_vec().into(),
+            }
+            .to_buf(),
+        );
+        // An event packet too short for its header
+        capture.extend(
+            CloudProtoPacket {
+                magic: CloudProtoMagic::TS,
+                kind: TsPacketKind::Event.into(),
+                version: CloudProtoVersion::Normal,
+                payload: vec![0; 4].into(),
+            }
+            .to_buf(),
+        );
+        let capture = Bytes::from(capture);
+        let mut out = Vec::new();
+        let limits = WalkLimits::default();
+        assert_eq!(
+            capture_to_jsonl(capture.clone(), &mut out, &limits).unwrap(),
+            3
+        );
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[..2],
+            [
+                "{\"offset\":0,\"txid\":512,\"raw_event_id\":864026796,\"event_id\":\"AgentOnline\",\
+                 \"fields\":{\"1\":[{\"varint\":1}]},\"data\":\"0801\"}",
+                "{\"offset\":22,\"magic\":143,\"kind\":4,\"payload_len\":8}"
+            ]
+        );
+        // Bad events get an error line, and the rest of the capture is still decoded
+        assert!(lines[2]
+            .starts_with("{\"offset\":38,\"magic\":143,\"kind\":3,\"payload_len\":4,\"error\":\""));
+
+        // Truncated frames are an error, after writing the complete ones
+        let mut out = Vec::new();
+        let truncated = capture.slice(..capture.len() - 1);
+        assert!(matches!(
+            capture_to_jsonl(truncated, &mut out, &limits),
+            Err(CloudProtoError::BadFrameSize(11, 12))
+        ));
+        assert_eq!(out.iter().filter(|&&b| b == b'\n').count(), 2);
+    }
+
+    #[test]
+    fn walk_limits() {
+        // 1: { 1: { 1: 1 } }
+        let nested = Event::new_raw(0, hex::decode("0a040a020801").unwrap());
+        let limits = WalkLimits {
+            max_depth: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            event_to_json_with_limits(&nested, 0, None, &limits),
+            "{\"txid\":0,\"raw_event_id\":0,\"event_id\":null,\
+             \"fields\":{\"1\":[{\"message\":{\"1\":[{\"message\":{\"truncated\":\"depth\"}}]}}]},\
+             \"data\":\"0a040a020801\"}"
+        );
+
+        // 1: 1, 1: 2, 2: 3
+        let many = Event::new_raw(0, hex::decode("080108021003").unwrap());
+        let limits = WalkLimits {
+            max_fields: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            event_to_json_with_limits(&many, 0, None, &limits),
+            "{\"txid\":0,\"raw_event_id\":0,\"event_id\":null,\
+             \"fields\":{\"1\":[{\"varint\":1}],\"truncated\":\"fields\"},\
+             \"data\":\"080108021003\"}"
+        );
+
+        // 1: 1, 2: 3, truncated before field 2 gets a key
+        let two_fields = Event::new_raw(0, hex::decode("08011003").unwrap());
+        assert_eq!(
+            event_to_json_with_limits(&two_fields, 0, None, &limits),
+            "{\"txid\":0,\"raw_event_id\":0,\"event_id\":null,\
+             \"fields\":{\"1\":[{\"varint\":1}],\"truncated\":\"fields\"},\
+             \"data\":\"08011003\"}"
+        );
+
+        // Fields past the limit are not collected, even when there are many of them
+        let flood = Event::new_raw(0, [0x08, 0x01].repeat(100_000));
+        let json = event_to_json_with_limits(
+            &flood,
+            0,
+            None,
+            &WalkLimits {
+                max_fields: 2,
+                max_output_len: 64,
+                ..Default::default()
+            },
+        );
+        assert!(json.contains(
+            "\"fields\":{\"1\":[{\"varint\":1},{\"varint\":1}],\"truncated\":\"fields\"}"
+        ));
+
+        // 1: "aaaaaaaaaaaaaaaa"
+        let text = Event::new_raw(
+            0,
+            hex::decode("0a1061616161616161616161616161616161").unwrap(),
+        );
+        let limits = WalkLimits {
+            max_output_len: 20,
+            ..Default::default()
+        };
+        assert!(event_to_json_with_limits(&text, 0, None, &limits).contains(
+            "\"fields\":{\"1\":[{\"string\":\"aaaaaaaaaaaaaa\",\"truncated\":\"output_size\"}]}"
+        ));
+
+        // 1: 16 non-text bytes
+        let large = Event::new_raw(
+            0,
+            hex::decode("0a10ffffffffffffffffffffffffffffffff").unwrap(),
+        );
+        let limits = WalkLimits {
+            max_output_len: 8,
+            ..Default::default()
+        };
+        assert_eq!(
+            event_to_json_with_limits(&large, 0, None, &limits),
+            "{\"txid\":0,\"raw_event_id\":0,\"event_id\":null,\
+             \"fields\":{\"1\":[{\"bytes\":\"ff\",\"truncated\":\"output_size\"}]},\
+             \"data\":\"0a10ffff\",\"data_truncated\":true}"
+        );
+    }
+}
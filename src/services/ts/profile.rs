@@ -0,0 +1,56 @@
+use crate::services::ts::TsConnectInfo;
+use crate::services::{DEFAULT_BOOTID_HEX, DEFAULT_UNK0_HEX};
+
+/// Describes how a given version of the official sensor behaves on the wire.
+///
+/// Centralizing these observations lets sockets mimic a specific sensor version,
+/// and makes it easy to track behavior changes in new versions with a new profile.
+/// Fields for behaviors not modeled by this crate yet may be added in the future.
+#[derive(Eq, PartialEq, Debug, Clone)]
+#[non_exhaustive]
+pub struct SensorProfile {
+    /// The sensor version this profile was observed on
+    pub sensor_version: u32,
+    /// TXID of the first event sent by the client in a session
+    pub first_txid: u64,
+    /// Difference between the TXIDs of consecutive events sent by the client
+    pub txid_increment: u64,
+    /// Default value of the [`TsConnectInfo::unk0`](TsConnectInfo) field
+    pub default_unk0: [u8; 16],
+    /// Default value of the [`TsConnectInfo::bootid`](TsConnectInfo) field
+    pub default_bootid: [u8; 16],
+    /// Default value of the [`TsConnectInfo::pt`](TsConnectInfo) field
+    pub default_pt: [u8; 8],
+}
+
+impl SensorProfile {
+    /// Behavior of the Linux sensor version 13601, which this crate was developed against
+    pub fn v13601() -> Self {
+        Self {
+            sensor_version: 13601,
+            // The TS server returns large quickly incrementing TXIDs, but the client uses these
+            first_txid: 0x200,
+            txid_increment: 0x100,
+            default_unk0: hex::decode(DEFAULT_UNK0_HEX).unwrap().try_into().unwrap(),
+            default_bootid: hex::decode(DEFAULT_BOOTID_HEX).unwrap().try_into().unwrap(),
+            default_pt: [0; 8],
+        }
+    }
+
+    /// Connection info for a new agent of this sensor version (with a zero AID)
+    pub fn connect_info(&self, cid: [u8; 16]) -> TsConnectInfo {
+        TsConnectInfo {
+            cid,
+            unk0: self.default_unk0,
+            aid: [0; 16],
+            bootid: self.default_bootid,
+            pt: self.default_pt,
+        }
+    }
+}
+
+impl Default for SensorProfile {
+    fn default() -> Self {
+        Self::v13601()
+    }
+}
@@ -0,0 +1,302 @@
+use crate::WalkLimits;
+use thiserror::Error;
+
+/// Errors found while walking serialized Protobuf data without a schema
+#[derive(Error, Debug, Eq, PartialEq, Clone)]
+pub enum ProtobufError {
+    #[error("Protobuf data truncated at offset {0:#x}")]
+    Truncated(usize),
+    #[error("Invalid Protobuf varint at offset {0:#x}")]
+    InvalidVarint(usize),
+    #[error("Unsupported Protobuf wire type {0} at offset {1:#x}")]
+    InvalidWireType(u8, usize),
+    #[error("Invalid Protobuf field number 0 at offset {0:#x}")]
+    InvalidFieldNumber(usize),
+}
+
+/// The value of a single Protobuf field, as far as we can tell without a schema
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum WireValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    /// Could be a string, bytes, a nested message, or packed repeated values
+    LengthDelimited(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// The wire type of a Protobuf field, without its value
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
+pub enum WireType {
+    Varint,
+    Fixed64,
+    LengthDelimited,
+    Fixed32,
+}
+
+impl WireValue<'_> {
+    pub fn wire_type(&self) -> WireType {
+        match self {
+            WireValue::Varint(_) => WireType::Varint,
+            WireValue::Fixed64(_) => WireType::Fixed64,
+            WireValue::LengthDelimited(_) => WireType::LengthDelimited,
+            WireValue::Fixed32(_) => WireType::Fixed32,
+        }
+    }
+}
+
+/// A single field of a serialized Protobuf message
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct WireField<'a> {
+    pub number: u32,
+    pub value: WireValue<'a>,
+}
+
+/// Iterates over the top-level fields of serialized Protobuf data, without a schema.
+///
+/// Deprecated group wire types are not supported, and are reported as errors.
+/// Iteration stops after the first error.
+pub struct WireFieldIter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    failed: bool,
+}
+
+impl<'a> WireFieldIter<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            failed: false,
+        }
+    }
+
+    fn read_varint(&mut self) -> Result<u64, ProtobufError> {
+        let start = self.pos;
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .buf
+                .get(self.pos)
+                .ok_or(ProtobufError::Truncated(start))?;
+            self.pos += 1;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(ProtobufError::InvalidVarint(start))
+    }
+
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8], ProtobufError> {
+        let start = self.pos;
+        let end = start
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(ProtobufError::Truncated(start))?;
+        self.pos = end;
+        Ok(&self.buf[start..end])
+    }
+
+    fn read_field(&mut self) -> Result<WireField<'a>, ProtobufError> {
+        let field_start = self.pos;
+        let key = self.read_varint()?;
+        let number = key >> 3;
+        if number == 0 || number > u32::MAX as u64 {
+            return Err(ProtobufError::InvalidFieldNumber(field_start));
+        }
+        let value = match key & 0x7 {
+            0 => WireValue::Varint(self.read_varint()?),
+            1 => {
+                let bytes = self.read_slice(8)?.try_into().unwrap();
+                WireValue::Fixed64(u64::from_le_bytes(bytes))
+            }
+            2 => {
+                let len = self.read_varint()?;
+                WireValue::LengthDelimited(self.read_slice(len as usize)?)
+            }
+            5 => {
+                let bytes = self.read_slice(4)?.try_into().unwrap();
+                WireValue::Fixed32(u32::from_le_bytes(bytes))
+            }
+            wire_type => return Err(ProtobufError::InvalidWireType(wire_type as u8, field_start)),
+        };
+        Ok(WireField {
+            number: number as u32,
+            value,
+        })
+    }
+}
+
+impl<'a> Iterator for WireFieldIter<'a> {
+    type Item = Result<WireField<'a>, ProtobufError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.pos >= self.buf.len() {
+            return None;
+        }
+        let field = self.read_field();
+        self.failed = field.is_err();
+        Some(field)
+    }
+}
+
+/// Recursively collects the printable UTF-8 strings found in serialized Protobuf data, in order.
+///
+/// Without a schema a length-delimited field could be a string or a nested message,
+/// so fields that look like printable text are taken as strings, and other fields are walked
+/// as nested messages if possible. Data after the first wire format error is ignored.
+/// The walk stops at the depth and field count of the `limits`, and once the strings
+/// add up to `max_output_len` bytes, the last one being cut short if needed.
+pub(crate) fn extract_strings(data: &[u8], limits: &WalkLimits) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut fields_left = limits.max_fields;
+    let mut output_left = limits.max_output_len;
+    extract_strings_into(
+        data,
+        0,
+        limits,
+        &mut fields_left,
+        &mut output_left,
+        &mut strings,
+    );
+    strings
+}
+
+fn extract_strings_into(
+    data: &[u8],
+    depth: usize,
+    limits: &WalkLimits,
+    fields_left: &mut usize,
+    output_left: &mut usize,
+    strings: &mut Vec<String>,
+) {
+    for field in WireFieldIter::new(data) {
+        if *fields_left == 0 || *output_left == 0 {
+            return;
+        }
+        *fields_left -= 1;
+        let bytes = match field {
+            Ok(WireField {
+                value: WireValue::LengthDelimited(bytes),
+                ..
+            }) => bytes,
+            Ok(_) => continue,
+            Err(_) => return,
+        };
+        match std::str::from_utf8(bytes) {
+            Ok(s) if is_printable(s) => {
+                let mut shown_len = s.len().min(*output_left);
+                while !s.is_char_boundary(shown_len) {
+                    shown_len -= 1;
+                }
+                if shown_len == 0 {
+                    // Not even the first character fits
+                    *output_left = 0;
+                    return;
+                }
+                *output_left -= shown_len;
+                strings.push(s[..shown_len].to_owned());
+            }
+            _ if depth < limits.max_depth => {
+                extract_strings_into(bytes, depth + 1, limits, fields_left, output_left, strings)
+            }
+            _ => {}
+        }
+    }
+}
+
+// Whether a length-delimited field is taken as text rather than a nested message
+pub(crate) fn is_printable(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| !c.is_control() || c == '\t' || c == '\n')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn walk_simple_message() {
+        // 1: 150, 2: "testing", 3: fixed32 1, 4: fixed64 2
+        let data = hex::decode("089601120774657374696e671d01000000210200000000000000").unwrap();
+        let fields = WireFieldIter::new(&data)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                WireField {
+                    number: 1,
+                    value: WireValue::Varint(150)
+                },
+                WireField {
+                    number: 2,
+                    value: WireValue::LengthDelimited(b"testing")
+                },
+                WireField {
+                    number: 3,
+                    value: WireValue::Fixed32(1)
+                },
+                WireField {
+                    number: 4,
+                    value: WireValue::Fixed64(2)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn extract_nested_strings() {
+        // 1: "root", 2: { 1: 42, 2: "/bin/sh -c id", 3: { 1: "nested" } }, 3: b"\x00\x01"
+        let data = hex::decode(
+            "0a04726f6f74121b082a120d2f62696e2f7368202d632069641a080a066e65737465641a020001",
+        )
+        .unwrap();
+        assert_eq!(
+            extract_strings(&data, &WalkLimits::default()),
+            vec!["root", "/bin/sh -c id", "nested"]
+        );
+        let shallow = WalkLimits {
+            max_depth: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            extract_strings(&data, &shallow),
+            vec!["root", "/bin/sh -c id"]
+        );
+        let few_fields = WalkLimits {
+            max_fields: 3,
+            ..Default::default()
+        };
+        assert_eq!(extract_strings(&data, &few_fields), vec!["root"]);
+        let short_output = WalkLimits {
+            max_output_len: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            extract_strings(&data, &short_output),
+            vec!["root", "/bin/s"]
+        );
+    }
+
+    #[test]
+    fn walk_invalid_messages() {
+        let first_err = |data: &[u8]| WireFieldIter::new(data).find_map(Result::err);
+        assert_eq!(
+            first_err(&[0x12, 0x05, b'a']),
+            Some(ProtobufError::Truncated(2))
+        );
+        assert_eq!(first_err(&[0x08, 0x80]), Some(ProtobufError::Truncated(1)));
+        assert_eq!(
+            first_err(&[0x08, 0x01, 0x0B]),
+            Some(ProtobufError::InvalidWireType(3, 2))
+        );
+        assert_eq!(
+            first_err(&[0x00, 0x01]),
+            Some(ProtobufError::InvalidFieldNumber(0))
+        );
+        assert_eq!(
+            first_err(&[0x08, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+            Some(ProtobufError::InvalidVarint(1))
+        );
+    }
+}
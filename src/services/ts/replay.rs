@@ -0,0 +1,149 @@
+use crate::redaction::SensitiveId;
+use crate::services::ts::TsConnectInfo;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Why a connection attempt looks like a replayed Connect packet
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum ReplaySuspicion {
+    /// The same identity connected too many times within the detection window
+    TooManyConnects { count: usize, window: Duration },
+    /// The same identity connected from too many different source addresses within the window
+    TooManySources { count: usize, window: Duration },
+}
+
+type SuspicionHandler = Box<dyn FnMut(&TsConnectInfo, &ReplaySuspicion) + Send>;
+
+/// Detects replayed Connect packets on a TS server.
+///
+/// A real sensor reconnects with the same (CID, AID, bootid, PT) tuple, but only occasionally
+/// and from a single address. Someone replaying a captured handshake tends to reconnect
+/// at implausible rates, or from many source addresses.
+///
+/// Call [`check`](Self::check) with the [`TsConnectInfo`](TsConnectInfo) returned by
+/// [`TsEventAcceptor::listen`](super::TsEventAcceptor::listen), and drop the acceptor
+/// instead of accepting if you want to reject suspicious connections.
+pub struct ConnectReplayDetector {
+    window: Duration,
+    max_connects: usize,
+    max_sources: usize,
+    history: HashMap<[u8; 56], VecDeque<(Instant, Option<IpAddr>)>>,
+    on_suspicion: Option<SuspicionHandler>,
+}
+
+impl ConnectReplayDetector {
+    /// Flag identities that connect more than `max_connects` times,
+    /// or from more than `max_sources` distinct addresses, within `window`
+    pub fn new(window: Duration, max_connects: usize, max_sources: usize) -> Self {
+        Self {
+            window,
+            max_connects,
+            max_sources,
+            history: HashMap::new(),
+            on_suspicion: None,
+        }
+    }
+
+    /// Call `handler` every time a suspicious connection is detected, e.g. to raise an alert
+    pub fn on_suspicion(
+        &mut self,
+        handler: impl FnMut(&TsConnectInfo, &ReplaySuspicion) + Send + 'static,
+    ) {
+        self.on_suspicion = Some(Box::new(handler));
+    }
+
+    /// Record a connection attempt, and return whether it looks like a replay
+    pub fn check(
+        &mut self,
+        info: &TsConnectInfo,
+        source: Option<IpAddr>,
+    ) -> Result<(), ReplaySuspicion> {
+        let now = Instant::now();
+        let window = self.window;
+        self.history.retain(|_, attempts| {
+            while matches!(attempts.front(), Some((at, _)) if now.duration_since(*at) > window) {
+                attempts.pop_front();
+            }
+            !attempts.is_empty()
+        });
+
+        let attempts = self.history.entry(Self::identity_key(info)).or_default();
+        attempts.push_back((now, source));
+
+        let mut sources: Vec<_> = attempts.iter().filter_map(|(_, src)| *src).collect();
+        sources.sort();
+        sources.dedup();
+        let suspicion = if attempts.len() > self.max_connects {
+            ReplaySuspicion::TooManyConnects {
+                count: attempts.len(),
+                window,
+            }
+        } else if sources.len() > self.max_sources {
+            ReplaySuspicion::TooManySources {
+                count: sources.len(),
+                window,
+            }
+        } else {
+            return Ok(());
+        };
+
+        warn!(
+            cid = %SensitiveId(&info.cid),
+            aid = %SensitiveId(&info.aid),
+            "Possible replayed TS Connect: {:?}",
+            suspicion
+        );
+        if let Some(handler) = &mut self.on_suspicion {
+            handler(info, &suspicion);
+        }
+        Err(suspicion)
+    }
+
+    fn identity_key(info: &TsConnectInfo) -> [u8; 56] {
+        let mut key = [0; 56];
+        key[..16].copy_from_slice(&info.cid);
+        key[16..32].copy_from_slice(&info.aid);
+        key[32..48].copy_from_slice(&info.bootid);
+        key[48..].copy_from_slice(&info.pt);
+        key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test(start_paused = true)]
+    async fn detect_replays() {
+        let mut detector = ConnectReplayDetector::new(Duration::from_secs(60), 2, 1);
+        let info = TsConnectInfo::new_simple([1; 16]);
+        let other = TsConnectInfo::new_simple([2; 16]);
+        let addr1 = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let addr2 = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+
+        assert_eq!(detector.check(&info, addr1), Ok(()));
+        assert_eq!(detector.check(&other, addr2), Ok(()));
+        assert_eq!(
+            detector.check(&info, addr2),
+            Err(ReplaySuspicion::TooManySources {
+                count: 2,
+                window: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(
+            detector.check(&info, addr1),
+            Err(ReplaySuspicion::TooManyConnects {
+                count: 3,
+                window: Duration::from_secs(60)
+            })
+        );
+
+        // Old attempts are forgotten after the window
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(detector.check(&info, addr2), Ok(()));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::services::ts::protobuf::{is_printable, WireField, WireFieldIter, WireValue};
+use crate::services::ts::Event;
+use crate::WalkLimits;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// A tentative Protobuf schema, inferred by walking many samples of the same message.
+///
+/// Without the original `.proto` the wire format is ambiguous, so this is only a draft:
+/// varints could be signed or unsigned (or enums), and a length-delimited field that happens
+/// to always contain printable text in the samples is assumed to be a string.
+/// The more samples, the better the guess.
+#[derive(Debug, Clone, Default)]
+pub struct InferredSchema {
+    /// Number of samples that were successfully walked
+    pub samples: usize,
+    /// Number of samples that were not valid Protobuf, and were ignored
+    pub invalid_samples: usize,
+    /// Observed fields, by field number
+    pub fields: BTreeMap<u32, InferredField>,
+}
+
+/// What we learned about a single field across all samples
+#[derive(Debug, Clone, Default)]
+pub struct InferredField {
+    /// Number of samples in which the field was present
+    pub presence: usize,
+    /// Whether the field was seen more than once in a single sample
+    pub repeated: bool,
+    /// Range of observed varint values
+    pub varint_range: Option<(u64, u64)>,
+    /// Number of fixed32 values observed
+    pub fixed32_count: usize,
+    /// Number of fixed64 values observed
+    pub fixed64_count: usize,
+    /// Range of observed lengths for length-delimited values
+    pub len_range: Option<(usize, usize)>,
+    /// Number of length-delimited values that looked like printable text
+    pub string_count: usize,
+    /// Number of length-delimited values
+    pub len_delimited_count: usize,
+    /// Schema inferred from the length-delimited values that parsed as nested messages
+    pub nested: Option<Box<InferredSchema>>,
+}
+
+fn widen<T: Ord + Copy>(range: &mut Option<(T, T)>, value: T) {
+    *range = Some(match *range {
+        None => (value, value),
+        Some((min, max)) => (min.min(value), max.max(value)),
+    });
+}
+
+impl InferredSchema {
+    /// Infer a schema from serialized Protobuf samples of the same message type
+    pub fn from_samples<'a>(samples: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        Self::from_samples_with_limits(samples, &WalkLimits::default())
+    }
+
+    /// Same as [`from_samples`](Self::from_samples), with explicit limits on the walk of each sample.
+    /// Nested messages past the depth limit are not inferred, and fields past the field limit are ignored.
+    pub fn from_samples_with_limits<'a>(
+        samples: impl IntoIterator<Item = &'a [u8]>,
+        limits: &WalkLimits,
+    ) -> Self {
+        let mut schema = Self::default();
+        for sample in samples {
+            let mut fields_left = limits.max_fields;
+            schema.add_sample(sample, 0, limits, &mut fields_left);
+        }
+        schema
+    }
+
+    /// Infer the schema of the events with the given raw event ID, ignoring all other events
+    pub fn from_events<'a>(raw_event_id: u32, events: impl IntoIterator<Item = &'a Event>) -> Self {
+        Self::from_samples(
+            events
+                .into_iter()
+                .filter(|ev| ev.raw_event_id == raw_event_id)
+                .map(|ev| ev.data.as_slice()),
+        )
+    }
+
+    fn add_sample(
+        &mut self,
+        sample: &[u8],
+        depth: usize,
+        limits: &WalkLimits,
+        fields_left: &mut usize,
+    ) {
+        if !WireFieldIter::new(sample).all(|f| f.is_ok()) {
+            self.invalid_samples += 1;
+            return;
+        }
+        self.samples += 1;
+
+        let mut seen_in_sample = BTreeMap::new();
+        for WireField { number, value } in WireFieldIter::new(sample).flatten() {
+            if *fields_left == 0 {
+                return;
+            }
+            *fields_left -= 1;
+            let count = seen_in_sample.entry(number).or_insert(0usize);
+            *count += 1;
+            let field = self.fields.entry(number).or_default();
+            if *count == 1 {
+                field.presence += 1;
+            } else {
+                field.repeated = true;
+            }
+            match value {
+                WireValue::Varint(v) => widen(&mut field.varint_range, v),
+                WireValue::Fixed32(_) => field.fixed32_count += 1,
+                WireValue::Fixed64(_) => field.fixed64_count += 1,
+                WireValue::LengthDelimited(bytes) => {
+                    field.len_delimited_count += 1;
+                    widen(&mut field.len_range, bytes.len());
+                    let is_text = std::str::from_utf8(bytes).map_or(false, is_printable);
+                    if is_text {
+                        field.string_count += 1;
+                    } else if depth < limits.max_depth && !bytes.is_empty() {
+                        let nested = field.nested.get_or_insert_with(Default::default);
+                        nested.add_sample(bytes, depth + 1, limits, fields_left);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Emit the inferred schema as a draft `.proto` message definition, with observations as comments
+    pub fn to_proto(&self, message_name: &str) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "// Inferred from {} samples ({} invalid samples ignored)",
+            self.samples, self.invalid_samples
+        )
+        .unwrap();
+        self.write_message(&mut out, message_name, 0);
+        out
+    }
+
+    fn write_message(&self, out: &mut String, name: &str, indent: usize) {
+        let pad = "  ".repeat(indent);
+        writeln!(out, "{}message {} {{", pad, name).unwrap();
+        for (number, field) in &self.fields {
+            let (ty, nested) = field.proto_type(*number);
+            let label = if field.repeated {
+                "repeated"
+            } else {
+                "optional"
+            };
+            writeln!(
+                out,
+                "{}  {} {} field_{} = {}; // {}",
+                pad,
+                label,
+                ty,
+                number,
+                number,
+                field.observations(self.samples)
+            )
+            .unwrap();
+            if let Some(nested) = nested {
+                nested.write_message(out, &ty, indent + 1);
+            }
+        }
+        writeln!(out, "{}}}", pad).unwrap();
+    }
+}
+
+impl InferredField {
+    fn wire_type_count(&self) -> usize {
+        [
+            self.varint_range.is_some(),
+            self.fixed32_count > 0,
+            self.fixed64_count > 0,
+            self.len_delimited_count > 0,
+        ]
+        .iter()
+        .filter(|&&seen| seen)
+        .count()
+    }
+
+    fn proto_type(&self, number: u32) -> (String, Option<&InferredSchema>) {
+        if self.wire_type_count() > 1 {
+            return ("bytes".into(), None);
+        }
+        if let Some((min, max)) = self.varint_range {
+            let ty = if max <= 1 && min <= 1 {
+                "bool"
+            } else if max > i64::MAX as u64 {
+                "int64"
+            } else {
+                "uint64"
+            };
+            return (ty.into(), None);
+        }
+        if self.fixed32_count > 0 {
+            return ("fixed32".into(), None);
+        }
+        if self.fixed64_count > 0 {
+            return ("fixed64".into(), None);
+        }
+        if self.string_count == self.len_delimited_count {
+            return ("string".into(), None);
+        }
+        match &self.nested {
+            Some(nested)
+                if nested.invalid_samples == 0
+                    && nested.samples + self.string_count == self.len_delimited_count =>
+            {
+                (format!("Field{}", number), Some(nested))
+            }
+            _ => ("bytes".into(), None),
+        }
+    }
+
+    fn observations(&self, samples: usize) -> String {
+        let mut notes = format!("present in {}/{} samples", self.presence, samples);
+        if self.wire_type_count() > 1 {
+            notes += ", conflicting wire types";
+        }
+        if let Some((min, max)) = self.varint_range {
+            write!(notes, ", values {}..={}", min, max).unwrap();
+        }
+        if let Some((min, max)) = self.len_range {
+            write!(notes, ", lengths {}..={}", min, max).unwrap();
+        }
+        notes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::ts::EventId;
+
+    #[test]
+    fn infer_simple_schema() {
+        let events = [
+            // 1: 1, 2: "foo", 3: { 1: 300 }
+            Event::new(
+                EventId::OsVersionInfo,
+                hex::decode("08011203666f6f1a0308ac02").unwrap(),
+            ),
+            // 1: 0, 2: "barbaz", 2: "qux"
+            Event::new(
+                EventId::OsVersionInfo,
+                hex::decode("0800120662617262617a1203717578").unwrap(),
+            ),
+            Event::new(EventId::AgentOnline, hex::decode("ffff").unwrap()),
+        ];
+        let schema = InferredSchema::from_events(EventId::OsVersionInfo as u32, &events);
+        assert_eq!(schema.samples, 2);
+        assert_eq!(schema.invalid_samples, 0);
+
+        let proto = schema.to_proto("OsVersionInfo");
+        assert_eq!(
+            proto,
+            "// Inferred from 2 samples (0 invalid samples ignored)\n\
+             message OsVersionInfo {\n  \
+               optional bool field_1 = 1; // present in 2/2 samples, values 0..=1\n  \
+               repeated string field_2 = 2; // present in 2/2 samples, lengths 3..=6\n  \
+               optional Field3 field_3 = 3; // present in 1/2 samples, lengths 3..=3\n  \
+               message Field3 {\n    \
+                 optional uint64 field_1 = 1; // present in 1/1 samples, values 300..=300\n  \
+               }\n\
+             }\n"
+        );
+    }
+}
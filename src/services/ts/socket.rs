@@ -1,19 +1,111 @@
-use crate::framing::{CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoVersion};
-use crate::services::ts::event::EVT_HDR_LEN;
-use crate::services::ts::{AgentIdStatus, Event, TsConnectInfo, TsPacketKind};
+use crate::anomaly::{AnomalyKind, AnomalyReporter};
+use crate::framing::{
+    CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoVersion, FrameTimestamp,
+    MemoryReport, WriteQueueDepth,
+};
+use crate::redaction::{PayloadDump, SensitiveId, SensitivePayload};
+use crate::services::ts::event::packet_event_id;
+use crate::services::ts::{
+    AgentIdStatus, Event, SensorProfile, TsConnectInfo, TsConnectionOrigin, TsError, TsPacketKind,
+    TxidAnomalyDetector, WelcomeSequence,
+};
 use crate::services::CloudProtoMagic;
+use crate::Anomaly;
+use bytes::Bytes;
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
-use std::io::Cursor;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
 use std::pin::Pin;
-use std::task::{ready, Context, Poll};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll, Waker};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
 use tracing::{debug, error, trace, warn};
 
-const HDR_TXID_SIZE: usize = std::mem::size_of::<u64>();
-// Values observed from the official client.
-// The TS server returns large quickly incrementing TXIDs, but these values here are fine.
-const FIRST_TXID: u64 = 0x200;
-const TXID_INCREMENT: u64 = 0x100;
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Handler called with malformed Event packets and the reason they could not be parsed
+pub type InvalidEventHandler = Box<dyn FnMut(&CloudProtoError, &CloudProtoPacket) + Send>;
+
+/// What a [`TsEventSocket`](TsEventSocket) should do when it receives a malformed Event packet
+pub enum InvalidEventPolicy {
+    /// Return the error from the [`Stream`](Stream). This is the default.
+    ///
+    /// Most callers drop the socket on the first error, so a single bad event ends the session.
+    Error,
+    /// Silently skip the invalid event, only counting it
+    Skip,
+    /// Skip the invalid event after passing it to this handler, along with the parsing error
+    Callback(InvalidEventHandler),
+}
+
+impl std::fmt::Debug for InvalidEventPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => f.write_str("Error"),
+            Self::Skip => f.write_str("Skip"),
+            Self::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+/// Unexpected packets received on a [`TsEventSocket`](TsEventSocket) for a single packet kind
+#[derive(Debug, Clone, Default)]
+pub struct UnexpectedPackets {
+    /// Total number of packets of this kind received
+    pub seen: usize,
+    /// Full copies of the first packets of this kind, up to the configured capture limit
+    pub captured: Vec<CloudProtoPacket>,
+}
+
+/// Lifecycle of a [`TsEventSocket`](TsEventSocket).
+///
+/// There is no state before the connection is established,
+/// since a socket can only be created by a successful connect or accept.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TsSocketState {
+    /// Events can be sent and received
+    Established,
+    /// The peer closed the connection, or the socket was closed locally.
+    /// The [`Stream`](Stream) ends, and sending returns an [`AlreadyClosed`](TsError::AlreadyClosed) error.
+    Closed,
+}
+
+/// When an event passed through a [`TsEventSocket`](TsEventSocket).
+///
+/// For received events, this is the time their frame was read, see
+/// [`CloudProtoSocket::last_frame_received_at`](CloudProtoSocket::last_frame_received_at).
+pub type EventTimestamp = FrameTimestamp;
+
+/// How long each phase of a client's TS handshake took, see [`TsEventSocket::handshake_timings`].
+///
+/// Durations are measured from `started_at`, when [`connect`](TsEventSocket::connect) was called.
+/// Establishing the transport (TCP, TLS) happens before that, so it should be timed by the caller.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HandshakeTimings {
+    pub started_at: EventTimestamp,
+    /// The Connect packet was written to the transport
+    pub connect_sent: Duration,
+    /// The server's ConnectionEstablished reply was received
+    pub established: Duration,
+}
+
+impl HandshakeTimings {
+    /// Time spent waiting for the server to reply, which includes the network round trip
+    pub fn server_reply(&self) -> Duration {
+        self.established - self.connect_sent
+    }
+}
+
+/// An [`Event`](Event) along with its txid and the time its packet was decoded
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReceivedEvent {
+    pub event: Event,
+    pub txid: u64,
+    pub received_at: EventTimestamp,
+}
 
 /// Async socket used to stream [`Event`](Event)s with the TS service
 ///
@@ -28,106 +120,586 @@ const TXID_INCREMENT: u64 = 0x100;
 /// saved as a 16 byte binary blob, right after the UTF-16 literal "CU".
 pub struct TsEventSocket<IO: AsyncRead + AsyncWrite> {
     io: CloudProtoSocket<IO>,
+    connection_id: u64,
+    connect_info: TsConnectInfo,
+    state: TsSocketState,
     next_txid: u64,
+    txid_increment: u64,
+    last_received_at: Instant,
+    idle_timeout: Option<(Duration, Pin<Box<Sleep>>)>,
+    unexpected_packets: BTreeMap<u8, UnexpectedPackets>,
+    max_captured_per_kind: usize,
+    raw_packets_tx: Option<mpsc::UnboundedSender<CloudProtoPacket>>,
+    kind_routes: BTreeMap<u8, mpsc::UnboundedSender<CloudProtoPacket>>,
+    invalid_event_policy: InvalidEventPolicy,
+    invalid_event_count: usize,
+    anomalies: AnomalyReporter,
+    // Only checked while anomalies are reported
+    received_txids: TxidAnomalyDetector,
 
     unacked_txid: Option<u64>,
     unacked_event: Option<Event>,
+    last_received_event: Option<(u64, EventTimestamp)>,
+    last_sent_event: Option<(u64, EventTimestamp)>,
+    handshake_timings: Option<HandshakeTimings>,
+
+    // Only tracked when an ACK window is configured, see set_max_unacked_events()
+    max_unacked_events: Option<usize>,
+    inflight_txids: VecDeque<u64>,
+    ack_window_waker: Option<Waker>,
+}
+
+/// The Connect packet a client sends to open a session
+pub(crate) fn connect_packet(info: &TsConnectInfo) -> CloudProtoPacket {
+    let mut payload = Vec::with_capacity(4 * 16 + 8);
+    payload.extend_from_slice(&info.cid);
+    payload.extend_from_slice(&info.unk0);
+    payload.extend_from_slice(&info.aid);
+    payload.extend_from_slice(&info.bootid);
+    payload.extend_from_slice(&info.pt);
+    CloudProtoPacket {
+        magic: CloudProtoMagic::TS,
+        kind: TsPacketKind::Connect.into(),
+        version: CloudProtoVersion::Connect,
+        payload: payload.into(),
+    }
+}
+
+/// The ACK for the event with this txid
+pub(crate) fn ack_packet(txid: u64) -> CloudProtoPacket {
+    CloudProtoPacket {
+        magic: CloudProtoMagic::TS,
+        kind: TsPacketKind::Ack.into(),
+        version: CloudProtoVersion::Normal,
+        payload: txid.to_be_bytes().to_vec().into(),
+    }
+}
+
+/// Checks the server's reply to a Connect packet, and updates the AID it assigned us
+pub(crate) fn check_connect_reply(
+    info: &mut TsConnectInfo,
+    reply: &CloudProtoPacket,
+) -> Result<(), TsError> {
+    // Log the connection packet for debugging, since we don't otherwise return the payload in errors
+    trace!(
+        "Received TS connect reply: {}",
+        PayloadDump::new(&reply.payload)
+    );
+
+    if reply.magic != CloudProtoMagic::TS {
+        return Err(CloudProtoError::BadMagic(reply.magic, CloudProtoMagic::TS).into());
+    }
+    if reply.kind != TsPacketKind::ConnectionEstablished {
+        error!(
+            "Bad TS connect reply kind: {:X?}, payload: {}",
+            reply,
+            SensitivePayload(&reply.payload)
+        );
+        return Err(CloudProtoError::WrongConnectionPacketKind(
+            reply.kind,
+            TsPacketKind::ConnectionEstablished.into(),
+        )
+        .into());
+    }
+    if reply.version != CloudProtoVersion::Normal {
+        error!(
+            "Bad TS connect reply version: {:X?}, payload: {}",
+            reply,
+            SensitivePayload(&reply.payload)
+        );
+        return Err(CloudProtoError::BadVersion(reply.version, CloudProtoVersion::Normal).into());
+    }
+
+    if reply.payload.len() != 17 {
+        warn!("TsEventSocket connect reply has unexpected size, continuing anyways")
+    } else if reply.payload[0] == AgentIdStatus::Unchanged as u8 {
+        debug!(
+            received_aid = %SensitiveId(&reply.payload[1..]),
+            "TS socket connected, AgentID unchanged",
+        );
+        if info.aid[..] != reply.payload[1..] {
+            warn!("TS server says to keep our AgentID, but replied with a different one!");
+        }
+        info.aid.copy_from_slice(&reply.payload[1..]);
+    } else if reply.payload[0] == AgentIdStatus::Changed as u8 {
+        debug!(
+            received_aid = %SensitiveId(&reply.payload[1..]),
+            "TS socket connected, AgentID has changed",
+        );
+        if info.aid[..] == reply.payload[1..] {
+            warn!("TS server says to change our AgentID, but replied with the same one!");
+        }
+        info.aid.copy_from_slice(&reply.payload[1..]);
+    } else {
+        warn!(
+            "Unexpected value from TS server when checking whether the AgentID changed: {:#x}",
+            reply.payload[0]
+        )
+    }
+    Ok(())
 }
 
 impl<IO> TsEventSocket<IO>
 where
     IO: AsyncRead + AsyncWrite,
 {
-    pub(crate) fn new(io: CloudProtoSocket<IO>) -> Self {
+    pub(crate) fn new(
+        mut io: CloudProtoSocket<IO>,
+        connect_info: TsConnectInfo,
+        profile: &SensorProfile,
+    ) -> Self {
+        io.set_dump_event_id(Some(packet_event_id));
         Self {
             io,
-            next_txid: FIRST_TXID,
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            connect_info,
+            state: TsSocketState::Established,
+            next_txid: profile.first_txid,
+            txid_increment: profile.txid_increment,
+            last_received_at: Instant::now(),
+            idle_timeout: None,
+            unexpected_packets: BTreeMap::new(),
+            max_captured_per_kind: 0,
+            raw_packets_tx: None,
+            kind_routes: BTreeMap::new(),
+            invalid_event_policy: InvalidEventPolicy::Error,
+            invalid_event_count: 0,
+            anomalies: AnomalyReporter::default(),
+            received_txids: TxidAnomalyDetector::new(None),
             unacked_txid: None,
             unacked_event: None,
+            last_received_event: None,
+            last_sent_event: None,
+            handshake_timings: None,
+            max_unacked_events: None,
+            inflight_txids: VecDeque::new(),
+            ack_window_waker: None,
         }
     }
 
-    pub async fn connect(
+    /// Connect to a TS server, behaving like the default [`SensorProfile`](SensorProfile)
+    pub async fn connect(io: CloudProtoSocket<IO>, info: TsConnectInfo) -> Result<Self, TsError> {
+        Self::connect_with_profile(io, info, &SensorProfile::default()).await
+    }
+
+    /// Connect to a TS server, mimicking the wire behavior described by `profile`
+    pub async fn connect_with_profile(
         mut io: CloudProtoSocket<IO>,
-        info: TsConnectInfo,
-    ) -> Result<Self, CloudProtoError> {
-        let mut payload = Vec::with_capacity(4 * 16 + 8);
-        payload.extend_from_slice(&info.cid);
-        payload.extend_from_slice(&info.unk0);
-        payload.extend_from_slice(&info.aid);
-        payload.extend_from_slice(&info.bootid);
-        payload.extend_from_slice(&info.pt);
-        let pkt = CloudProtoPacket {
-            magic: CloudProtoMagic::TS,
-            kind: TsPacketKind::Connect.into(),
-            version: CloudProtoVersion::Connect,
-            payload,
-        };
-        io.send(pkt).await?;
+        mut info: TsConnectInfo,
+        profile: &SensorProfile,
+    ) -> Result<Self, TsError> {
+        let started_at = EventTimestamp::now();
+        io.send(connect_packet(&info)).await?;
+        let connect_sent = started_at.monotonic.elapsed();
 
         let reply = match io.next().await {
             Some(pkt) => pkt?,
             None => {
-                return Err(CloudProtoError::ClosedByPeer(
-                    "TS server closed connection".into(),
-                ))
+                let origin = TsConnectionOrigin {
+                    peer_addr: io.peer_addr(),
+                    cid: Some(info.cid),
+                    aid: Some(info.aid),
+                    ..Default::default()
+                };
+                return Err(TsError::closed_by_peer(
+                    "TS server closed connection",
+                    origin,
+                ));
             }
         };
-        // Log the connection packet for debugging, since we don't otherwise return the payload in errors
-        trace!("Received TS connect reply: {}", hex::encode(&reply.payload));
+        let established = started_at.monotonic.elapsed();
+        check_connect_reply(&mut info, &reply)?;
 
-        if reply.magic != CloudProtoMagic::TS {
-            return Err(CloudProtoError::BadMagic(reply.magic, CloudProtoMagic::TS));
+        debug!(
+            "TS handshake done in {:?}, server replied after {:?}",
+            established,
+            established - connect_sent
+        );
+        let mut sock = Self::new(io, info, profile);
+        sock.handshake_timings = Some(HandshakeTimings {
+            started_at,
+            connect_sent,
+            established,
+        });
+        Ok(sock)
+    }
+
+    /// A process-unique identifier for this connection, also attached to the socket's log messages.
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// Identifies this connection in errors, see [`TsConnectionOrigin`](TsConnectionOrigin)
+    pub fn origin(&self) -> TsConnectionOrigin {
+        TsConnectionOrigin {
+            connection_id: Some(self.connection_id),
+            peer_addr: self.io.peer_addr(),
+            cid: Some(self.connect_info.cid),
+            aid: Some(self.connect_info.aid),
         }
-        if reply.kind != TsPacketKind::ConnectionEstablished {
-            error!(
-                "Bad TS connect reply kind: {:X?}, payload: {}",
-                reply,
-                hex::encode(&reply.payload)
-            );
-            return Err(CloudProtoError::WrongConnectionPacketKind(
-                reply.kind,
-                TsPacketKind::ConnectionEstablished.into(),
-            ));
+    }
+
+    /// The identity of the client on this connection.
+    ///
+    /// The `aid` field is the Agent ID negotiated during connection, which may differ
+    /// from the one initially sent by the client if the server assigned a new one.
+    pub fn connect_info(&self) -> &TsConnectInfo {
+        &self.connect_info
+    }
+
+    /// Timing breakdown of the handshake, for sockets created with [`connect`](Self::connect).
+    /// This is `None` for sockets returned by a [`TsEventAcceptor`](super::TsEventAcceptor).
+    pub fn handshake_timings(&self) -> Option<HandshakeTimings> {
+        self.handshake_timings
+    }
+
+    /// Memory held by this socket, including its [`CloudProtoSocket`](CloudProtoSocket) buffers
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            inflight_events: self.inflight_txids.len(),
+            captured_packet_bytes: self
+                .unexpected_packets
+                .values()
+                .flat_map(|unexpected| &unexpected.captured)
+                .map(|pkt| pkt.payload.len())
+                .sum(),
+            ..self.io.memory_report()
         }
-        if reply.version != CloudProtoVersion::Normal {
-            error!(
-                "Bad TS connect reply version: {:X?}, payload: {}",
-                reply,
-                hex::encode(&reply.payload)
-            );
-            return Err(CloudProtoError::BadVersion(
-                reply.version,
-                CloudProtoVersion::Normal,
-            ));
+    }
+
+    /// How much data is waiting in the write buffer, including ACKs.
+    /// See [`CloudProtoSocket::write_queue_depth`](CloudProtoSocket::write_queue_depth).
+    pub fn write_queue_depth(&self) -> WriteQueueDepth {
+        self.io.write_queue_depth()
+    }
+
+    /// Writes buffered data until at most `level` bytes are left in the write buffer.
+    /// See [`CloudProtoSocket::wait_for_drain`](CloudProtoSocket::wait_for_drain).
+    pub async fn wait_for_drain(&mut self, level: usize) -> Result<(), TsError> {
+        Ok(self.io.wait_for_drain(level).await?)
+    }
+
+    /// Whether this connection is still established
+    pub fn state(&self) -> TsSocketState {
+        self.state
+    }
+
+    /// The txid that will be used for the next sent event
+    pub fn next_txid(&self) -> u64 {
+        self.next_txid
+    }
+
+    /// Override the txid of the next sent event. Following events continue incrementing from there.
+    ///
+    /// This is useful to replay historical events to a server that correlates by txid.
+    /// Set it before each `start_send` to give every event an explicit txid.
+    pub fn set_next_txid(&mut self, txid: u64) {
+        self.next_txid = txid;
+    }
+
+    /// The txid and decode time of the last event returned by the [`Stream`](Stream).
+    ///
+    /// The timestamp is taken as soon as the packet is decoded, before ACKing it,
+    /// so it is not skewed by the time the event spends buffered or by slow consumers.
+    pub fn last_received_event(&self) -> Option<(u64, EventTimestamp)> {
+        self.last_received_event
+    }
+
+    /// The txid and send time of the last event passed to the [`Sink`](Sink)
+    pub fn last_sent_event(&self) -> Option<(u64, EventTimestamp)> {
+        self.last_sent_event
+    }
+
+    /// Receive the next event along with its txid and receive timestamp
+    pub async fn next_received(&mut self) -> Option<Result<ReceivedEvent, TsError>>
+    where
+        IO: Unpin,
+    {
+        let event = match self.next().await? {
+            Ok(event) => event,
+            Err(e) => return Some(Err(e)),
+        };
+        let (txid, received_at) = self
+            .last_received_event
+            .expect("Received event without a timestamp");
+        Some(Ok(ReceivedEvent {
+            event,
+            txid,
+            received_at,
+        }))
+    }
+
+    /// Time elapsed since any packet (including ACKs) was last received from the peer.
+    pub fn idle_duration(&self) -> Duration {
+        self.last_received_at.elapsed()
+    }
+
+    /// Detect half-open connections, where the transport is still alive but the peer stopped talking.
+    ///
+    /// If nothing is received for `timeout`, the [`Stream`](Stream) returns a
+    /// [`TsError::IdleTimeout`](TsError::IdleTimeout) error,
+    /// and you should probably drop the connection.
+    /// The timer only runs while the receive side is being polled.
+    ///
+    /// This uses `tokio::time`, so it can be tested with `tokio::time::pause()`.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout.map(|timeout| {
+            let deadline = self.last_received_at + timeout;
+            (timeout, Box::pin(tokio::time::sleep_until(deadline)))
+        });
+    }
+
+    /// Keep full copies of up to `max_per_kind` unexpected packets of each kind.
+    ///
+    /// Packets of unknown kinds are rare, so it can be worth capturing them for later analysis
+    /// instead of losing them to log truncation. Captures are disabled by default,
+    /// but unexpected packets are always counted. See [`unexpected_packets`](Self::unexpected_packets).
+    pub fn set_unexpected_packet_capture(&mut self, max_per_kind: usize) {
+        self.max_captured_per_kind = max_per_kind;
+    }
+
+    /// Summary of the unexpected packets received so far, by packet kind
+    pub fn unexpected_packets(&self) -> &BTreeMap<u8, UnexpectedPackets> {
+        &self.unexpected_packets
+    }
+
+    /// Returns the unexpected packets received so far, and resets the counters and captures
+    pub fn take_unexpected_packets(&mut self) -> BTreeMap<u8, UnexpectedPackets> {
+        std::mem::take(&mut self.unexpected_packets)
+    }
+
+    /// Send a raw CloudProto packet on this connection, bypassing the event layer.
+    ///
+    /// This is an escape hatch to experiment with undocumented packet kinds.
+    /// The packet is sent as-is, so it does not consume a txid.
+    pub async fn send_raw(&mut self, pkt: CloudProtoPacket) -> Result<(), TsError>
+    where
+        IO: Unpin,
+    {
+        if self.state == TsSocketState::Closed {
+            return Err(TsError::AlreadyClosed);
         }
+        Ok(self.io.send(pkt).await?)
+    }
 
-        if reply.payload.len() != 17 {
-            warn!("TsEventSocket connect reply has unexpected size, continuing anyways")
-        } else if reply.payload[0] == AgentIdStatus::Unchanged as u8 {
-            debug!(
-                received_aid = hex::encode(&reply.payload[1..]),
-                "TS socket connected, AgentID unchanged",
-            );
-            if info.aid[..] != reply.payload {
-                warn!("TS server says to keep our AgentID, but replied with a different one!");
+    /// Send the events of a [`WelcomeSequence`](WelcomeSequence) to the connected sensor,
+    /// waiting for the delay of each step before sending it.
+    ///
+    /// Servers typically call this when they receive the sensor's `AgentOnline` event.
+    pub async fn send_welcome(&mut self, sequence: &WelcomeSequence) -> Result<(), TsError>
+    where
+        IO: Unpin,
+    {
+        for (delay, ev) in sequence.render(&self.connect_info) {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
             }
-        } else if reply.payload[0] == AgentIdStatus::Changed as u8 {
-            debug!(
-                received_aid = hex::encode(&reply.payload[1..]),
-                "TS socket connected, AgentID has changed",
-            );
-            if info.aid[..] == reply.payload {
-                warn!("TS server says to change our AgentID, but replied with the same one!");
-            }
-        } else {
-            warn!(
-                "Unexpected value from TS server when checking whether the AgentID changed: {:#x}",
-                reply.payload[0]
-            )
+            self.send(ev).await?;
         }
+        Ok(())
+    }
 
-        Ok(Self::new(io))
+    /// Send a packet of the given kind within this session, with the usual TS header.
+    ///
+    /// This is meant to experiment with the unnamed packet kinds (values above 4),
+    /// while keeping the event layer running. Replies can be received with
+    /// [`route_packet_kind`](Self::route_packet_kind). The packet does not consume a txid.
+    ///
+    /// Named kinds are handled by the socket itself and return a
+    /// [`ReservedPacketKind`](TsError::ReservedPacketKind) error,
+    /// see [`send_raw`](Self::send_raw) to send them anyway.
+    pub async fn send_packet_kind(
+        &mut self,
+        kind: TsPacketKind,
+        payload: impl Into<Bytes>,
+    ) -> Result<(), TsError>
+    where
+        IO: Unpin,
+    {
+        let kind = u8::from(kind);
+        if kind <= TsPacketKind::Ack.into() {
+            return Err(TsError::ReservedPacketKind(kind));
+        }
+        self.send_raw(CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind,
+            version: CloudProtoVersion::Normal,
+            payload: payload.into(),
+        })
+        .await
+    }
+
+    /// **For fuzzing only**: send an event packet after letting `corrupt` modify it.
+    ///
+    /// The packet is built exactly like for a normal event, using and advancing the next txid,
+    /// so the rest of the session stays consistent. `corrupt` can then truncate the payload,
+    /// change the txid, kind or version, etc. to exercise a server's error handling.
+    /// See also [`send_raw`](Self::send_raw) to send arbitrary packets.
+    pub async fn send_malformed(
+        &mut self,
+        ev: Event,
+        corrupt: impl FnOnce(&mut CloudProtoPacket),
+    ) -> Result<(), TsError>
+    where
+        IO: Unpin,
+    {
+        let mut pkt = ev.into_packet(self.next_txid);
+        self.next_txid = self.next_txid.wrapping_add(self.txid_increment);
+        corrupt(&mut pkt);
+        warn!(
+            conn_id = self.connection_id,
+            "Sending deliberately malformed packet of kind {:#x}", pkt.kind
+        );
+        self.send_raw(pkt).await
+    }
+
+    /// Route received packets that are neither Events nor ACKs to the returned receiver,
+    /// instead of recording them as [`unexpected_packets`](Self::unexpected_packets).
+    ///
+    /// Packets are only routed while the [`Stream`](Stream) is polled.
+    /// If the receiver is dropped, packets are recorded as unexpected again.
+    pub fn route_raw_packets(&mut self) -> mpsc::UnboundedReceiver<CloudProtoPacket> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.raw_packets_tx = Some(tx);
+        rx
+    }
+
+    /// Route received packets of this kind to the returned receiver, ahead of
+    /// [`route_raw_packets`](Self::route_raw_packets) and the unexpected packet counters.
+    ///
+    /// Events and ACKs are always handled by the socket, so their kinds return a
+    /// [`ReservedPacketKind`](TsError::ReservedPacketKind) error.
+    /// Packets are only routed while the [`Stream`](Stream) is polled.
+    /// If the receiver is dropped, packets of this kind are handled like other unexpected packets again.
+    pub fn route_packet_kind(
+        &mut self,
+        kind: TsPacketKind,
+    ) -> Result<mpsc::UnboundedReceiver<CloudProtoPacket>, TsError> {
+        let kind = u8::from(kind);
+        if kind == TsPacketKind::Event || kind == TsPacketKind::Ack {
+            return Err(TsError::ReservedPacketKind(kind));
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.kind_routes.insert(kind, tx);
+        Ok(rx)
+    }
+
+    fn record_unexpected_packet(&mut self, pkt: CloudProtoPacket) {
+        let pkt = match self.kind_routes.get(&pkt.kind) {
+            Some(tx) => match tx.send(pkt) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(pkt)) => {
+                    self.kind_routes.remove(&pkt.kind);
+                    pkt
+                }
+            },
+            None => pkt,
+        };
+        let pkt = match &self.raw_packets_tx {
+            Some(tx) => match tx.send(pkt) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(pkt)) => {
+                    self.raw_packets_tx = None;
+                    pkt
+                }
+            },
+            None => pkt,
+        };
+        // Hoping this was a non-essential packet and continuing happily...
+        warn!(
+            conn_id = self.connection_id,
+            "Received unexpected CloudProto packet kind: {:#x}", pkt.kind
+        );
+        self.anomalies.report(AnomalyKind::UnknownPacketKind {
+            magic: pkt.magic,
+            kind: pkt.kind,
+        });
+        trace!(
+            "Unexpected packet payload: {}",
+            PayloadDump::new(&pkt.payload)
+        );
+        let entry = self.unexpected_packets.entry(pkt.kind).or_default();
+        entry.seen += 1;
+        if entry.captured.len() < self.max_captured_per_kind {
+            entry.captured.push(pkt);
+        }
+    }
+
+    /// Choose how malformed Event packets are handled, so that long-lived sessions can survive them.
+    ///
+    /// Skipped events are not ACKed, since their txid may not be trustworthy.
+    pub fn set_invalid_event_policy(&mut self, policy: InvalidEventPolicy) {
+        self.invalid_event_policy = policy;
+    }
+
+    /// Number of malformed Event packets received so far, including skipped ones
+    pub fn invalid_event_count(&self) -> usize {
+        self.invalid_event_count
+    }
+
+    /// Report [`Anomaly`](Anomaly)s of this connection to this channel, including those of its
+    /// [`CloudProtoSocket`](CloudProtoSocket), tagged with the [`connection_id`](Self::connection_id).
+    ///
+    /// Received event txids that repeat or go backwards are also reported,
+    /// see [`TxidAnomalyDetector`](TxidAnomalyDetector) to check them against a [`SensorProfile`](SensorProfile).
+    pub fn set_anomaly_sender(&mut self, tx: Option<mpsc::UnboundedSender<Anomaly>>) {
+        self.anomalies = AnomalyReporter::new(tx).with_connection_id(self.connection_id);
+        self.io.set_anomaly_reporter(self.anomalies.clone());
+    }
+
+    // Returns the error if it should be passed on to the caller
+    fn handle_invalid_event(
+        &mut self,
+        err: CloudProtoError,
+        pkt: &CloudProtoPacket,
+    ) -> Option<CloudProtoError> {
+        self.invalid_event_count += 1;
+        self.anomalies
+            .report(AnomalyKind::InvalidEvent(err.to_string()));
+        match &mut self.invalid_event_policy {
+            InvalidEventPolicy::Error => return Some(err),
+            InvalidEventPolicy::Skip => {}
+            InvalidEventPolicy::Callback(handler) => handler(&err, pkt),
+        }
+        warn!(
+            conn_id = self.connection_id,
+            "Skipping invalid event packet: {}", err
+        );
+        None
+    }
+
+    /// Limits the number of sent events that the peer has not ACKed yet.
+    ///
+    /// Once `limit` events are in flight, [`poll_ready`](Sink::poll_ready) returns `Pending`
+    /// until ACKs are received, so that a stalled peer exerts backpressure on the sender.
+    /// By default there is no limit, which matches the behavior of the official client.
+    ///
+    /// ACKs are only processed while the receive side of this socket is polled.
+    /// If you set a limit, you **must** keep polling the [`Stream`](Stream) concurrently
+    /// (e.g. after using [`StreamExt::split`](StreamExt::split)), otherwise sending will deadlock.
+    pub fn set_max_unacked_events(&mut self, limit: Option<usize>) {
+        self.max_unacked_events = limit;
+        if limit.is_none() {
+            self.inflight_txids.clear();
+        }
+        if let Some(waker) = self.ack_window_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Number of sent events that were not ACKed yet.
+    /// This is only tracked while a limit is set with [`set_max_unacked_events`](Self::set_max_unacked_events).
+    pub fn unacked_event_count(&self) -> usize {
+        self.inflight_txids.len()
+    }
+
+    fn handle_received_ack(&mut self, txid: u64) {
+        if let Some(pos) = self.inflight_txids.iter().position(|&id| id == txid) {
+            self.inflight_txids.remove(pos);
+            if let Some(waker) = self.ack_window_waker.take() {
+                waker.wake();
+            }
+        }
     }
 }
 
@@ -135,10 +707,13 @@ impl<IO> Stream for TsEventSocket<IO>
 where
     IO: AsyncRead + AsyncWrite,
 {
-    type Item = Result<Event, CloudProtoError>;
+    type Item = Result<Event, TsError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
+        if this.state == TsSocketState::Closed {
+            return Poll::Ready(None);
+        }
 
         // (Shh, don't tell anyone, but this is a stealth goto we take just once after receiving an event!)
         'process_pending_acks: loop {
@@ -146,12 +721,7 @@ where
                 assert!(this.unacked_event.is_some());
                 ready!(this.io.poll_ready_unpin(cx))?;
 
-                this.io.start_send_unpin(CloudProtoPacket {
-                    magic: CloudProtoMagic::TS,
-                    kind: TsPacketKind::Ack.into(),
-                    version: CloudProtoVersion::Normal,
-                    payload: txid.to_be_bytes().to_vec(),
-                })?;
+                this.io.start_send_unpin(ack_packet(*txid))?;
                 let _ = this.unacked_txid.take();
 
                 // If the ACK doesn't finish leaving here, that's fine,
@@ -165,9 +735,33 @@ where
 
             '_receive_packets: loop {
                 let pkt = match this.io.poll_next_unpin(cx)? {
-                    Poll::Ready(Some(pkt)) => pkt,
-                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Ready(Some(pkt)) => {
+                        this.last_received_at = Instant::now();
+                        if let Some((timeout, sleep)) = &mut this.idle_timeout {
+                            sleep.as_mut().reset(this.last_received_at + *timeout);
+                        }
+                        pkt
+                    }
+                    Poll::Ready(None) => {
+                        debug!(conn_id = this.connection_id, "TS connection closed by peer");
+                        this.state = TsSocketState::Closed;
+                        return Poll::Ready(None);
+                    }
                     Poll::Pending => {
+                        if let Some((timeout, sleep)) = &mut this.idle_timeout {
+                            if sleep.as_mut().poll(cx).is_ready() {
+                                warn!(
+                                    conn_id = this.connection_id,
+                                    "No packets received for {:?}, giving up on connection",
+                                    timeout
+                                );
+                                this.anomalies.report(AnomalyKind::IdleTimeout(*timeout));
+                                return Poll::Ready(Some(Err(TsError::IdleTimeout {
+                                    timeout: *timeout,
+                                    origin: this.origin(),
+                                })));
+                            }
+                        }
                         // If the user is only polling the read side, some of our ACKs might never finish flushing,
                         // the other server would stop sending, and this poll_next would be Pending forever :)
                         // So if we have nothing left but the user is still reading, it's a good time to flush our send side
@@ -188,22 +782,25 @@ where
                     if pkt.payload.len() == 8 {
                         let txid = u64::from_be_bytes(pkt.payload[..].try_into().unwrap());
                         trace!("Received ACK for event txid {:#x}", txid);
+                        this.handle_received_ack(txid);
                     } else {
                         error!(
+                            conn_id = this.connection_id,
                             "Received ACK packet with invalid size: {:#x}",
                             pkt.payload.len()
-                        )
+                        );
+                        this.anomalies
+                            .report(AnomalyKind::BadAckSize(pkt.payload.len()));
                     }
                     continue;
                 } else if pkt.kind == TsPacketKind::Event {
-                    if pkt.payload.len() < HDR_TXID_SIZE + EVT_HDR_LEN {
-                        return Poll::Ready(Some(Err(CloudProtoError::PayloadTooShort(
-                            pkt.payload.len(),
-                            HDR_TXID_SIZE + EVT_HDR_LEN,
-                        ))));
-                    }
-                    let txid = u64::from_be_bytes(pkt.payload[..HDR_TXID_SIZE].try_into().unwrap());
-                    let ev = Event::from_read(&mut Cursor::new(&pkt.payload[HDR_TXID_SIZE..]))?;
+                    let (txid, ev) = match Event::from_packet(&pkt) {
+                        Ok(parsed) => parsed,
+                        Err(e) => match this.handle_invalid_event(e, &pkt) {
+                            Some(e) => return Poll::Ready(Some(Err(e.into()))),
+                            None => continue,
+                        },
+                    };
 
                     // We ACK received events before returning them, to make sure we keep getting polled until the ACK is sent
                     // So we have to buffer the event and its txid, in case we get Poll::Pending while trying to ACK it
@@ -211,18 +808,23 @@ where
                         "Received event with txid {:#x}, preparing to send ACK",
                         txid
                     );
+                    if this.anomalies.is_enabled() {
+                        if let Err(anomaly) = this.received_txids.check(txid) {
+                            this.anomalies.report(AnomalyKind::Txid(anomaly));
+                        }
+                    }
                     assert!(this.unacked_txid.is_none());
+                    let received_at = this
+                        .io
+                        .last_frame_received_at()
+                        .unwrap_or_else(EventTimestamp::now);
+                    this.last_received_event = Some((txid, received_at));
                     this.unacked_txid = Some(txid);
                     assert!(this.unacked_event.is_none());
                     this.unacked_event = Some(ev);
                     continue 'process_pending_acks;
                 } else {
-                    // Hoping this was a non-essential packet and continuing happily...
-                    warn!(
-                        "Received unexpected CloudProto packet kind: {:#x}",
-                        pkt.kind
-                    );
-                    trace!("Unexpected packet payload: {}", hex::encode(&pkt.payload));
+                    this.record_unexpected_packet(pkt);
                 }
             }
         }
@@ -233,7 +835,7 @@ impl<IO> Sink<Event> for TsEventSocket<IO>
 where
     IO: AsyncRead + AsyncWrite,
 {
-    type Error = std::io::Error;
+    type Error = TsError;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         // If we wanted to tracked ACKs for our tx, here we would need to block when the
@@ -305,40 +907,614 @@ where
         // A lot of the client code is like this, half implemented stuff. But maybe we should
         // really be impressed by this surely purposeful obfuscation and misdirection.
         // (...almost as effective as having to follow all those damn C++ virtual calls everywhere!)
+        //
+        // So by default we don't track anything. Users who do keep polling the RX side concurrently
+        // can still opt into an ACK window with set_max_unacked_events(), and then we block here.
         let this = self.get_mut();
-        this.io.poll_ready_unpin(cx)
+        if this.state == TsSocketState::Closed {
+            return Poll::Ready(Err(TsError::AlreadyClosed));
+        }
+        if let Some(limit) = this.max_unacked_events {
+            if this.inflight_txids.len() >= limit {
+                this.ack_window_waker = Some(cx.waker().clone());
+                // Pending ACKs may be stuck in our own write buffer, so the peer can't keep going either
+                ready!(this.io.poll_flush_unpin(cx))?;
+                return Poll::Pending;
+            }
+        }
+        this.io.poll_ready_unpin(cx).map_err(Into::into)
     }
 
     fn start_send(self: Pin<&mut Self>, ev: Event) -> Result<(), Self::Error> {
         let this = self.get_mut();
+        if this.state == TsSocketState::Closed {
+            return Err(TsError::AlreadyClosed);
+        }
 
-        let mut buf = Vec::with_capacity(HDR_TXID_SIZE + EVT_HDR_LEN + ev.data.len());
-        buf.extend_from_slice(&this.next_txid.to_be_bytes());
-        this.next_txid += TXID_INCREMENT;
-        match ev.into_write(&mut buf) {
-            Ok(_) => {}
-            Err(CloudProtoError::Io { source }) => return Err(source),
-            Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Unexpected error while sending Event: {}", e),
-                ))
+        this.last_sent_event = Some((this.next_txid, EventTimestamp::now()));
+        if this.max_unacked_events.is_some() {
+            this.inflight_txids.push_back(this.next_txid);
+        }
+        let pkt = ev.into_packet(this.next_txid);
+        this.next_txid = this.next_txid.wrapping_add(this.txid_increment);
+        Ok(this.io.start_send_unpin(pkt)?)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().io.poll_flush_unpin(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.state = TsSocketState::Closed;
+        this.io.poll_close_unpin(cx).map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::framing::{
+        CloudProtoError, CloudProtoPacket, CloudProtoSocket, CloudProtoSocketBuilder,
+        CloudProtoVersion, MemoryReport,
+    };
+    use crate::services::ts::{
+        AgentIdStatus, Event, EventId, InvalidEventPolicy, SensorProfile, TemplatePart,
+        TsConnectInfo, TsConnectResponse, TsError, TsEventAcceptor, TsEventSocket, TsPacketKind,
+        TsSocketState, TxidAnomaly, WelcomeSequence,
+    };
+    use crate::services::CloudProtoMagic;
+    use crate::{AnomalyKind, AnomalySeverity};
+    use futures_util::{FutureExt, SinkExt, StreamExt};
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+
+    pub(crate) async fn connected_pair(
+    ) -> Result<(TsEventSocket<DuplexStream>, TsEventSocket<DuplexStream>), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let (acceptor, info) = TsEventAcceptor::listen(CloudProtoSocket::new(server)).await?;
+            acceptor
+                .accept(TsConnectResponse {
+                    agent_id_status: AgentIdStatus::Unchanged,
+                    aid: info.aid,
+                })
+                .await
+        });
+        let client = TsEventSocket::connect(
+            CloudProtoSocket::new(client),
+            TsConnectInfo::new_simple([1; 16]),
+        )
+        .await?;
+        let server = server.await.expect("Server task join error!")?;
+        Ok((client, server))
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn idle_timeout() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        server.set_idle_timeout(Some(Duration::from_secs(60)));
+
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        client
+            .send(Event::new(EventId::AgentOnline, vec![]))
+            .await?;
+        assert!(server.next().await.unwrap().is_ok());
+        assert!(server.idle_duration() < Duration::from_secs(1));
+
+        let start = tokio::time::Instant::now();
+        match server.next().await {
+            Some(Err(TsError::IdleTimeout { origin, .. })) => {
+                assert_eq!(origin.connection_id, Some(server.connection_id()));
+                assert_eq!(origin.aid, Some(server.connect_info().aid));
             }
+            other => panic!("Expected idle timeout, got {:?}", other),
         }
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn capture_unexpected_packets() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = CloudProtoSocket::new(client);
+        let mut server = TsEventSocket::new(
+            CloudProtoSocket::new(server),
+            TsConnectInfo::new_simple([0; 16]),
+            &SensorProfile::default(),
+        );
+        server.set_unexpected_packet_capture(2);
+
+        for i in 0..3 {
+            client
+                .send(CloudProtoPacket {
+                    magic: CloudProtoMagic::TS,
+                    kind: TsPacketKind::Other(0x42).into(),
+                    version: CloudProtoVersion::Normal,
+                    payload: vec![i].into(),
+                })
+                .await?;
+        }
+        let mut event_payload = 0x200u64.to_be_bytes().to_vec();
+        event_payload.extend_from_slice(&(EventId::AgentOnline as u32).to_be_bytes());
+        client
+            .send(CloudProtoPacket {
+                magic: CloudProtoMagic::TS,
+                kind: TsPacketKind::Event.into(),
+                version: CloudProtoVersion::Normal,
+                payload: event_payload.into(),
+            })
+            .await?;
+        assert!(server.next().await.unwrap().is_ok());
+
+        let unexpected = server.take_unexpected_packets();
+        assert_eq!(unexpected.len(), 1);
+        assert_eq!(unexpected[&0x42].seen, 3);
+        let payloads: Vec<_> = unexpected[&0x42]
+            .captured
+            .iter()
+            .map(|pkt| pkt.payload.clone())
+            .collect();
+        assert_eq!(payloads, vec![vec![0], vec![1]]);
+        assert!(server.unexpected_packets().is_empty());
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn memory_report() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        client.set_max_unacked_events(Some(4));
+        let ev = Event::new(EventId::AgentOnline, vec![0; 0x20]);
+        client.feed(ev.clone()).await?;
+        let report = client.memory_report();
+        assert_eq!(report.sockets, 1);
+        assert_eq!(report.queued_frames, 1);
+        assert_eq!(report.inflight_events, 1);
+        assert!(report.write_buffer_capacity >= report.queued_bytes);
+
+        client.flush().await?;
+        assert_eq!(client.memory_report().queued_frames, 0);
+        assert_eq!(server.next().await.unwrap()?, ev);
+
+        let total: MemoryReport = [client.memory_report(), server.memory_report()]
+            .into_iter()
+            .sum();
+        assert_eq!(total.sockets, 2);
+        assert_eq!(total.captured_packet_bytes, 0);
+        assert!(total.total_bytes() >= total.read_buffer_capacity);
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn txid_wraparound() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = TsEventSocket::new(
+            CloudProtoSocket::new(client),
+            TsConnectInfo::new_simple([0; 16]),
+            &SensorProfile::default(),
+        );
+        let mut server = CloudProtoSocket::new(server);
+
+        let increment = SensorProfile::default().txid_increment;
+        let first_txid = u64::MAX - increment + 1;
+        client.set_next_txid(first_txid);
+        for _ in 0..2 {
+            client
+                .feed(Event::new(EventId::AgentOnline, vec![]))
+                .await?;
+        }
+        client.flush().await?;
+        assert_eq!(client.next_txid(), increment);
+
+        for expected in [first_txid, 0] {
+            let pkt = server.next().await.unwrap()?;
+            assert_eq!(pkt.payload[..8], expected.to_be_bytes());
+        }
+        Ok(())
+    }
 
-        this.io.start_send_unpin(CloudProtoPacket {
+    #[test_log::test(tokio::test)]
+    async fn explicit_txids() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = TsEventSocket::new(
+            CloudProtoSocket::new(client),
+            TsConnectInfo::new_simple([0; 16]),
+            &SensorProfile::default(),
+        );
+        let mut server = CloudProtoSocket::new(server);
+
+        client.set_next_txid(0xAAAA00);
+        client
+            .feed(Event::new(EventId::AgentOnline, vec![]))
+            .await?;
+        client.set_next_txid(0x42);
+        client
+            .feed(Event::new(EventId::AgentOnline, vec![]))
+            .await?;
+        client.flush().await?;
+        assert_eq!(
+            client.next_txid(),
+            0x42 + SensorProfile::default().txid_increment
+        );
+
+        for expected in [0xAAAA00u64, 0x42] {
+            let pkt = server.next().await.unwrap()?;
+            assert_eq!(pkt.payload[..8], expected.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn state_transitions() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        assert_eq!(client.state(), TsSocketState::Established);
+
+        client.close().await?;
+        assert_eq!(client.state(), TsSocketState::Closed);
+        let err = client
+            .send(Event::new(EventId::AgentOnline, vec![]))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TsError::AlreadyClosed));
+        let err = client
+            .send_raw(Event::new(EventId::AgentOnline, vec![]).into_packet(0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TsError::AlreadyClosed));
+
+        assert!(server.next().await.is_none());
+        assert_eq!(server.state(), TsSocketState::Closed);
+        assert!(server.next().await.is_none());
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn route_raw_packets() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        let mut raw_rx = server.route_raw_packets();
+        let (tx, mut anomalies) = tokio::sync::mpsc::unbounded_channel();
+        server.set_anomaly_sender(Some(tx));
+
+        let raw = CloudProtoPacket {
             magic: CloudProtoMagic::TS,
-            kind: TsPacketKind::Event.into(),
+            kind: TsPacketKind::Other(0x42).into(),
             version: CloudProtoVersion::Normal,
-            payload: buf,
-        })
+            payload: vec![0xAA, 0xBB].into(),
+        };
+        client.send_raw(raw.clone()).await?;
+        client
+            .send(Event::new(EventId::AgentOnline, vec![]))
+            .await?;
+
+        let ev = server.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::AgentOnline));
+        assert_eq!(raw_rx.try_recv().unwrap(), raw);
+        assert!(server.unexpected_packets().is_empty());
+        // Routed packets were asked for, so they are not anomalies
+        assert!(anomalies.try_recv().is_err());
+        Ok(())
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.get_mut().io.poll_flush_unpin(cx)
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn welcome_sequence() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        let welcome = WelcomeSequence::new()
+            .step(
+                EventId::ConfigurationLoaded,
+                vec![TemplatePart::Literal(vec![0x0A, 0x10]), TemplatePart::Aid],
+            )
+            .delayed_step(
+                0x12345678u32,
+                vec![TemplatePart::Cid],
+                Duration::from_secs(5),
+            );
+
+        client
+            .send(Event::new(EventId::AgentOnline, vec![]))
+            .await?;
+        let ev = server.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::AgentOnline));
+        let start = tokio::time::Instant::now();
+        server.send_welcome(&welcome).await?;
+        assert!(start.elapsed() >= Duration::from_secs(5));
+
+        let ev = client.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::ConfigurationLoaded));
+        let mut expected = vec![0x0A, 0x10];
+        expected.extend_from_slice(&client.connect_info().aid);
+        assert_eq!(ev.data, expected);
+        let ev = client.next().await.unwrap()?;
+        assert_eq!(ev.raw_event_id, 0x12345678);
+        assert_eq!(ev.data, [1; 16]);
+        Ok(())
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.get_mut().io.poll_close_unpin(cx)
+    #[test_log::test(tokio::test)]
+    async fn route_packet_kinds() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        let mut raw_rx = server.route_raw_packets();
+        let mut kind_rx = server.route_packet_kind(TsPacketKind::Other(0x42))?;
+        let (tx, mut anomalies) = tokio::sync::mpsc::unbounded_channel();
+        server.set_anomaly_sender(Some(tx));
+
+        client
+            .send_packet_kind(TsPacketKind::Other(0x42), vec![0xAA])
+            .await?;
+        client
+            .send_packet_kind(TsPacketKind::Other(0x43), vec![0xBB])
+            .await?;
+        client
+            .send(Event::new(EventId::AgentOnline, vec![]))
+            .await?;
+
+        let ev = server.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::AgentOnline));
+        let routed = kind_rx.try_recv().unwrap();
+        assert_eq!(routed.kind, 0x42);
+        assert_eq!(routed.payload, vec![0xAA]);
+        assert_eq!(raw_rx.try_recv().unwrap().kind, 0x43);
+        assert!(kind_rx.try_recv().is_err());
+        assert!(anomalies.try_recv().is_err());
+        assert_eq!(client.next_txid(), server.next_txid() + 0x100);
+
+        // Kinds handled by the event layer can't be sent or routed separately
+        for kind in [
+            TsPacketKind::Event,
+            TsPacketKind::Ack,
+            TsPacketKind::Other(3),
+        ] {
+            let err = client.send_packet_kind(kind, vec![]).await.unwrap_err();
+            assert!(matches!(err, TsError::ReservedPacketKind(_)));
+            let err = server.route_packet_kind(kind).unwrap_err();
+            assert!(matches!(err, TsError::ReservedPacketKind(_)));
+        }
+        let err = client
+            .send_packet_kind(TsPacketKind::Connect, vec![])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TsError::ReservedPacketKind(1)));
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn send_malformed_events() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        server.set_invalid_event_policy(InvalidEventPolicy::Skip);
+        let first_txid = client.next_txid();
+
+        client
+            .send_malformed(Event::new(EventId::AgentOnline, vec![]), |pkt| {
+                pkt.payload.truncate(6)
+            })
+            .await?;
+        client
+            .send(Event::new(EventId::DiskCapacity, vec![]))
+            .await?;
+
+        let received = server.next_received().await.unwrap()?;
+        assert_eq!(received.event.event_id, Some(EventId::DiskCapacity));
+        assert_eq!(
+            received.txid,
+            first_txid + SensorProfile::default().txid_increment
+        );
+        assert_eq!(server.invalid_event_count(), 1);
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn event_timestamps() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        assert_eq!(client.last_sent_event(), None);
+
+        client
+            .send(Event::new(EventId::AgentOnline, vec![0x08, 0x01]))
+            .await?;
+        let (sent_txid, sent_at) = client.last_sent_event().unwrap();
+        tokio::time::advance(Duration::from_secs(3)).await;
+
+        let received = server.next_received().await.unwrap()?;
+        assert_eq!(received.event.event_id, Some(EventId::AgentOnline));
+        assert_eq!(received.txid, sent_txid);
+        assert_eq!(
+            received.received_at.monotonic - sent_at.monotonic,
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            server.last_received_event(),
+            Some((received.txid, received.received_at))
+        );
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn handshake_timings() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let (acceptor, info) = TsEventAcceptor::listen(CloudProtoSocket::new(server)).await?;
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            acceptor
+                .accept(TsConnectResponse {
+                    agent_id_status: AgentIdStatus::Unchanged,
+                    aid: info.aid,
+                })
+                .await
+        });
+        let client = TsEventSocket::connect(
+            CloudProtoSocket::new(client),
+            TsConnectInfo::new_simple([1; 16]),
+        )
+        .await?;
+        let server = server.await.expect("Server task join error!")?;
+        assert_eq!(server.handshake_timings(), None);
+
+        let timings = client.handshake_timings().unwrap();
+        assert_eq!(timings.connect_sent, Duration::ZERO);
+        assert_eq!(timings.established, Duration::from_millis(250));
+        assert_eq!(timings.server_reply(), Duration::from_millis(250));
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn report_anomalies() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = CloudProtoSocket::new(client);
+        let mut server = TsEventSocket::new(
+            CloudProtoSocketBuilder::new()
+                .soft_frame_length(0x40)
+                .build(server),
+            TsConnectInfo::new_simple([0; 16]),
+            &SensorProfile::default(),
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        server.set_anomaly_sender(Some(tx));
+
+        let pkt = |kind: TsPacketKind, payload: Vec<u8>| CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: kind.into(),
+            version: CloudProtoVersion::Normal,
+            payload: payload.into(),
+        };
+        client.feed(pkt(TsPacketKind::Other(0x42), vec![])).await?;
+        client.feed(pkt(TsPacketKind::Ack, vec![0; 3])).await?;
+        let ev = Event::new(EventId::AgentOnline, vec![0; 0x40]);
+        client.feed(ev.clone().into_packet(0x300)).await?;
+        client.feed(ev.clone().into_packet(0x200)).await?;
+        client.flush().await?;
+        assert_eq!(server.next().await.unwrap()?, ev);
+        assert_eq!(server.next().await.unwrap()?, ev);
+
+        let mut anomalies = Vec::new();
+        while let Ok(anomaly) = rx.try_recv() {
+            assert_eq!(anomaly.connection_id, Some(server.connection_id()));
+            anomalies.push((anomaly.severity, anomaly.kind));
+        }
+        let oversized = AnomalyKind::OversizedFrame {
+            frame_length: 0x54,
+            soft_frame_length: 0x40,
+        };
+        assert_eq!(
+            anomalies,
+            vec![
+                (
+                    AnomalySeverity::Warning,
+                    AnomalyKind::UnknownPacketKind {
+                        magic: CloudProtoMagic::TS,
+                        kind: 0x42
+                    }
+                ),
+                (AnomalySeverity::Warning, AnomalyKind::BadAckSize(3)),
+                (AnomalySeverity::Notice, oversized.clone()),
+                (AnomalySeverity::Notice, oversized),
+                (
+                    AnomalySeverity::Notice,
+                    AnomalyKind::Txid(TxidAnomaly::WentBackwards {
+                        previous: 0x300,
+                        txid: 0x200
+                    })
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn skip_invalid_events() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = CloudProtoSocket::new(client);
+        let mut server = TsEventSocket::new(
+            CloudProtoSocket::new(server),
+            TsConnectInfo::new_simple([0; 16]),
+            &SensorProfile::default(),
+        );
+        let (seen_tx, seen_rx) = std::sync::mpsc::channel();
+        server.set_invalid_event_policy(InvalidEventPolicy::Callback(Box::new(move |err, pkt| {
+            assert!(matches!(err, CloudProtoError::PayloadTooShort(3, _)));
+            seen_tx.send(pkt.payload.clone()).unwrap();
+        })));
+
+        let mut event_payload = 0x200u64.to_be_bytes().to_vec();
+        event_payload.extend_from_slice(&(EventId::AgentOnline as u32).to_be_bytes());
+        for payload in [vec![1, 2, 3], event_payload] {
+            client
+                .send(CloudProtoPacket {
+                    magic: CloudProtoMagic::TS,
+                    kind: TsPacketKind::Event.into(),
+                    version: CloudProtoVersion::Normal,
+                    payload: payload.into(),
+                })
+                .await?;
+        }
+        let ev = server.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::AgentOnline));
+        assert_eq!(server.invalid_event_count(), 1);
+        assert_eq!(seen_rx.try_recv().unwrap(), vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn protocol_errors() -> Result<(), TsError> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut client = CloudProtoSocket::new(client);
+        let mut server = TsEventSocket::new(
+            CloudProtoSocket::new(server),
+            TsConnectInfo::new_simple([0; 16]),
+            &SensorProfile::default(),
+        );
+        client
+            .send(CloudProtoPacket {
+                magic: CloudProtoMagic::TS,
+                kind: TsPacketKind::Event.into(),
+                version: CloudProtoVersion::Normal,
+                payload: vec![1, 2, 3].into(),
+            })
+            .await?;
+        let err = server.next().await.unwrap().unwrap_err();
+        assert!(err.is_protocol_violation());
+        assert!(matches!(
+            err,
+            TsError::Protocol(CloudProtoError::PayloadTooShort(3, _))
+        ));
+
+        drop(client);
+        assert!(server.next().await.is_none());
+        let err = server
+            .send(Event::new(EventId::AgentOnline, vec![]))
+            .await
+            .unwrap_err();
+        assert!(!err.is_protocol_violation());
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn ack_window_backpressure() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        server.set_max_unacked_events(Some(1));
+        let (mut server_tx, mut server_rx) = server.split();
+
+        server_tx
+            .send(Event::new(EventId::ChannelRundown, vec![1]))
+            .await?;
+        // The window is full until the client ACKs the first event
+        assert!(server_tx
+            .send(Event::new(EventId::ChannelRundown, vec![2]))
+            .now_or_never()
+            .is_none());
+
+        let ev = client.next().await.unwrap()?;
+        assert_eq!(ev.data, &[1]);
+        client
+            .send(Event::new(EventId::AgentOnline, vec![]))
+            .await?;
+        // Receiving the client's event means we went through its ACK first
+        let ev = server_rx.next().await.unwrap()?;
+        assert_eq!(ev.event_id, Some(EventId::AgentOnline));
+
+        // The split sink kept our second event buffered, now it can leave
+        server_tx
+            .flush()
+            .now_or_never()
+            .expect("ACK window should have room")?;
+        let ev = client.next().await.unwrap()?;
+        assert_eq!(ev.data, &[2]);
+        Ok(())
     }
 }
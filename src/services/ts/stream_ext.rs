@@ -0,0 +1,158 @@
+use crate::services::ts::{Event, TsError};
+use futures_util::{Stream, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::time::{timeout, timeout_at, Instant};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Convenience combinators for streams of received [`Event`](Event)s with deadlines.
+///
+/// This is implemented for [`TsEventSocket`](super::TsEventSocket),
+/// but also for its receive half after a [`split`](StreamExt::split).
+pub trait TsEventStreamExt {
+    /// Wait for the next event with the given ID, discarding any other events received meanwhile.
+    ///
+    /// Returns a [`Timeout`](TsError::Timeout) error if no matching event arrives in time.
+    fn next_matching(
+        &mut self,
+        raw_event_id: impl Into<u32>,
+        deadline: Duration,
+    ) -> BoxFuture<'_, Result<Event, TsError>>;
+
+    /// Expect the next events to have exactly these IDs, in order, within the deadline.
+    ///
+    /// Returns an [`UnexpectedEvent`](TsError::UnexpectedEvent) error on the first mismatch.
+    fn expect_sequence(
+        &mut self,
+        raw_event_ids: &[impl Into<u32> + Copy],
+        deadline: Duration,
+    ) -> BoxFuture<'_, Result<Vec<Event>, TsError>>;
+
+    /// Collect all events received until nothing arrives for `idle`, or the stream ends.
+    ///
+    /// If the stream returns an error, the error is returned and the events collected so far are dropped.
+    fn collect_until_idle(&mut self, idle: Duration) -> BoxFuture<'_, Result<Vec<Event>, TsError>>;
+}
+
+fn closed() -> TsError {
+    TsError::closed_by_peer(
+        "TS stream ended while waiting for events",
+        Default::default(),
+    )
+}
+
+impl<S> TsEventStreamExt for S
+where
+    S: Stream<Item = Result<Event, TsError>> + Unpin + Send,
+{
+    fn next_matching(
+        &mut self,
+        raw_event_id: impl Into<u32>,
+        deadline: Duration,
+    ) -> BoxFuture<'_, Result<Event, TsError>> {
+        let raw_event_id = raw_event_id.into();
+        Box::pin(async move {
+            let wait = async {
+                while let Some(ev) = self.next().await {
+                    let ev = ev?;
+                    if ev.raw_event_id == raw_event_id {
+                        return Ok(ev);
+                    }
+                }
+                Err(closed())
+            };
+            timeout(deadline, wait)
+                .await
+                .map_err(|_| TsError::Timeout(deadline))?
+        })
+    }
+
+    fn expect_sequence(
+        &mut self,
+        raw_event_ids: &[impl Into<u32> + Copy],
+        deadline: Duration,
+    ) -> BoxFuture<'_, Result<Vec<Event>, TsError>> {
+        let raw_event_ids: Vec<u32> = raw_event_ids.iter().map(|&id| id.into()).collect();
+        Box::pin(async move {
+            let end = Instant::now() + deadline;
+            let mut events = Vec::with_capacity(raw_event_ids.len());
+            for expected in raw_event_ids {
+                let ev = match timeout_at(end, self.next()).await {
+                    Err(_) => return Err(TsError::Timeout(deadline)),
+                    Ok(None) => return Err(closed()),
+                    Ok(Some(ev)) => ev?,
+                };
+                if ev.raw_event_id != expected {
+                    return Err(TsError::UnexpectedEvent(ev.raw_event_id, expected));
+                }
+                events.push(ev);
+            }
+            Ok(events)
+        })
+    }
+
+    fn collect_until_idle(&mut self, idle: Duration) -> BoxFuture<'_, Result<Vec<Event>, TsError>> {
+        Box::pin(async move {
+            let mut events = Vec::new();
+            while let Ok(ev) = timeout(idle, self.next()).await {
+                match ev {
+                    Some(ev) => events.push(ev?),
+                    None => break,
+                }
+            }
+            Ok(events)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::ts::socket::test::connected_pair;
+    use crate::services::ts::EventId;
+    use futures_util::SinkExt;
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn deadline_combinators() -> Result<(), TsError> {
+        let (mut client, mut server) = connected_pair().await?;
+        for id in [
+            EventId::AgentOnline,
+            EventId::ChannelRundown,
+            EventId::ChannelDownloadComplete,
+            EventId::DiskCapacity,
+            EventId::DiskUtilization,
+        ] {
+            client.feed(Event::new(id, vec![])).await?;
+        }
+        client.flush().await?;
+
+        let ev = server
+            .next_matching(EventId::ChannelRundown, Duration::from_secs(1))
+            .await?;
+        assert_eq!(ev.event_id, Some(EventId::ChannelRundown));
+
+        let seq = [EventId::ChannelDownloadComplete, EventId::ChannelRundown];
+        match server.expect_sequence(&seq, Duration::from_secs(1)).await {
+            Err(TsError::UnexpectedEvent(actual, expected)) => {
+                assert_eq!(actual, EventId::DiskCapacity as u32);
+                assert_eq!(expected, EventId::ChannelRundown as u32);
+            }
+            other => panic!("Expected unexpected event error, got {:?}", other),
+        }
+
+        let events = server.collect_until_idle(Duration::from_secs(1)).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_id, Some(EventId::DiskUtilization));
+
+        match server
+            .next_matching(EventId::AgentOnline, Duration::from_secs(5))
+            .await
+        {
+            Err(TsError::Timeout(d)) => assert_eq!(d, Duration::from_secs(5)),
+            other => panic!("Expected timeout, got {:?}", other),
+        }
+        Ok(())
+    }
+}
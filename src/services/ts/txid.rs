@@ -0,0 +1,127 @@
+use crate::services::ts::SensorProfile;
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// Why a received txid deviates from the expected pattern
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum TxidAnomaly {
+    /// The first txid of the session is not the expected one
+    UnexpectedFirstTxid { txid: u64, expected: u64 },
+    /// The same txid was received twice in a row
+    Repeated { txid: u64 },
+    /// The txid is lower than the previous one
+    WentBackwards { previous: u64, txid: u64 },
+    /// The txid did not increase by the expected increment
+    UnexpectedIncrement {
+        previous: u64,
+        txid: u64,
+        expected: u64,
+    },
+}
+
+/// Flags received txid sequences that deviate from the expected increment pattern.
+///
+/// Different sensor builds number their events differently, and injected traffic
+/// in a proxy deployment tends to break the sequence. Feed it the txids of received events,
+/// e.g. from [`TsEventSocket::next_received`](super::TsEventSocket::next_received).
+///
+/// Note that the TS server uses large, irregularly incrementing txids,
+/// so only the txids of a sensor's events follow a strict pattern.
+#[derive(Debug, Clone, Default)]
+pub struct TxidAnomalyDetector {
+    expected_first: Option<u64>,
+    expected_increment: Option<u64>,
+    previous: Option<u64>,
+    increments: BTreeMap<u64, usize>,
+}
+
+impl TxidAnomalyDetector {
+    /// Expect txids to always increase by `expected_increment`, if set,
+    /// and otherwise only flag txids that repeat or go backwards
+    pub fn new(expected_increment: Option<u64>) -> Self {
+        Self {
+            expected_increment,
+            ..Default::default()
+        }
+    }
+
+    /// Expect the txids that a sensor with this profile would send
+    pub fn for_profile(profile: &SensorProfile) -> Self {
+        Self {
+            expected_first: Some(profile.first_txid),
+            expected_increment: Some(profile.txid_increment),
+            ..Default::default()
+        }
+    }
+
+    /// How many times each increment between consecutive txids was seen.
+    /// This can help fingerprint the peer's build, even when no anomaly is flagged.
+    pub fn observed_increments(&self) -> &BTreeMap<u64, usize> {
+        &self.increments
+    }
+
+    /// Record a received txid, and return whether it deviates from the expected pattern
+    pub fn check(&mut self, txid: u64) -> Result<(), TxidAnomaly> {
+        let anomaly = match self.previous.replace(txid) {
+            None => match self.expected_first {
+                Some(expected) if txid != expected => {
+                    TxidAnomaly::UnexpectedFirstTxid { txid, expected }
+                }
+                _ => return Ok(()),
+            },
+            Some(previous) if txid == previous => TxidAnomaly::Repeated { txid },
+            Some(previous) if txid < previous => TxidAnomaly::WentBackwards { previous, txid },
+            Some(previous) => {
+                let increment = txid - previous;
+                *self.increments.entry(increment).or_default() += 1;
+                match self.expected_increment {
+                    Some(expected) if increment != expected => TxidAnomaly::UnexpectedIncrement {
+                        previous,
+                        txid,
+                        expected,
+                    },
+                    _ => return Ok(()),
+                }
+            }
+        };
+        warn!("Anomalous txid sequence: {:?}", anomaly);
+        Err(anomaly)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_txid_anomalies() {
+        let mut detector = TxidAnomalyDetector::for_profile(&SensorProfile::v13601());
+        assert_eq!(detector.check(0x200), Ok(()));
+        assert_eq!(detector.check(0x300), Ok(()));
+        assert_eq!(
+            detector.check(0x300),
+            Err(TxidAnomaly::Repeated { txid: 0x300 })
+        );
+        assert_eq!(
+            detector.check(0x380),
+            Err(TxidAnomaly::UnexpectedIncrement {
+                previous: 0x300,
+                txid: 0x380,
+                expected: 0x100
+            })
+        );
+        assert_eq!(
+            detector.check(0x200),
+            Err(TxidAnomaly::WentBackwards {
+                previous: 0x380,
+                txid: 0x200
+            })
+        );
+        assert_eq!(detector.observed_increments()[&0x100], 1);
+        assert_eq!(detector.observed_increments()[&0x80], 1);
+
+        let mut detector = TxidAnomalyDetector::new(None);
+        assert_eq!(detector.check(0x1234), Ok(()));
+        assert_eq!(detector.check(0x99999), Ok(()));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::services::ts::{Event, EventId, TsConnectInfo};
+use std::time::Duration;
+
+/// A piece of a [`WelcomeStep`](WelcomeStep) payload
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum TemplatePart {
+    Literal(Vec<u8>),
+    /// The [`TsConnectInfo::cid`](TsConnectInfo) of the connected sensor
+    Cid,
+    /// The AID of the connected sensor, as negotiated during connection
+    Aid,
+    Unk0,
+    Bootid,
+}
+
+/// One event of a [`WelcomeSequence`](WelcomeSequence)
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct WelcomeStep {
+    pub raw_event_id: u32,
+    /// Concatenated to build the event data
+    pub payload: Vec<TemplatePart>,
+    /// How long to wait before sending this event
+    pub delay: Duration,
+}
+
+/// The events a server sends to a sensor after its `AgentOnline` event.
+///
+/// Sensors expect the cloud to follow up on `AgentOnline` (e.g. with configuration and
+/// channel version checks) and may go quiet without it. This crate does not know the exact
+/// sequence of the real cloud, so record it from a capture and replay it as a script,
+/// with [`TsEventSocket::send_welcome`](super::TsEventSocket::send_welcome).
+/// Parts of the payloads that depend on the sensor are filled in from its [`TsConnectInfo`](TsConnectInfo).
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct WelcomeSequence {
+    steps: Vec<WelcomeStep>,
+}
+
+impl WelcomeSequence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an event sent right after the previous one
+    pub fn step(self, raw_event_id: impl Into<u32>, payload: Vec<TemplatePart>) -> Self {
+        self.delayed_step(raw_event_id, payload, Duration::ZERO)
+    }
+
+    /// Append an event sent `delay` after the previous one
+    pub fn delayed_step(
+        mut self,
+        raw_event_id: impl Into<u32>,
+        payload: Vec<TemplatePart>,
+        delay: Duration,
+    ) -> Self {
+        self.steps.push(WelcomeStep {
+            raw_event_id: raw_event_id.into(),
+            payload,
+            delay,
+        });
+        self
+    }
+
+    pub fn steps(&self) -> &[WelcomeStep] {
+        &self.steps
+    }
+
+    /// The events of the sequence for this sensor, with the delay before each of them
+    pub fn render(&self, info: &TsConnectInfo) -> Vec<(Duration, Event)> {
+        self.steps
+            .iter()
+            .map(|step| {
+                let mut data = Vec::new();
+                for part in &step.payload {
+                    match part {
+                        TemplatePart::Literal(bytes) => data.extend_from_slice(bytes),
+                        TemplatePart::Cid => data.extend_from_slice(&info.cid),
+                        TemplatePart::Aid => data.extend_from_slice(&info.aid),
+                        TemplatePart::Unk0 => data.extend_from_slice(&info.unk0),
+                        TemplatePart::Bootid => data.extend_from_slice(&info.bootid),
+                    }
+                }
+                let ev = Event {
+                    raw_event_id: step.raw_event_id,
+                    event_id: EventId::from_repr(step.raw_event_id),
+                    data,
+                };
+                (step.delay, ev)
+            })
+            .collect()
+    }
+}
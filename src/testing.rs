@@ -0,0 +1,666 @@
+//! Helpers to check that what a socket writes stays byte-compatible with captured traffic.
+//!
+//! Wrap the IO of any socket in a [`RecordingIo`](RecordingIo), then compare everything
+//! it wrote against a golden transcript with [`Transcript::assert_matches`](Transcript::assert_matches).
+//!
+//! Timing can be checked too: record the frames of a socket with a [`FrameRecorder`](FrameRecorder),
+//! and compare its ACKs with those of a real capture using [`AckTrace`](AckTrace).
+
+use crate::framing::{
+    CloudProtoPacket, CloudProtoVersion, FrameDirection, FrameTimestamp, WireLogger,
+};
+use crate::services::ts::{Event, TsPacketKind};
+use crate::services::CloudProtoMagic;
+use bytes::Bytes;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+// Number of bytes per line in transcript diffs
+const DIFF_LINE_LEN: usize = 16;
+// Tags from Wireshark's epan/exported_pdu.h, as written by ExportedPduWriter
+const EXP_PDU_TAG_END_OF_OPT: u16 = 0;
+const EXP_PDU_TAG_P2P_DIRECTION: u16 = 35;
+
+/// Wraps an IO object and records all the bytes written to it.
+pub struct RecordingIo<IO> {
+    io: IO,
+    transcript: Transcript,
+}
+
+/// The bytes written so far to a [`RecordingIo`](RecordingIo)
+#[derive(Clone, Default)]
+pub struct Transcript {
+    written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<IO> RecordingIo<IO> {
+    /// Returns the wrapped IO, and a handle to the transcript of what gets written to it
+    pub fn new(io: IO) -> (Self, Transcript) {
+        let transcript = Transcript::default();
+        let recording = Self {
+            io,
+            transcript: transcript.clone(),
+        };
+        (recording, transcript)
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for RecordingIo<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for RecordingIo<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.io).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            this.transcript
+                .written
+                .lock()
+                .unwrap()
+                .extend_from_slice(&buf[..count]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+impl Transcript {
+    /// A copy of all the bytes written so far
+    pub fn bytes(&self) -> Vec<u8> {
+        self.written.lock().unwrap().clone()
+    }
+
+    /// Panics with a readable diff if the bytes written so far don't match the golden transcript.
+    ///
+    /// See [`match_golden`](match_golden) for the transcript format.
+    pub fn assert_matches(&self, golden: &str) {
+        if let Err(diff) = match_golden(&self.bytes(), golden) {
+            panic!("Transcript does not match golden transcript:\n{}", diff);
+        }
+    }
+}
+
+fn parse_golden(golden: &str) -> Result<Vec<Option<u8>>, String> {
+    let digits: Vec<char> = golden.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return Err("Golden transcript has an odd number of hex digits".into());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| match pair {
+            ['?', '?'] => Ok(None),
+            [hi, lo] => {
+                let byte = format!("{}{}", hi, lo);
+                u8::from_str_radix(&byte, 16)
+                    .map(Some)
+                    .map_err(|_| format!("Invalid byte {:?} in golden transcript", byte))
+            }
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Compares `actual` with a golden transcript, and returns a readable diff if they differ.
+///
+/// The golden transcript is written in hex, and whitespace is ignored so it can be split
+/// into lines and fields. A `??` placeholder matches any byte, for values like txids or timestamps
+/// that change between runs.
+pub fn match_golden(actual: &[u8], golden: &str) -> Result<(), String> {
+    let expected = parse_golden(golden)?;
+    let matches = |i: usize| match (expected.get(i), actual.get(i)) {
+        (Some(None), Some(_)) => true,
+        (Some(Some(e)), Some(a)) => e == a,
+        _ => false,
+    };
+    let len = expected.len().max(actual.len());
+    let first_mismatch = match (0..len).find(|&i| !matches(i)) {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+
+    let mut diff = format!(
+        "First mismatch at offset {:#x}, expected {} bytes but got {}\n",
+        first_mismatch,
+        expected.len(),
+        actual.len()
+    );
+    let first_line = first_mismatch / DIFF_LINE_LEN * DIFF_LINE_LEN;
+    for start in (first_line..len).step_by(DIFF_LINE_LEN) {
+        let end = (start + DIFF_LINE_LEN).min(len);
+        let (mut exp_line, mut act_line, mut markers) =
+            (String::new(), String::new(), String::new());
+        for i in start..end {
+            match expected.get(i) {
+                Some(Some(b)) => write!(exp_line, "{:02x} ", b).unwrap(),
+                Some(None) => exp_line += "?? ",
+                None => exp_line += "   ",
+            }
+            match actual.get(i) {
+                Some(b) => write!(act_line, "{:02x} ", b).unwrap(),
+                None => act_line += "   ",
+            }
+            markers += if matches(i) { "   " } else { "^^ " };
+        }
+        let lines = [
+            format!("{:08x} expected: {}", start, exp_line),
+            format!("{:08x}   actual: {}", start, act_line),
+            format!("                   {}", markers),
+        ];
+        for line in lines {
+            writeln!(diff, "{}", line.trim_end()).unwrap();
+        }
+    }
+    Err(diff)
+}
+
+/// A decoded frame of a capture, and when it was seen
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CapturedFrame {
+    pub direction: FrameDirection,
+    /// Time since an arbitrary origin, only the differences between frames are meaningful
+    pub at: Duration,
+    pub packet: CloudProtoPacket,
+}
+
+/// A [`WireLogger`](WireLogger) that keeps the frames of a socket in memory, for tests.
+///
+/// Times are taken from the monotonic clock, relative to the first frame.
+#[derive(Clone, Default)]
+pub struct FrameRecorder {
+    frames: Arc<Mutex<Vec<CapturedFrame>>>,
+    origin: Option<Instant>,
+}
+
+impl FrameRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of all the frames recorded so far
+    pub fn frames(&self) -> Vec<CapturedFrame> {
+        self.frames.lock().unwrap().clone()
+    }
+}
+
+impl WireLogger for FrameRecorder {
+    fn log_frame(
+        &mut self,
+        direction: FrameDirection,
+        frame: &[u8],
+        timestamp: FrameTimestamp,
+    ) -> io::Result<()> {
+        let packet = CloudProtoPacket::from_buf(Bytes::copy_from_slice(frame))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let origin = *self.origin.get_or_insert(timestamp.monotonic);
+        self.frames.lock().unwrap().push(CapturedFrame {
+            direction,
+            at: timestamp.monotonic.duration_since(origin),
+            packet,
+        });
+        Ok(())
+    }
+}
+
+fn invalid_capture(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads the frames of a pcap file written by an [`ExportedPduWriter`](crate::framing::ExportedPduWriter).
+///
+/// Times are relative to the UNIX epoch, with microsecond precision.
+/// Frames that were truncated in the file can't be decoded, and are skipped.
+pub fn read_exported_pdu_capture(pcap: &[u8]) -> io::Result<Vec<CapturedFrame>> {
+    let u32_at = |buf: &[u8], pos: usize| {
+        buf.get(pos..pos + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| invalid_capture("Truncated pcap file"))
+    };
+    if u32_at(pcap, 0)? != 0xa1b2c3d4 || pcap.len() < 24 {
+        return Err(invalid_capture("Not a little-endian pcap file"));
+    }
+
+    let mut frames = Vec::new();
+    let mut pos = 24;
+    while pos < pcap.len() {
+        let at = Duration::from_secs(u32_at(pcap, pos)? as u64)
+            + Duration::from_micros(u32_at(pcap, pos + 4)? as u64);
+        let captured_len = u32_at(pcap, pos + 8)? as usize;
+        let original_len = u32_at(pcap, pos + 12)? as usize;
+        let record = pcap
+            .get(pos + 16..pos + 16 + captured_len)
+            .ok_or_else(|| invalid_capture("Truncated pcap record"))?;
+        pos += 16 + captured_len;
+
+        let mut direction = None;
+        let mut tag_pos = 0;
+        let frame = loop {
+            let tag = record
+                .get(tag_pos..tag_pos + 4)
+                .ok_or_else(|| invalid_capture("Truncated exported PDU tags"))?;
+            let tag_id = u16::from_be_bytes([tag[0], tag[1]]);
+            let tag_len = u16::from_be_bytes([tag[2], tag[3]]) as usize;
+            let value = record
+                .get(tag_pos + 4..tag_pos + 4 + tag_len)
+                .ok_or_else(|| invalid_capture("Truncated exported PDU tags"))?;
+            tag_pos += 4 + tag_len;
+            match tag_id {
+                EXP_PDU_TAG_END_OF_OPT => break &record[tag_pos..],
+                EXP_PDU_TAG_P2P_DIRECTION if tag_len == 4 => {
+                    direction = match u32::from_be_bytes(value.try_into().unwrap()) {
+                        0 => Some(FrameDirection::Sent),
+                        1 => Some(FrameDirection::Received),
+                        _ => None,
+                    }
+                }
+                _ => {}
+            }
+        };
+        let direction =
+            direction.ok_or_else(|| invalid_capture("Record without a P2P direction"))?;
+        if captured_len < original_len {
+            continue;
+        }
+        let packet = CloudProtoPacket::from_buf(Bytes::copy_from_slice(frame))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        frames.push(CapturedFrame {
+            direction,
+            at,
+            packet,
+        });
+    }
+    Ok(frames)
+}
+
+/// An ACK sent by one side of a TS session, see [`AckTrace`](AckTrace)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TracedAck {
+    /// Index of the ACK in the captured frames
+    pub frame: usize,
+    /// `None` if the ACK payload is not a txid
+    pub txid: Option<u64>,
+    pub version: CloudProtoVersion,
+    pub payload_len: usize,
+    /// Time since the ACKed event was received, `None` if no such event was pending
+    pub delay: Option<Duration>,
+    /// Whether an earlier event was still un-ACKed when this one was ACKed
+    pub out_of_order: bool,
+}
+
+/// How the ACKs of a TS session differ from those of a reference capture
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AckDivergence {
+    /// The ACK packet's version or payload length was never seen in the reference
+    Structure {
+        frame: usize,
+        version: CloudProtoVersion,
+        payload_len: usize,
+    },
+    /// An ACK for a txid that was not received, or was already ACKed
+    Unsolicited { frame: usize, txid: Option<u64> },
+    /// An ACK sent while an earlier event was still un-ACKed, but the reference ACKs in order
+    OutOfOrder { frame: usize, txid: u64 },
+    /// An ACK that took longer than any ACK of the reference, plus the allowed slack
+    Slow {
+        frame: usize,
+        txid: u64,
+        delay: Duration,
+        max_delay: Duration,
+    },
+    /// An event that was never ACKed, although later events were
+    Missing { txid: u64 },
+}
+
+/// The ACKs sent by one side of a TS session, matched with the events they acknowledge.
+///
+/// Trace a real capture and a capture of this crate's sockets (e.g. from a [`FrameRecorder`](FrameRecorder)),
+/// then [`diff`](AckTrace::diff) them to check that ACKs have the same structure and a plausible cadence.
+/// Only TS frames are considered.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AckTrace {
+    pub acks: Vec<TracedAck>,
+    /// Events that were never ACKed, although later events were
+    pub missing: Vec<u64>,
+}
+
+impl AckTrace {
+    /// Traces the ACKs of the side of the capture that sends them in the `acker` direction
+    pub fn new(frames: &[CapturedFrame], acker: FrameDirection) -> Self {
+        let mut trace = Self::default();
+        // Events not yet ACKed, in the order they were received
+        let mut pending: Vec<(usize, u64, Duration)> = Vec::new();
+        // Frame index of the latest received event that was ACKed
+        let mut latest_acked = None;
+        for (frame, captured) in frames.iter().enumerate() {
+            let pkt = &captured.packet;
+            if pkt.magic != CloudProtoMagic::TS {
+                continue;
+            }
+            if captured.direction != acker && pkt.kind == TsPacketKind::Event {
+                if let Ok((txid, _)) = Event::from_packet(pkt) {
+                    pending.push((frame, txid, captured.at));
+                }
+            } else if captured.direction == acker && pkt.kind == TsPacketKind::Ack {
+                let txid = <[u8; 8]>::try_from(&pkt.payload[..])
+                    .ok()
+                    .map(u64::from_be_bytes);
+                let found = txid.and_then(|txid| pending.iter().position(|&(_, t, _)| t == txid));
+                let (delay, out_of_order) = match found {
+                    Some(index) => {
+                        let (event_frame, _, received_at) = pending.remove(index);
+                        latest_acked = latest_acked.max(Some(event_frame));
+                        (Some(captured.at.saturating_sub(received_at)), index > 0)
+                    }
+                    None => (None, false),
+                };
+                trace.acks.push(TracedAck {
+                    frame,
+                    txid,
+                    version: pkt.version,
+                    payload_len: pkt.payload.len(),
+                    delay,
+                    out_of_order,
+                });
+            }
+        }
+        // Events at the end of the capture may just not have been ACKed yet
+        trace.missing = pending
+            .into_iter()
+            .filter(|&(event_frame, _, _)| Some(event_frame) < latest_acked)
+            .map(|(_, txid, _)| txid)
+            .collect();
+        trace
+    }
+
+    /// The longest time taken to ACK an event
+    pub fn max_delay(&self) -> Option<Duration> {
+        self.acks.iter().filter_map(|ack| ack.delay).max()
+    }
+
+    /// Compares these ACKs with those of a `reference` trace.
+    ///
+    /// ACKs may take up to `slack` longer than the slowest ACK of the reference,
+    /// since the timing of a test environment is never exactly that of the real cloud.
+    pub fn diff(&self, reference: &AckTrace, slack: Duration) -> Vec<AckDivergence> {
+        let reference_in_order = reference.acks.iter().all(|ack| !ack.out_of_order);
+        let max_delay = reference.max_delay().unwrap_or_default() + slack;
+        let mut divergences = Vec::new();
+        for ack in &self.acks {
+            let known_structure = reference
+                .acks
+                .iter()
+                .any(|r| r.version == ack.version && r.payload_len == ack.payload_len);
+            if !known_structure {
+                divergences.push(AckDivergence::Structure {
+                    frame: ack.frame,
+                    version: ack.version,
+                    payload_len: ack.payload_len,
+                });
+            }
+            match (ack.txid, ack.delay) {
+                (Some(txid), Some(delay)) => {
+                    if ack.out_of_order && reference_in_order {
+                        divergences.push(AckDivergence::OutOfOrder {
+                            frame: ack.frame,
+                            txid,
+                        });
+                    }
+                    if delay > max_delay {
+                        divergences.push(AckDivergence::Slow {
+                            frame: ack.frame,
+                            txid,
+                            delay,
+                            max_delay,
+                        });
+                    }
+                }
+                (txid, _) => divergences.push(AckDivergence::Unsolicited {
+                    frame: ack.frame,
+                    txid,
+                }),
+            }
+        }
+        divergences.extend(
+            self.missing
+                .iter()
+                .map(|&txid| AckDivergence::Missing { txid }),
+        );
+        divergences
+    }
+
+    /// Panics with the list of divergences if these ACKs differ from those of the `reference`.
+    ///
+    /// See [`diff`](AckTrace::diff).
+    pub fn assert_matches(&self, reference: &AckTrace, slack: Duration) {
+        let divergences = self.diff(reference, slack);
+        if !divergences.is_empty() {
+            panic!(
+                "ACKs diverge from the reference capture:\n{:#?}",
+                divergences
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framing::{CloudProtoSocket, ExportedPduWriter};
+    use crate::services::ts::{
+        AgentIdStatus, Event, EventId, SensorProfile, TsConnectInfo, TsConnectResponse, TsError,
+        TsEventAcceptor, TsEventSocket,
+    };
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::UNIX_EPOCH;
+
+    #[test_log::test(tokio::test)]
+    async fn golden_transcript() -> Result<(), TsError> {
+        let (client, _server) = tokio::io::duplex(16 * 1024);
+        let (client, transcript) = RecordingIo::new(client);
+        let mut client = TsEventSocket::new(
+            CloudProtoSocket::new(client),
+            TsConnectInfo::new_simple([0; 16]),
+            &SensorProfile::default(),
+        );
+        client
+            .send(Event::new(EventId::AgentOnline, vec![0x08, 0x01]))
+            .await?;
+
+        // Header, then txid, event ID and Protobuf data
+        transcript.assert_matches(
+            "8f 03 0001 00000016
+             ?? ?? ?? ?? ?? ?? ?? ?? 338000ac 0801",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn golden_diff() {
+        assert_eq!(match_golden(&[1, 2, 3], "01 ?? 03"), Ok(()));
+        let diff = match_golden(&[1, 2, 3], "01 02 04 05").unwrap_err();
+        assert_eq!(
+            diff,
+            "First mismatch at offset 0x2, expected 4 bytes but got 3\n\
+             00000000 expected: 01 02 04 05\n\
+             00000000   actual: 01 02 03\n\
+             \x20                        ^^ ^^\n"
+        );
+    }
+
+    fn ack_packet(txid: u64) -> CloudProtoPacket {
+        CloudProtoPacket {
+            magic: CloudProtoMagic::TS,
+            kind: TsPacketKind::Ack.into(),
+            version: CloudProtoVersion::Normal,
+            payload: txid.to_be_bytes().to_vec().into(),
+        }
+    }
+
+    fn frame(direction: FrameDirection, at_ms: u64, packet: CloudProtoPacket) -> CapturedFrame {
+        CapturedFrame {
+            direction,
+            at: Duration::from_millis(at_ms),
+            packet,
+        }
+    }
+
+    // What a capture of the cloud ACKing a sensor looks like, from the sensor's side
+    fn reference_capture() -> io::Result<Vec<CapturedFrame>> {
+        let mut pcap = ExportedPduWriter::new(Vec::new())?;
+        let event = |txid| Event::new(EventId::AgentOnline, vec![]).into_packet(txid);
+        let frames = [
+            (FrameDirection::Sent, 1000, event(0x200)),
+            (FrameDirection::Received, 1020, ack_packet(0x200)),
+            (FrameDirection::Sent, 1100, event(0x300)),
+            (FrameDirection::Received, 1150, ack_packet(0x300)),
+        ];
+        for (direction, at_ms, pkt) in frames {
+            let timestamp = FrameTimestamp {
+                wall: UNIX_EPOCH + Duration::from_millis(at_ms),
+                monotonic: Instant::now(),
+            };
+            pcap.log_frame(direction, &pkt.to_buf(), timestamp)?;
+        }
+        read_exported_pdu_capture(&pcap.into_inner()?)
+    }
+
+    #[test]
+    fn read_capture() -> io::Result<()> {
+        let frames = reference_capture()?;
+        assert_eq!(frames.len(), 4);
+        assert_eq!(
+            frames[1],
+            frame(FrameDirection::Received, 1020, ack_packet(0x200))
+        );
+        assert!(read_exported_pdu_capture(b"not a pcap").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_capture() -> io::Result<()> {
+        let timestamp = FrameTimestamp {
+            wall: UNIX_EPOCH,
+            monotonic: Instant::now(),
+        };
+        for frame in ["9f01000100000004", "9f010001"] {
+            let mut pcap = ExportedPduWriter::new(Vec::new())?;
+            let frame = hex::decode(frame).unwrap();
+            pcap.log_frame(FrameDirection::Received, &frame, timestamp)?;
+            let err = read_exported_pdu_capture(&pcap.into_inner()?).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn server_ack_cadence() -> Result<(), TsError> {
+        let reference = AckTrace::new(&reference_capture()?, FrameDirection::Received);
+        assert_eq!(reference.max_delay(), Some(Duration::from_millis(50)));
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let recorder = FrameRecorder::new();
+        let mut server = CloudProtoSocket::new(server);
+        server.set_wire_logger(Some(Box::new(recorder.clone())));
+        let server = tokio::spawn(async move {
+            let (acceptor, info) = TsEventAcceptor::listen(server).await?;
+            let mut server = acceptor
+                .accept(TsConnectResponse {
+                    agent_id_status: AgentIdStatus::Unchanged,
+                    aid: info.aid,
+                })
+                .await?;
+            for _ in 0..3 {
+                server.next().await.unwrap()?;
+            }
+            Ok::<_, TsError>(server)
+        });
+        let mut client = TsEventSocket::connect(
+            CloudProtoSocket::new(client),
+            TsConnectInfo::new_simple([1; 16]),
+        )
+        .await?;
+        for _ in 0..3 {
+            client
+                .send(Event::new(EventId::AgentOnline, vec![]))
+                .await?;
+        }
+        let _server = server.await.unwrap()?;
+
+        let actual = AckTrace::new(&recorder.frames(), FrameDirection::Sent);
+        assert_eq!(actual.acks.len(), 3);
+        actual.assert_matches(&reference, Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn ack_divergences() -> io::Result<()> {
+        let reference = AckTrace::new(&reference_capture()?, FrameDirection::Received);
+        let event = |txid| Event::new(EventId::AgentOnline, vec![]).into_packet(txid);
+        let short_ack = CloudProtoPacket {
+            payload: vec![0; 4].into(),
+            ..ack_packet(0)
+        };
+        let frames = [
+            frame(FrameDirection::Received, 0, event(0x200)),
+            frame(FrameDirection::Received, 10, event(0x300)),
+            frame(FrameDirection::Received, 20, event(0x400)),
+            frame(FrameDirection::Sent, 30, ack_packet(0x300)),
+            frame(FrameDirection::Sent, 500, ack_packet(0x400)),
+            frame(FrameDirection::Sent, 510, short_ack),
+        ];
+        let actual = AckTrace::new(&frames, FrameDirection::Sent);
+        assert_eq!(
+            actual.diff(&reference, Duration::from_millis(100)),
+            vec![
+                AckDivergence::OutOfOrder {
+                    frame: 3,
+                    txid: 0x300
+                },
+                AckDivergence::OutOfOrder {
+                    frame: 4,
+                    txid: 0x400
+                },
+                AckDivergence::Slow {
+                    frame: 4,
+                    txid: 0x400,
+                    delay: Duration::from_millis(480),
+                    max_delay: Duration::from_millis(150),
+                },
+                AckDivergence::Structure {
+                    frame: 5,
+                    version: CloudProtoVersion::Normal,
+                    payload_len: 4,
+                },
+                AckDivergence::Unsolicited {
+                    frame: 5,
+                    txid: None
+                },
+                AckDivergence::Missing { txid: 0x200 },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Full client/server loops over in-memory transports, using only the public API
+
+use crowdstrike_cloudproto::framing::{CloudProtoError, CloudProtoPacket, CloudProtoSocket};
+use crowdstrike_cloudproto::services::lfo::{LfoClient, LfoError, LfoPacketKind, LfoRequest};
+use crowdstrike_cloudproto::services::ts::{
+    AgentIdStatus, Event, EventId, TsConnectInfo, TsConnectResponse, TsError, TsEventAcceptor,
+    TsEventSocket, TsEventStreamExt, TsPacketKind,
+};
+use crowdstrike_cloudproto::services::CloudProtoMagic;
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::spawn;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Relays packets from one socket to the other, returning the raw event IDs that went through
+async fn relay(
+    mut from: impl StreamExt<Item = Result<CloudProtoPacket, CloudProtoError>> + Unpin,
+    mut to: impl SinkExt<CloudProtoPacket, Error = std::io::Error> + Unpin,
+) -> Result<Vec<u32>, CloudProtoError> {
+    let mut event_ids = Vec::new();
+    while let Some(pkt) = from.next().await {
+        let pkt = pkt?;
+        if pkt.kind == TsPacketKind::Event {
+            let (_txid, ev) = Event::from_packet(&pkt)?;
+            event_ids.push(ev.raw_event_id);
+        }
+        to.send(pkt).await?;
+    }
+    to.close().await?;
+    Ok(event_ids)
+}
+
+#[test_log::test(tokio::test)]
+async fn ts_session_through_proxy() -> Result<(), TsError> {
+    let (client_io, proxy_downstream) = tokio::io::duplex(16 * 1024);
+    let (proxy_upstream, server_io) = tokio::io::duplex(16 * 1024);
+
+    let (down_tx, down_rx) = CloudProtoSocket::new(proxy_downstream).split();
+    let (up_tx, up_rx) = CloudProtoSocket::new(proxy_upstream).split();
+    let to_server = spawn(relay(down_rx, up_tx));
+    let to_client = spawn(relay(up_rx, down_tx));
+
+    let new_aid = [0xAA; 16];
+    let server = spawn(async move {
+        let (acceptor, info) = TsEventAcceptor::listen(CloudProtoSocket::new(server_io)).await?;
+        assert_eq!(info.cid, [0x42; 16]);
+        let mut sock = acceptor
+            .accept(TsConnectResponse {
+                agent_id_status: AgentIdStatus::Changed,
+                aid: new_aid,
+            })
+            .await?;
+        sock.next_matching(EventId::AgentOnline, TIMEOUT).await?;
+        sock.send(Event::new(EventId::ChannelRundown, vec![0x08, 0x01]))
+            .await?;
+        let rest = sock.collect_until_idle(Duration::from_millis(100)).await?;
+        Ok::<_, TsError>(rest)
+    });
+
+    let client_io = CloudProtoSocket::new(client_io);
+    let mut client: TsEventSocket<DuplexStream> =
+        TsEventSocket::connect(client_io, TsConnectInfo::new_simple([0x42; 16])).await?;
+    assert_eq!(client.connect_info().aid, new_aid);
+    client
+        .send(Event::new(EventId::AgentOnline, vec![]))
+        .await?;
+    let ev = client
+        .next_matching(EventId::ChannelRundown, TIMEOUT)
+        .await?;
+    assert_eq!(ev.data, vec![0x08, 0x01]);
+    client
+        .send(Event::new(EventId::DiskUtilization, vec![]))
+        .await?;
+
+    let rest = server.await.unwrap()?;
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].event_id, Some(EventId::DiskUtilization));
+
+    client.close().await?;
+    let seen_by_proxy = to_server.await.unwrap()?;
+    assert_eq!(
+        seen_by_proxy,
+        vec![EventId::AgentOnline as u32, EventId::DiskUtilization as u32]
+    );
+    assert_eq!(
+        to_client.await.unwrap()?,
+        vec![EventId::ChannelRundown as u32]
+    );
+    Ok(())
+}
+
+// Builds an LFO ReplyOk payload for a file transmitted in a single chunk
+fn lfo_reply_payload(data: &[u8], comp_format: u16, transmitted: &[u8]) -> Vec<u8> {
+    use sha2::Digest;
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&0u32.to_be_bytes());
+    payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&sha2::Sha256::digest(data));
+    payload.extend_from_slice(&comp_format.to_be_bytes());
+    payload.extend_from_slice(transmitted);
+    payload.extend_from_slice(&crc32fast::hash(transmitted).to_be_bytes());
+    payload
+}
+
+#[test_log::test(tokio::test)]
+async fn lfo_download_from_mock_server() -> Result<(), LfoError> {
+    use crowdstrike_cloudproto::framing::CloudProtoVersion;
+    use crowdstrike_cloudproto::services::lfo::CompressionFormats;
+
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let file = b"channel file contents ".repeat(100);
+    #[cfg_attr(not(feature = "lfo-compress-xz"), allow(unused_mut))]
+    let mut replies = vec![(CompressionFormats::None as u16, file.clone())];
+    #[cfg(feature = "lfo-compress-xz")]
+    {
+        use crowdstrike_cloudproto::services::lfo::{LfoCompression, XzCompression};
+        replies.push((XzCompression.id(), XzCompression.encode(&file).unwrap()));
+    }
+    let request_count = replies.len();
+    let server_file = file.clone();
+
+    let server = spawn(async move {
+        let mut sock = CloudProtoSocket::new(server_io);
+        for (comp_format, transmitted) in replies {
+            let req = sock.next().await.unwrap()?;
+            assert_eq!(req.magic, CloudProtoMagic::LFO);
+            assert_eq!(req.kind, LfoPacketKind::GetFileRequest);
+            sock.send(CloudProtoPacket {
+                magic: CloudProtoMagic::LFO,
+                kind: LfoPacketKind::ReplyOk.into(),
+                version: CloudProtoVersion::Normal,
+                payload: lfo_reply_payload(&server_file, comp_format, &transmitted).into(),
+            })
+            .await?;
+        }
+        let req = sock.next().await.unwrap()?;
+        assert_eq!(req.kind, LfoPacketKind::GetFileRequest);
+        let mut fail_payload = vec![0; 8];
+        fail_payload.extend_from_slice(b"internal error");
+        sock.send(CloudProtoPacket {
+            magic: CloudProtoMagic::LFO,
+            kind: LfoPacketKind::ReplyFail.into(),
+            version: CloudProtoVersion::Normal,
+            payload: fail_payload.into(),
+        })
+        .await?;
+        Ok::<_, CloudProtoError>(())
+    });
+
+    let mut client = LfoClient::new(CloudProtoSocket::new(client_io));
+    let req = LfoRequest::new_simple("/channel/file".into());
+    for _ in 0..request_count {
+        let data = client.get(&req).await?.into_data().await?;
+        assert_eq!(data, file);
+    }
+    assert!(matches!(client.get(&req).await, Err(LfoError::NotFound)));
+    server.await.unwrap()?;
+    Ok(())
+}